@@ -0,0 +1,308 @@
+#[cfg(any(test, feature = "testing"))]
+use crate::testing::{message_json, Api};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use discord::client::RowComponent;
+use discord::types::{
+	AllowedMentions, ApplicationCommand, ApplicationCommandOption, ApplicationId, ChannelId, Embed,
+	GuildId, Member, MessageId, RoleId, UserId,
+};
+#[cfg(any(test, feature = "testing"))]
+use reqwest::Method;
+#[cfg(any(test, feature = "testing"))]
+use serde_json::json;
+
+/// Client for the Discord REST API. Requests go through the `discord-async` client of
+/// the gateway connection, or in tests to the Discord routes of a `testing::StandIn`
+#[derive(Clone)]
+pub struct Client(Backend);
+
+#[derive(Clone)]
+enum Backend {
+	Discord(discord::Client),
+	#[cfg(any(test, feature = "testing"))]
+	StandIn(Api),
+}
+
+impl From<discord::Client> for Client {
+	fn from(client: discord::Client) -> Self {
+		Self(Backend::Discord(client))
+	}
+}
+
+impl Client {
+	#[cfg(any(test, feature = "testing"))]
+	pub(crate) fn stand_in(api: Api) -> Self {
+		Self(Backend::StandIn(api))
+	}
+
+	pub fn create_message(&self, channel_id: ChannelId) -> MessageBuilder {
+		MessageBuilder::new(self.clone(), channel_id, None)
+	}
+
+	pub fn edit_message(&self, channel_id: ChannelId, message_id: MessageId) -> MessageBuilder {
+		MessageBuilder::new(self.clone(), channel_id, Some(message_id))
+	}
+
+	pub async fn delete_message(&self, ids: (ChannelId, MessageId)) -> Result<()> {
+		match &self.0 {
+			Backend::Discord(c) => {
+				c.delete_message(ids).await?;
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!("/channels/{}/messages/{}", ids.0, ids.1);
+				api.send(Method::DELETE, &path, None).await?;
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn add_guild_member_role(
+		&self,
+		guild_id: GuildId,
+		user_id: UserId,
+		role_id: RoleId,
+	) -> Result<()> {
+		match &self.0 {
+			Backend::Discord(c) => {
+				c.add_guild_member_role(guild_id, user_id, role_id).await?;
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!("/guilds/{}/members/{}/roles/{}", guild_id, user_id, role_id);
+				api.send(Method::PUT, &path, None).await?;
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn remove_guild_member_role(
+		&self,
+		guild_id: GuildId,
+		user_id: UserId,
+		role_id: RoleId,
+	) -> Result<()> {
+		match &self.0 {
+			Backend::Discord(c) => {
+				c.remove_guild_member_role(guild_id, user_id, role_id)
+					.await?;
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!("/guilds/{}/members/{}/roles/{}", guild_id, user_id, role_id);
+				api.send(Method::DELETE, &path, None).await?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Open a direct message channel with `user_id`, returning its id
+	pub async fn create_dm(&self, user_id: UserId) -> Result<ChannelId> {
+		match &self.0 {
+			Backend::Discord(c) => Ok(c.create_dm(user_id).await?.id),
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let body = json!({ "recipient_id": user_id });
+				let channel = api
+					.send(Method::POST, "/users/@me/channels", Some(body))
+					.await?;
+				Ok(serde_json::from_value(channel["id"].clone())?)
+			}
+		}
+	}
+
+	/// Prevent `user_id` from talking in the guild until `until`
+	pub async fn timeout_guild_member(
+		&self,
+		guild_id: GuildId,
+		user_id: UserId,
+		until: DateTime<Utc>,
+	) -> Result<()> {
+		match &self.0 {
+			Backend::Discord(c) => {
+				c.timeout_guild_member(guild_id, user_id, until).await?;
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!("/guilds/{}/members/{}", guild_id, user_id);
+				let body = json!({ "communication_disabled_until": until.to_rfc3339() });
+				api.send(Method::PATCH, &path, Some(body)).await?;
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn get_guild_member(&self, guild_id: GuildId, user_id: UserId) -> Result<Member> {
+		match &self.0 {
+			Backend::Discord(c) => Ok(c.get_guild_member(guild_id, user_id).await?),
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!("/guilds/{}/members/{}", guild_id, user_id);
+				Ok(serde_json::from_value(
+					api.send(Method::GET, &path, None).await?,
+				)?)
+			}
+		}
+	}
+
+	pub async fn create_command(
+		&self,
+		application_id: ApplicationId,
+		guild_id: GuildId,
+		name: &str,
+		description: &str,
+		options: Vec<ApplicationCommandOption>,
+	) -> Result<()> {
+		match &self.0 {
+			Backend::Discord(c) => {
+				c.create_command(application_id, guild_id, name, description, options)
+					.await?;
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!(
+					"/applications/{}/guilds/{}/commands",
+					application_id, guild_id
+				);
+				let body = json!({
+					"name": name,
+					"description": description,
+					"options": options,
+				});
+				api.send(Method::POST, &path, Some(body)).await?;
+			}
+		}
+		Ok(())
+	}
+
+	pub async fn delete_command(
+		&self,
+		application_id: ApplicationId,
+		guild_id: GuildId,
+		command: &ApplicationCommand,
+	) -> Result<()> {
+		match &self.0 {
+			Backend::Discord(c) => {
+				c.delete_command(application_id, guild_id, command.id)
+					.await?;
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Backend::StandIn(api) => {
+				let path = format!(
+					"/applications/{}/guilds/{}/commands/{}",
+					application_id, guild_id, command.id
+				);
+				api.send(Method::DELETE, &path, None).await?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Everything a message or interaction response can carry
+#[derive(Default)]
+pub(crate) struct MessageData {
+	pub content: Option<String>,
+	pub embeds: Vec<Embed>,
+	pub components: Option<Vec<RowComponent>>,
+	pub allowed_mentions: Option<AllowedMentions>,
+}
+
+/// Apply `MessageData` to one of the message builders of `discord-async`
+macro_rules! apply_message {
+	($builder:expr, $message:expr) => {{
+		let message = $message;
+		let mut builder = $builder;
+		if let Some(content) = message.content {
+			builder = builder.content(content);
+		}
+		for embed in message.embeds {
+			builder = builder.embed(embed);
+		}
+		if let Some(components) = message.components {
+			builder = builder.component_rows(components);
+		}
+		if let Some(allowed_mentions) = message.allowed_mentions {
+			builder = builder.allowed_mentions(allowed_mentions);
+		}
+		builder
+	}};
+}
+pub(crate) use apply_message;
+
+pub struct MessageBuilder {
+	client: Client,
+	channel_id: ChannelId,
+	message_id: Option<MessageId>,
+	message: MessageData,
+}
+
+impl MessageBuilder {
+	fn new(client: Client, channel_id: ChannelId, message_id: Option<MessageId>) -> Self {
+		Self {
+			client,
+			channel_id,
+			message_id,
+			message: MessageData::default(),
+		}
+	}
+
+	pub fn content<T: Into<String>>(mut self, content: T) -> Self {
+		self.message.content = Some(content.into());
+		self
+	}
+
+	pub fn embed(mut self, embed: Embed) -> Self {
+		self.message.embeds.push(embed);
+		self
+	}
+
+	pub fn component_rows(mut self, rows: Vec<RowComponent>) -> Self {
+		self.message.components = Some(rows);
+		self
+	}
+
+	pub fn component_row<T: Into<RowComponent>>(mut self, row: T) -> Self {
+		self.message
+			.components
+			.get_or_insert_with(Vec::new)
+			.push(row.into());
+		self
+	}
+
+	pub fn allowed_mentions(mut self, allowed_mentions: AllowedMentions) -> Self {
+		self.message.allowed_mentions = Some(allowed_mentions);
+		self
+	}
+
+	/// Create or edit the message, returning its id
+	pub async fn send(self) -> Result<MessageId> {
+		let channel_id = self.channel_id;
+		match (self.client.0, self.message_id) {
+			(Backend::Discord(c), None) => {
+				let builder = apply_message!(c.create_message(channel_id), self.message);
+				Ok(builder.send().await?.id)
+			}
+			(Backend::Discord(c), Some(message_id)) => {
+				let builder = apply_message!(c.edit_message(channel_id, message_id), self.message);
+				builder.send().await?;
+				Ok(message_id)
+			}
+			#[cfg(any(test, feature = "testing"))]
+			(Backend::StandIn(api), None) => {
+				let path = format!("/channels/{}/messages", channel_id);
+				let body = Some(message_json(self.message)?);
+				let message = api.send(Method::POST, &path, body).await?;
+				Ok(serde_json::from_value(message["id"].clone())?)
+			}
+			#[cfg(any(test, feature = "testing"))]
+			(Backend::StandIn(api), Some(message_id)) => {
+				let path = format!("/channels/{}/messages/{}", channel_id, message_id);
+				let body = Some(message_json(self.message)?);
+				api.send(Method::PATCH, &path, body).await?;
+				Ok(message_id)
+			}
+		}
+	}
+}
@@ -0,0 +1,188 @@
+use crate::client::{apply_message, Client};
+use crate::interaction::ResponseBuilder;
+use discord::interaction::CanRespond;
+use discord::types::{
+	ApplicationCommand, ApplicationId, Channel, ChannelId, GuildId, Interaction, Member, Role,
+	RoleId, UserId,
+};
+use discord::voice::{Controller, Listener, Updater};
+use discord::GatewayEvent;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// We would like to prevent generics bleeding up everywhere.
+// Luckily all of our binaries use the same `S` in `Guild<S>`,
+// which allows us to define our handlers in terms of that type.
+// This might change in the future, forcing us to bite the bullet.
+type GatewayGuild = discord::Guild<UnboundedReceiver<GatewayEvent>>;
+
+/// The guild a bot runs in. Normally backed by the gateway connection, in tests by
+/// a `testing::FakeGuild` whose REST calls go to a `testing::StandIn`. Also holds the
+/// user id of the bot, taken from READY
+pub struct Guild(Inner, UserId);
+
+enum Inner {
+	Gateway(GatewayGuild),
+	#[cfg(any(test, feature = "testing"))]
+	Fake(crate::testing::FakeGuild),
+}
+
+impl Guild {
+	pub fn new(guild: GatewayGuild, user_id: UserId) -> Self {
+		Self(Inner::Gateway(guild), user_id)
+	}
+
+	#[cfg(any(test, feature = "testing"))]
+	pub(crate) fn fake(guild: crate::testing::FakeGuild) -> Self {
+		let user_id = guild.user_id;
+		Self(Inner::Fake(guild), user_id)
+	}
+
+	pub fn id(&self) -> GuildId {
+		match &self.0 {
+			Inner::Gateway(g) => g.id(),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g.id,
+		}
+	}
+
+	pub fn application_id(&self) -> ApplicationId {
+		match &self.0 {
+			Inner::Gateway(g) => g.application_id(),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g.application_id,
+		}
+	}
+
+	/// User id of the bot
+	pub fn user_id(&self) -> UserId {
+		self.1
+	}
+
+	pub fn name(&self) -> &str {
+		match &self.0 {
+			Inner::Gateway(g) => g.name(),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => &g.name,
+		}
+	}
+
+	pub fn client(&self) -> Client {
+		match &self.0 {
+			Inner::Gateway(g) => g.client().into(),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => Client::stand_in(g.api.clone()),
+		}
+	}
+
+	pub fn command(&self, name: &str) -> Option<&ApplicationCommand> {
+		match &self.0 {
+			Inner::Gateway(g) => g.command(name),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g.commands.iter().find(|c| c.name == name),
+		}
+	}
+
+	/// All commands of our application in this guild
+	pub fn commands(&self) -> Box<dyn Iterator<Item = &ApplicationCommand> + '_> {
+		match &self.0 {
+			Inner::Gateway(g) => Box::new(g.commands()),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => Box::new(g.commands.iter()),
+		}
+	}
+
+	pub fn channel(&self, id: ChannelId) -> Option<&Channel> {
+		match &self.0 {
+			Inner::Gateway(g) => g.channel(id),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g.channels.iter().find(|c| c.id == id),
+		}
+	}
+
+	pub fn role(&self, id: RoleId) -> Option<&Role> {
+		match &self.0 {
+			Inner::Gateway(g) => g.role(id),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g.roles.iter().find(|r| r.id == id),
+		}
+	}
+
+	pub fn member(&self, id: UserId) -> Option<&Member> {
+		match &self.0 {
+			Inner::Gateway(g) => g.member(id),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g
+				.members
+				.iter()
+				.find(|m| m.user.as_ref().map(|u| u.id) == Some(id)),
+		}
+	}
+
+	pub fn members(&self) -> Box<dyn Iterator<Item = &Member> + '_> {
+		match &self.0 {
+			Inner::Gateway(g) => Box::new(g.members()),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => Box::new(g.members.iter()),
+		}
+	}
+
+	/// Position of the highest role of `member`
+	pub fn member_role_position(&self, member: &Member) -> i32 {
+		match &self.0 {
+			Inner::Gateway(g) => g.member_role_position(member),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(_) => member
+				.roles
+				.iter()
+				.filter_map(|&r| self.role(r))
+				.map(|r| r.position)
+				.max()
+				.unwrap_or(0),
+		}
+	}
+
+	/// Voice needs the gateway connection, so this panics on a fake guild
+	pub fn create_player(&self) -> (Updater, Controller, Listener) {
+		match &self.0 {
+			Inner::Gateway(g) => g.create_player(),
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(_) => panic!("Fake guilds have no voice connection"),
+		}
+	}
+
+	pub(crate) fn respond(&self, interaction: &Interaction) -> ResponseBuilder {
+		match &self.0 {
+			Inner::Gateway(g) => {
+				let builder = interaction.respond(g);
+				ResponseBuilder::new(Box::new(move |message, ephemeral| {
+					let mut builder = apply_message!(builder, message);
+					if ephemeral {
+						builder = builder.ephemeral();
+					}
+					Box::pin(async move {
+						builder.send().await?;
+						Ok(())
+					})
+				}))
+			}
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(g) => g.api.respond(interaction),
+		}
+	}
+}
+
+impl Stream for Guild {
+	type Item = GatewayEvent;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		match &mut self.get_mut().0 {
+			Inner::Gateway(g) => Pin::new(g).poll_next(cx),
+			// Events are fed to the handlers directly, see `testing::feed`
+			#[cfg(any(test, feature = "testing"))]
+			Inner::Fake(_) => Poll::Ready(None),
+		}
+	}
+}
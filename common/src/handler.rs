@@ -1,13 +1,8 @@
+use crate::Guild;
 use discord::voice::Updater;
 use discord::{types::Event, GatewayEvent};
 use serde_json::Value;
 
-// We would like to prevent generics bleeding up everywhere.
-// Luckily all of our binaries use the same `S` in `Guild<S>`,
-// which allows us to define our handlers in terms of that type.
-// This might change in the future, forcing us to bite the bullet.
-pub type Guild = discord::Guild<futures::channel::mpsc::UnboundedReceiver<GatewayEvent>>;
-
 pub trait EventHandler {
 	fn config(&mut self, _guild: &Guild, _name: &str, config: Value) -> Option<Value> {
 		Some(config)
@@ -85,6 +80,64 @@ where
 	}
 }
 
+/// Dynamic counterpart of `Chain`, for when the handlers are only known at runtime.
+/// Handlers are called in the order they were pushed
+#[derive(Default)]
+pub struct Pipeline {
+	handlers: Vec<Box<dyn EventHandler>>,
+}
+
+impl Pipeline {
+	pub fn new() -> Self {
+		Self {
+			handlers: Vec::new(),
+		}
+	}
+
+	pub fn push(&mut self, handler: Box<dyn EventHandler>) {
+		self.handlers.push(handler);
+	}
+
+	pub fn len(&self) -> usize {
+		self.handlers.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.handlers.is_empty()
+	}
+}
+
+impl EventHandler for Pipeline {
+	fn config(&mut self, guild: &Guild, name: &str, mut config: Value) -> Option<Value> {
+		for handler in &mut self.handlers {
+			config = handler.config(guild, name, config)?;
+		}
+		Some(config)
+	}
+
+	fn event(&mut self, guild: &Guild, event: &Event) -> bool {
+		self.handlers.iter_mut().all(|h| h.event(guild, event))
+	}
+
+	fn guild_online(&mut self, guild: &Guild) {
+		for handler in &mut self.handlers {
+			handler.guild_online(guild);
+		}
+	}
+
+	fn guild_offline(&mut self, guild: &Guild) {
+		for handler in &mut self.handlers {
+			handler.guild_offline(guild);
+		}
+	}
+
+	fn session_invalidated(&mut self, guild: &Guild) {
+		for handler in &mut self.handlers {
+			handler.session_invalidated(guild);
+		}
+	}
+}
+
 pub trait HasUpdater {
 	fn updater(&mut self) -> &mut Updater;
 }
@@ -0,0 +1,82 @@
+use crate::client::MessageData;
+use crate::Guild;
+use anyhow::Result;
+use discord::client::RowComponent;
+pub use discord::types::Interaction;
+use discord::types::{AllowedMentions, Embed};
+use futures::future::BoxFuture;
+use log::warn;
+
+pub trait Respond {
+	fn respond(&self, guild: &Guild) -> ResponseBuilder;
+}
+
+impl Respond for Interaction {
+	fn respond(&self, guild: &Guild) -> ResponseBuilder {
+		guild.respond(self)
+	}
+}
+
+/// Sends the response, given its message and whether it is ephemeral
+pub(crate) type SendResponse =
+	Box<dyn FnOnce(MessageData, bool) -> BoxFuture<'static, Result<()>> + Send>;
+
+pub struct ResponseBuilder {
+	message: MessageData,
+	ephemeral: bool,
+	send: SendResponse,
+}
+
+impl ResponseBuilder {
+	pub(crate) fn new(send: SendResponse) -> Self {
+		Self {
+			message: MessageData::default(),
+			ephemeral: false,
+			send,
+		}
+	}
+
+	pub fn content<T: Into<String>>(mut self, content: T) -> Self {
+		self.message.content = Some(content.into());
+		self
+	}
+
+	pub fn embed(mut self, embed: Embed) -> Self {
+		self.message.embeds.push(embed);
+		self
+	}
+
+	pub fn component_rows(mut self, rows: Vec<RowComponent>) -> Self {
+		self.message.components = Some(rows);
+		self
+	}
+
+	pub fn allowed_mentions(mut self, allowed_mentions: AllowedMentions) -> Self {
+		self.message.allowed_mentions = Some(allowed_mentions);
+		self
+	}
+
+	/// Only show the response to the user that triggered the interaction
+	pub fn ephemeral(mut self) -> Self {
+		self.ephemeral = true;
+		self
+	}
+
+	pub async fn send(self) -> Result<()> {
+		(self.send)(self.message, self.ephemeral).await
+	}
+
+	/// Send the response in the background, as a task of `spawner`
+	pub fn spawn<S: Spawner>(self, spawner: &S) {
+		spawner.spawn_boxed(Box::pin(async move {
+			if let Err(e) = self.send().await {
+				warn!("Unable to respond to interaction: {}", e);
+			}
+		}));
+	}
+}
+
+/// Runs background tasks on behalf of a bot, which may wait for them on shutdown
+pub trait Spawner {
+	fn spawn_boxed(&self, fut: BoxFuture<'static, ()>);
+}
@@ -1,8 +1,15 @@
+mod client;
 pub mod display;
+mod guild;
 mod handler;
+pub mod interaction;
 // pub mod spotify;
 mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
+pub use client::*;
 pub use discord;
+pub use guild::Guild;
 pub use handler::*;
 pub use storage::*;
@@ -1,10 +1,42 @@
 // use crate::config::Config;
 // use crate::modules::{Configurator, DbConfigurator, FileConfigurator};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+use futures::StreamExt;
+use log::info;
 use sqlx::any::{AnyConnectOptions, AnyKind, AnyPool};
+use sqlx::{query, query_as};
 use std::convert::{TryFrom, TryInto};
 use std::ops::Deref;
 
+const CREATE_SCHEMA_MIGRATIONS: &'static str = r#"
+	CREATE TABLE IF NOT EXISTS schema_migrations (
+		module TEXT NOT NULL,
+		version INTEGER NOT NULL,
+		name TEXT NOT NULL,
+		applied_timestamp BIGINT NOT NULL,
+		PRIMARY KEY (module, version)
+	);
+"#;
+
+/// A named schema change. The migrations of a module are applied in the order
+/// they are declared in, the (1-based) position in that list is their version.
+/// Released migrations must never be reordered, renamed or changed.
+pub struct Migration {
+	pub name: &'static str,
+	pub sqlite: &'static str,
+	pub postgres: &'static str,
+}
+
+impl Migration {
+	fn sql(&self, kind: StorageKind) -> &'static str {
+		match kind {
+			StorageKind::Sqlite => self.sqlite,
+			StorageKind::Postgres => self.postgres,
+		}
+	}
+}
+
 #[derive(Clone)]
 pub struct Storage {
 	kind: StorageKind,
@@ -14,16 +46,87 @@ pub struct Storage {
 impl Storage {
 	pub async fn new(uri: &str) -> Result<Self> {
 		let options: AnyConnectOptions = uri.parse()?;
-		Ok(Self {
+		let storage = Self {
 			kind: options.kind().try_into()?,
 			pool: AnyPool::connect_with(options).await?,
-		})
+		};
+
+		// Create the migration bookkeeping up front, so modules
+		// can run their migrations concurrently afterwards
+		query(CREATE_SCHEMA_MIGRATIONS)
+			.execute(&storage.pool)
+			.await?;
+
+		Ok(storage)
 	}
 
 	pub fn kind(&self) -> StorageKind {
 		self.kind
 	}
 
+	/// Bring the schema of `module` up to date by applying all of its migrations
+	/// that have not been recorded in `schema_migrations` yet. Every migration runs
+	/// in its own transaction. Refuses to continue if the database has migrations
+	/// applied that this binary doesn't know about.
+	pub async fn migrate(&self, module: &str, migrations: &[Migration]) -> Result<()> {
+		let applied = query_as::<_, (i32, String)>(
+			"SELECT version, name FROM schema_migrations WHERE module = $1 ORDER BY version",
+		)
+		.bind(module)
+		.fetch_all(&self.pool)
+		.await?;
+
+		for (version, name) in &applied {
+			match usize::try_from(*version - 1)
+				.ok()
+				.and_then(|i| migrations.get(i))
+			{
+				Some(m) if m.name == *name => {}
+				Some(m) => bail!(
+					"Migration {} of '{}' is '{}' in the database but '{}' in this binary",
+					version,
+					module,
+					name,
+					m.name
+				),
+				None => bail!(
+					"Database schema of '{}' is at version {} ('{}'), ahead of this binary (version {})",
+					module,
+					version,
+					name,
+					migrations.len()
+				),
+			}
+		}
+
+		let current = applied.last().map(|(v, _)| *v as usize).unwrap_or(0);
+		for (i, migration) in migrations.iter().enumerate().skip(current) {
+			let version = i as i32 + 1;
+			info!(
+				"Applying migration {} '{}' of '{}'",
+				version, migration.name, module
+			);
+
+			let mut tx = self.pool.begin().await?;
+			{
+				let mut res = query(migration.sql(self.kind)).execute_many(&mut tx).await;
+				while let Some(r) = res.next().await {
+					r?;
+				}
+			}
+			query("INSERT INTO schema_migrations (module, version, name, applied_timestamp) VALUES ($1, $2, $3, $4)")
+				.bind(module)
+				.bind(version)
+				.bind(migration.name)
+				.bind(Utc::now().timestamp())
+				.execute(&mut tx)
+				.await?;
+			tx.commit().await?;
+		}
+
+		Ok(())
+	}
+
 	/*pub fn configurator(&self, config: &Config) -> Result<Configurator> {
 		match self.kind {
 			StorageKind::Sqlite => Ok(Configurator::File(FileConfigurator::new(
@@ -66,8 +169,90 @@ impl TryFrom<AnyKind> for StorageKind {
 	fn try_from(kind: AnyKind) -> Result<Self> {
 		match kind {
 			AnyKind::Sqlite => Ok(StorageKind::Sqlite),
-			// AnyKind::Postgres => Ok(StorageKind::Postgres),
+			AnyKind::Postgres => Ok(StorageKind::Postgres),
+			// Only reachable when sqlx is built with additional drivers
+			#[allow(unreachable_patterns)]
 			_ => Err(anyhow!("Unsupported db kind")),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::process;
+
+	const FIRST: Migration = Migration {
+		name: "create_things",
+		sqlite: "CREATE TABLE things (id INTEGER PRIMARY KEY NOT NULL);",
+		postgres: "CREATE TABLE things (id BIGINT PRIMARY KEY NOT NULL);",
+	};
+
+	const SECOND: Migration = Migration {
+		name: "add_things_name",
+		sqlite: "ALTER TABLE things ADD COLUMN name TEXT;",
+		postgres: "ALTER TABLE things ADD COLUMN name TEXT;",
+	};
+
+	async fn storage(name: &str) -> Storage {
+		let path = env::temp_dir().join(format!("dnbot_{}_{}.db", name, process::id()));
+		let _ = std::fs::remove_file(&path);
+		Storage::new(&format!("sqlite://{}?mode=rwc", path.display()))
+			.await
+			.unwrap()
+	}
+
+	async fn versions(storage: &Storage) -> Vec<(i32, String)> {
+		query_as(
+			"SELECT version, name FROM schema_migrations WHERE module = 'test' ORDER BY version",
+		)
+		.fetch_all(&**storage)
+		.await
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn migrate() {
+		let storage = storage("migrate").await;
+
+		storage.migrate("test", &[FIRST]).await.unwrap();
+		assert_eq!(versions(&storage).await, vec![(1, FIRST.name.to_owned())]);
+
+		// Re-running is a no-op, new migrations are applied on top
+		storage.migrate("test", &[FIRST]).await.unwrap();
+		storage.migrate("test", &[FIRST, SECOND]).await.unwrap();
+		assert_eq!(
+			versions(&storage).await,
+			vec![(1, FIRST.name.to_owned()), (2, SECOND.name.to_owned())]
+		);
+		query("INSERT INTO things (id, name) VALUES (1, 'thing')")
+			.execute(&*storage)
+			.await
+			.unwrap();
+	}
+
+	#[tokio::test]
+	async fn migrate_ahead() {
+		let storage = storage("migrate_ahead").await;
+		storage.migrate("test", &[FIRST, SECOND]).await.unwrap();
+		assert!(storage.migrate("test", &[FIRST]).await.is_err());
+		assert!(storage.migrate("test", &[SECOND, FIRST]).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn migrate_failure_rolls_back() {
+		let storage = storage("migrate_failure").await;
+		let broken = Migration {
+			name: "broken",
+			sqlite: "CREATE TABLE others (id INTEGER); SELECT * FROM missing;",
+			postgres: "CREATE TABLE others (id BIGINT); SELECT * FROM missing;",
+		};
+		assert!(storage.migrate("test", &[FIRST, broken]).await.is_err());
+		assert_eq!(versions(&storage).await, vec![(1, FIRST.name.to_owned())]);
+		assert!(query("SELECT * FROM others")
+			.execute(&*storage)
+			.await
+			.is_err());
+	}
+}
@@ -0,0 +1,666 @@
+//! Support for testing modules without talking to Discord.
+//!
+//! `StandIn` is a local HTTP server that records every request it receives and
+//! answers with canned responses, so REST calls can be asserted on. `GuildState`
+//! describes a synthetic guild and serves it from the stand-in on the same routes
+//! the Discord API uses.
+//!
+//! `GuildState::guild` turns the description into a `Guild` without a gateway
+//! connection. Its `Client` and interaction responses send their requests to the
+//! stand-in, so a module can be driven with `feed` and its REST calls checked.
+
+use crate::client::MessageData;
+use crate::interaction::ResponseBuilder;
+use crate::{Client, EventHandler, Guild, Storage};
+use anyhow::Result;
+use discord::types::{
+	ApplicationCommand, ApplicationCommandOptionType, ApplicationId, Channel, Event, GuildId,
+	Interaction, Member, Role, UserId,
+};
+use futures::channel::oneshot;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use url::form_urlencoded;
+use warp::http::{HeaderMap, Method, Response as HttpResponse, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::Filter;
+
+/// Feed `events` through `handler` in order, returning for every event
+/// whether the handler passed it on
+pub fn feed<H>(handler: &mut H, guild: &Guild, events: &[Event]) -> Vec<bool>
+where
+	H: EventHandler + ?Sized,
+{
+	events.iter().map(|e| handler.event(guild, e)).collect()
+}
+
+/// Storage backed by a fresh SQLite database in the temp directory
+pub async fn temp_storage(name: &str) -> Storage {
+	let path = std::env::temp_dir().join(format!("dnbot_{}_{}.db", name, std::process::id()));
+	let _ = std::fs::remove_file(&path);
+	Storage::new(&format!("sqlite://{}?mode=rwc", path.display()))
+		.await
+		.expect("unable to create storage")
+}
+
+#[derive(Clone, Debug)]
+pub struct Request {
+	pub method: Method,
+	pub path: String,
+	pub query: HashMap<String, String>,
+	pub headers: HeaderMap,
+	pub body: Bytes,
+}
+
+impl Request {
+	pub fn is(&self, method: Method, path: &str) -> bool {
+		self.method == method && self.path == path
+	}
+
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.get(name).and_then(|h| h.to_str().ok())
+	}
+
+	pub fn json(&self) -> Option<Value> {
+		serde_json::from_slice(&self.body).ok()
+	}
+
+	/// Body of an `application/x-www-form-urlencoded` request
+	pub fn form(&self) -> HashMap<String, String> {
+		form_urlencoded::parse(&self.body).into_owned().collect()
+	}
+}
+
+#[derive(Clone, Debug)]
+struct Response {
+	method: Method,
+	path: String,
+	status: StatusCode,
+	content_type: &'static str,
+	body: String,
+}
+
+#[derive(Default)]
+struct State {
+	requests: Vec<Request>,
+	// Most recently added response takes precedence
+	responses: Vec<Response>,
+}
+
+impl State {
+	fn response(&self, method: &Method, path: &str) -> HttpResponse<Body> {
+		let response = self
+			.responses
+			.iter()
+			.rev()
+			.find(|r| &r.method == method && r.path == path);
+		let (status, content_type, body) = match response {
+			Some(r) => (r.status, r.content_type, r.body.clone()),
+			None => (StatusCode::OK, "application/json", "{}".to_owned()),
+		};
+		let mut response = HttpResponse::new(Body::from(body));
+		*response.status_mut() = status;
+		if let Ok(content_type) = content_type.parse() {
+			response.headers_mut().insert("content-type", content_type);
+		}
+		response
+	}
+}
+
+/// Local HTTP server that records requests and replies with canned responses.
+/// Unknown routes are answered with `200 {}`
+pub struct StandIn {
+	addr: SocketAddr,
+	state: Arc<Mutex<State>>,
+	shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl StandIn {
+	pub async fn start() -> Self {
+		let state = Arc::new(Mutex::new(State::default()));
+		let filter_state = Arc::clone(&state);
+		let filter = warp::method()
+			.and(warp::path::full())
+			.and(warp::query::<HashMap<String, String>>())
+			.and(warp::header::headers_cloned())
+			.and(warp::body::bytes())
+			.map(
+				move |method: Method, path: warp::path::FullPath, query, headers, body| {
+					let mut state = filter_state.lock().unwrap();
+					let path = path.as_str().to_owned();
+					let response = state.response(&method, &path);
+					state.requests.push(Request {
+						method,
+						path,
+						query,
+						headers,
+						body,
+					});
+					response
+				},
+			);
+
+		let (shutdown, recv) = oneshot::channel();
+		let (addr, server) =
+			warp::serve(filter).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async {
+				let _ = recv.await;
+			});
+		tokio::spawn(server);
+
+		Self {
+			addr,
+			state,
+			shutdown: Some(shutdown),
+		}
+	}
+
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Base URL of the stand-in, without trailing slash
+	pub fn url(&self) -> String {
+		format!("http://{}", self.addr)
+	}
+
+	/// REST client that sends its requests here
+	pub fn client(&self) -> Client {
+		Client::stand_in(self.api())
+	}
+
+	fn api(&self) -> Api {
+		Api {
+			http: reqwest::Client::new(),
+			url: self.url(),
+		}
+	}
+
+	pub fn respond<T>(&self, method: Method, path: &str, status: u16, body: T)
+	where
+		T: Into<String>,
+	{
+		self.add_response(method, path, status, "text/plain", body.into());
+	}
+
+	pub fn respond_json(&self, method: Method, path: &str, status: u16, body: Value) {
+		self.add_response(method, path, status, "application/json", body.to_string());
+	}
+
+	fn add_response(
+		&self,
+		method: Method,
+		path: &str,
+		status: u16,
+		content_type: &'static str,
+		body: String,
+	) {
+		let status = StatusCode::from_u16(status).expect("invalid status code");
+		self.state.lock().unwrap().responses.push(Response {
+			method,
+			path: path.to_owned(),
+			status,
+			content_type,
+			body,
+		});
+	}
+
+	/// All requests received so far
+	pub fn requests(&self) -> Vec<Request> {
+		self.state.lock().unwrap().requests.clone()
+	}
+
+	/// Requests received so far, clearing the record
+	pub fn take_requests(&self) -> Vec<Request> {
+		std::mem::take(&mut self.state.lock().unwrap().requests)
+	}
+
+	/// Wait until at least `count` requests have been received. Most handlers
+	/// perform their REST calls on a spawned task, so they arrive asynchronously
+	pub async fn wait_for(&self, count: usize, timeout: Duration) -> Vec<Request> {
+		let deadline = Instant::now() + timeout;
+		loop {
+			let requests = self.requests();
+			if requests.len() >= count || Instant::now() >= deadline {
+				return requests;
+			}
+			sleep(Duration::from_millis(10)).await;
+		}
+	}
+}
+
+impl Drop for StandIn {
+	fn drop(&mut self) {
+		if let Some(shutdown) = self.shutdown.take() {
+			let _ = shutdown.send(());
+		}
+	}
+}
+
+/// Synthetic guild, in the shape of the Discord API objects
+pub struct GuildState {
+	id: u64,
+	application_id: u64,
+	user_id: u64,
+	name: String,
+	roles: Vec<Value>,
+	channels: Vec<Value>,
+	members: Vec<Value>,
+	commands: Vec<Value>,
+}
+
+impl GuildState {
+	pub fn new(id: u64, application_id: u64, name: &str) -> Self {
+		Self {
+			id,
+			application_id,
+			user_id: application_id,
+			name: name.to_owned(),
+			// Every guild has an @everyone role with the same id as the guild
+			roles: vec![role_json(id, "@everyone", 0)],
+			channels: Vec::new(),
+			members: Vec::new(),
+			commands: Vec::new(),
+		}
+	}
+
+	/// User id of the bot, the same as the application id unless set
+	pub fn bot_user(mut self, user_id: u64) -> Self {
+		self.user_id = user_id;
+		self
+	}
+
+	pub fn role(mut self, id: u64, name: &str, position: i32) -> Self {
+		self.roles.push(role_json(id, name, position));
+		self
+	}
+
+	pub fn text_channel(mut self, id: u64, name: &str) -> Self {
+		self.channels.push(json!({
+			"id": id.to_string(),
+			"type": 0,
+			"guild_id": self.id.to_string(),
+			"name": name,
+			"position": self.channels.len(),
+		}));
+		self
+	}
+
+	/// Public thread in the channel `parent_id`
+	pub fn thread(mut self, id: u64, parent_id: u64, name: &str) -> Self {
+		self.channels.push(json!({
+			"id": id.to_string(),
+			"type": 11,
+			"guild_id": self.id.to_string(),
+			"parent_id": parent_id.to_string(),
+			"name": name,
+		}));
+		self
+	}
+
+	pub fn member(mut self, user_id: u64, username: &str, roles: &[u64]) -> Self {
+		self.members.push(json!({
+			"user": {
+				"id": user_id.to_string(),
+				"username": username,
+				"discriminator": "0",
+				"avatar": null,
+			},
+			"roles": roles.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+			"joined_at": "2020-01-01T00:00:00+00:00",
+			"deaf": false,
+			"mute": false,
+		}));
+		self
+	}
+
+	pub fn command(mut self, id: u64, name: &str, description: &str) -> Self {
+		self.commands.push(json!({
+			"id": id.to_string(),
+			"application_id": self.application_id.to_string(),
+			"guild_id": self.id.to_string(),
+			"name": name,
+			"description": description,
+			"options": [],
+			"version": "1",
+		}));
+		self
+	}
+
+	/// Data of the `GUILD_CREATE` gateway event for this guild
+	pub fn guild_create(&self) -> Value {
+		json!({
+			"id": self.id.to_string(),
+			"name": self.name,
+			"owner_id": "0",
+			"roles": self.roles,
+			"channels": self.channels,
+			"members": self.members,
+			"member_count": self.members.len(),
+			"emojis": [],
+			"features": [],
+			"voice_states": [],
+			"threads": [],
+		})
+	}
+
+	/// Message object of `user_id`, as sent in `MESSAGE_CREATE`. The author has
+	/// to be one of the members
+	pub fn message(&self, id: u64, channel_id: u64, user_id: u64, content: &str) -> Value {
+		let mut member = self.member_json(user_id);
+		let author = member["user"].take();
+		if let Some(member) = member.as_object_mut() {
+			member.remove("user");
+		}
+		json!({
+			"id": id.to_string(),
+			"type": 0,
+			"channel_id": channel_id.to_string(),
+			"guild_id": self.id.to_string(),
+			"author": author,
+			"member": member,
+			"content": content,
+			"timestamp": "2020-01-01T00:00:00+00:00",
+			"tts": false,
+			"mention_everyone": false,
+			"mentions": [],
+			"mention_roles": [],
+			"attachments": [],
+			"embeds": [],
+			"pinned": false,
+		})
+	}
+
+	/// Interaction object of `user_id` using the slash command `name` in `channel_id`,
+	/// as sent in `INTERACTION_CREATE`. Options are given as name, type and value
+	pub fn command_interaction(
+		&self,
+		id: u64,
+		channel_id: u64,
+		user_id: u64,
+		name: &str,
+		options: &[(&str, ApplicationCommandOptionType, &str)],
+	) -> Value {
+		let options: Vec<_> = options
+			.iter()
+			.map(|(name, option_type, value)| {
+				// Integers are sent as numbers, users as their id
+				let (option_type, value) = match option_type {
+					ApplicationCommandOptionType::String => (3, json!(value)),
+					ApplicationCommandOptionType::Integer => {
+						let value: i64 = value.parse().expect("invalid integer option");
+						(4, json!(value))
+					}
+					ApplicationCommandOptionType::User => (6, json!(value)),
+					_ => unimplemented!("option type of '{}'", name),
+				};
+				json!({ "name": name, "type": option_type, "value": value })
+			})
+			.collect();
+		let data = json!({ "id": "0", "name": name, "type": 1, "options": options });
+		self.interaction_json(id, 2, channel_id, user_id, data)
+	}
+
+	/// Interaction object of `user_id` clicking the button `custom_id` in `channel_id`
+	pub fn button_interaction(
+		&self,
+		id: u64,
+		channel_id: u64,
+		user_id: u64,
+		custom_id: &str,
+	) -> Value {
+		let data = json!({ "custom_id": custom_id, "component_type": 2 });
+		self.interaction_json(id, 3, channel_id, user_id, data)
+	}
+
+	fn interaction_json(
+		&self,
+		id: u64,
+		kind: u8,
+		channel_id: u64,
+		user_id: u64,
+		data: Value,
+	) -> Value {
+		json!({
+			"id": id.to_string(),
+			"application_id": self.application_id.to_string(),
+			"type": kind,
+			"data": data,
+			"guild_id": self.id.to_string(),
+			"channel_id": channel_id.to_string(),
+			"member": self.member_json(user_id),
+			"token": format!("token{}", id),
+			"version": 1,
+		})
+	}
+
+	fn member_json(&self, user_id: u64) -> Value {
+		let user_id = user_id.to_string();
+		self.members
+			.iter()
+			.find(|m| m["user"]["id"] == user_id.as_str())
+			.cloned()
+			.expect("unknown member")
+	}
+
+	/// This guild without a gateway connection. REST calls made through it go to
+	/// `stand_in`
+	pub fn guild(&self, stand_in: &StandIn) -> Guild {
+		fn parse<T: serde::de::DeserializeOwned>(values: &[Value]) -> Vec<T> {
+			values
+				.iter()
+				.map(|v| serde_json::from_value(v.clone()).expect("invalid guild state"))
+				.collect()
+		}
+
+		Guild::fake(FakeGuild {
+			id: self.id.into(),
+			application_id: self.application_id.into(),
+			user_id: self.user_id.into(),
+			name: self.name.clone(),
+			api: stand_in.api(),
+			channels: parse(&self.channels),
+			roles: parse(&self.roles),
+			members: parse(&self.members),
+			commands: parse(&self.commands),
+		})
+	}
+
+	/// Serve the REST endpoints a client uses to look up this guild's state
+	pub fn serve(&self, stand_in: &StandIn) {
+		let guild = format!("/guilds/{}", self.id);
+		stand_in.respond_json(Method::GET, &guild, 200, self.guild_create());
+		stand_in.respond_json(
+			Method::GET,
+			&format!("{}/roles", guild),
+			200,
+			json!(self.roles),
+		);
+		stand_in.respond_json(
+			Method::GET,
+			&format!("{}/channels", guild),
+			200,
+			json!(self.channels),
+		);
+		stand_in.respond_json(
+			Method::GET,
+			&format!("{}/members", guild),
+			200,
+			json!(self.members),
+		);
+		for member in &self.members {
+			let user_id = member["user"]["id"].as_str().unwrap_or_default();
+			stand_in.respond_json(
+				Method::GET,
+				&format!("{}/members/{}", guild, user_id),
+				200,
+				member.clone(),
+			);
+		}
+		stand_in.respond_json(
+			Method::GET,
+			&format!(
+				"/applications/{}/guilds/{}/commands",
+				self.application_id, self.id
+			),
+			200,
+			json!(self.commands),
+		);
+	}
+}
+
+/// Guild built from a `GuildState`
+pub(crate) struct FakeGuild {
+	pub id: GuildId,
+	pub application_id: ApplicationId,
+	pub user_id: UserId,
+	pub name: String,
+	pub api: Api,
+	pub channels: Vec<Channel>,
+	pub roles: Vec<Role>,
+	pub members: Vec<Member>,
+	pub commands: Vec<ApplicationCommand>,
+}
+
+/// Backend of `Client` in tests, sending requests to the Discord routes of a `StandIn`
+#[derive(Clone)]
+pub(crate) struct Api {
+	http: reqwest::Client,
+	url: String,
+}
+
+impl Api {
+	pub async fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+		let mut request = self.http.request(method, format!("{}{}", self.url, path));
+		if let Some(body) = body {
+			request = request
+				.header("content-type", "application/json")
+				.body(body.to_string());
+		}
+		let bytes = request.send().await?.error_for_status()?.bytes().await?;
+		if bytes.is_empty() {
+			return Ok(Value::Null);
+		}
+		Ok(serde_json::from_slice(&bytes)?)
+	}
+
+	/// Response to `interaction`, sent to its callback route
+	pub fn respond(&self, interaction: &Interaction) -> ResponseBuilder {
+		// Clicks on a component update the message the component is attached to
+		let kind = if interaction.interaction_type.is_component_interaction() {
+			7
+		} else {
+			4
+		};
+		let path = format!(
+			"/interactions/{}/{}/callback",
+			interaction.id, interaction.token
+		);
+		let api = self.clone();
+		ResponseBuilder::new(Box::new(move |message, ephemeral| {
+			Box::pin(async move {
+				let mut data = message_json(message)?;
+				if ephemeral {
+					data["flags"] = json!(64);
+				}
+				let body = json!({ "type": kind, "data": data });
+				api.send(Method::POST, &path, Some(body)).await?;
+				Ok(())
+			})
+		}))
+	}
+}
+
+/// Body of a message or interaction response, in the shape of the Discord API
+pub(crate) fn message_json(message: MessageData) -> Result<Value> {
+	let mut json = json!({});
+	if let Some(content) = message.content {
+		json["content"] = json!(content);
+	}
+	if !message.embeds.is_empty() {
+		json["embeds"] = serde_json::to_value(message.embeds)?;
+	}
+	if let Some(components) = message.components {
+		json["components"] = serde_json::to_value(components)?;
+	}
+	if let Some(allowed_mentions) = message.allowed_mentions {
+		json["allowed_mentions"] = serde_json::to_value(allowed_mentions)?;
+	}
+	Ok(json)
+}
+
+fn role_json(id: u64, name: &str, position: i32) -> Value {
+	json!({
+		"id": id.to_string(),
+		"name": name,
+		"color": 0,
+		"hoist": false,
+		"position": position,
+		"permissions": "0",
+		"managed": false,
+		"mentionable": false,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn stand_in_records() {
+		let stand_in = StandIn::start().await;
+		GuildState::new(1, 2, "Test")
+			.role(3, "Moderator", 1)
+			.text_channel(4, "general")
+			.member(5, "someone", &[3])
+			.command(6, "joined", "See how long ago a user joined the server")
+			.serve(&stand_in);
+		stand_in.respond(Method::POST, "/hub", 202, "");
+
+		let client = reqwest::Client::new();
+		let commands = client
+			.get(format!(
+				"{}/applications/2/guilds/1/commands",
+				stand_in.url()
+			))
+			.send()
+			.await
+			.unwrap()
+			.bytes()
+			.await
+			.unwrap();
+		let commands: Value = serde_json::from_slice(&commands).unwrap();
+		assert_eq!(commands[0]["name"], "joined");
+
+		let res = client
+			.post(format!("{}/hub?x=1", stand_in.url()))
+			.form(&[("hub.mode", "subscribe"), ("hub.topic", "a b&c")])
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status().as_u16(), 202);
+
+		let res = client
+			.post(format!("{}/channels/4/messages", stand_in.url()))
+			.header("content-type", "application/json")
+			.body(json!({ "content": "Hello" }).to_string())
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(res.status().as_u16(), 200);
+
+		let requests = stand_in.take_requests();
+		assert_eq!(requests.len(), 3);
+		assert!(requests[1].is(Method::POST, "/hub"));
+		assert_eq!(requests[1].query.get("x").map(|x| x.as_str()), Some("1"));
+		let form = requests[1].form();
+		assert_eq!(form.get("hub.mode").map(|x| x.as_str()), Some("subscribe"));
+		assert_eq!(form.get("hub.topic").map(|x| x.as_str()), Some("a b&c"));
+		assert_eq!(requests[2].json().unwrap()["content"], "Hello");
+		assert!(stand_in.requests().is_empty());
+	}
+}
@@ -0,0 +1,363 @@
+use crate::supervisor::Tracker;
+use common::discord::types::{ApplicationCommand, ApplicationCommandOption};
+use common::Guild;
+use futures::lock::Mutex as AsyncMutex;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+// Declarations arriving in quick succession, like the configs at startup, are synced together
+const SETTLE: Duration = Duration::from_secs(3);
+// Pause between requests to Discord, to stay clear of the rate limit
+const REQUEST_DELAY: Duration = Duration::from_secs(1);
+// Time to wait before retrying a sync with failed requests, doubled after every failure
+const RETRY_MIN: Duration = Duration::from_secs(30);
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Slash command as a module wants it to be registered
+#[derive(Clone, Debug)]
+pub struct CommandSpec {
+	pub name: String,
+	pub description: String,
+	pub options: Vec<ApplicationCommandOption>,
+}
+
+impl CommandSpec {
+	pub fn new<N, D>(name: N, description: D, options: Vec<ApplicationCommandOption>) -> Self
+	where
+		N: Into<String>,
+		D: Into<String>,
+	{
+		Self {
+			name: name.into(),
+			description: description.into(),
+			options,
+		}
+	}
+
+	/// Whether a command with this description and options is out of date
+	fn differs(&self, description: &str, options: &[ApplicationCommandOption]) -> bool {
+		self.description != description
+			|| self.options.len() != options.len()
+			|| self.options.iter().zip(options).any(|(a, b)| {
+				a.option_type != b.option_type
+					|| a.name != b.name
+					|| a.description != b.description
+					|| a.required != b.required
+					|| a.choices.len() != b.choices.len()
+					|| a.choices
+						.iter()
+						.zip(&b.choices)
+						.any(|(a, b)| a.name != b.name || a.value != b.value)
+			})
+	}
+}
+
+#[derive(Default)]
+struct State {
+	// Modules that declare commands
+	expected: HashSet<&'static str>,
+	// Commands declared by every module, by module name
+	declared: HashMap<&'static str, Vec<CommandSpec>>,
+	// When the declarations last changed, if they haven't been synced since
+	changed: Option<Instant>,
+	// Consecutive syncs with failed requests, used for the backoff
+	failures: u32,
+	// Commands we created (`Some`) or deleted (`None`). The guild doesn't
+	// necessarily learn about these, so they take precedence over its commands
+	synced: HashMap<String, Option<CommandSpec>>,
+}
+
+/// Keeps the slash commands of the guild in line with the ones the modules declare.
+/// Modules declare their commands whenever their config changes, after which the
+/// registry creates the missing and outdated commands and deletes the stale ones
+#[derive(Clone)]
+pub struct CommandRegistry {
+	state: Arc<Mutex<State>>,
+	// Only one sync talks to Discord at a time
+	lock: Arc<AsyncMutex<()>>,
+	tracker: Tracker,
+}
+
+impl CommandRegistry {
+	pub fn new(tracker: Tracker) -> Self {
+		Self {
+			state: Default::default(),
+			lock: Default::default(),
+			tracker,
+		}
+	}
+
+	/// Let the registry know `module` declares commands. Stale commands are only deleted
+	/// once every such module has declared, so the commands of a module that declares late,
+	/// or whose config doesn't load, aren't deleted and recreated
+	pub fn expect(&self, module: &'static str) {
+		self.state.lock().unwrap().expected.insert(module);
+	}
+
+	/// Replace the commands of `module`. They are synced once the declarations settle
+	pub fn declare(&self, module: &'static str, commands: Vec<CommandSpec>) {
+		let mut state = self.state.lock().unwrap();
+		state.declared.insert(module, commands);
+		state.changed = Some(Instant::now());
+		state.failures = 0;
+	}
+
+	/// When the next sync is due, if any
+	pub fn deadline(&self) -> Option<Instant> {
+		let state = self.state.lock().unwrap();
+		let backoff = match state.failures {
+			0 => Duration::ZERO,
+			f => RETRY_MIN
+				.saturating_mul(2u32.saturating_pow(f - 1))
+				.min(RETRY_MAX),
+		};
+		state.changed.map(|c| c + SETTLE + backoff)
+	}
+
+	/// Bring the commands of `guild` in line with the declared ones
+	pub fn sync(&self, guild: &Guild) {
+		let (create, delete) = {
+			let mut state = self.state.lock().unwrap();
+			state.changed = None;
+			state.diff(guild)
+		};
+		if create.is_empty() && delete.is_empty() {
+			debug!("Commands up to date");
+			return;
+		}
+		info!(
+			"Syncing commands: {} to register, {} to delete",
+			create.len(),
+			delete.len()
+		);
+
+		let state = Arc::clone(&self.state);
+		let lock = Arc::clone(&self.lock);
+		let client = guild.client();
+		let application_id = guild.application_id();
+		let guild_id = guild.id();
+		self.tracker.spawn(async move {
+			let _guard = lock.lock().await;
+			let mut failed = false;
+			for spec in create {
+				match client
+					.create_command(
+						application_id,
+						guild_id,
+						&spec.name,
+						&spec.description,
+						spec.options.clone(),
+					)
+					.await
+				{
+					Ok(_) => {
+						debug!("Registered '{}'", spec.name);
+						let mut state = state.lock().unwrap();
+						state.synced.insert(spec.name.clone(), Some(spec));
+					}
+					Err(e) => {
+						warn!("Unable to register '{}': {}", spec.name, e);
+						failed = true;
+					}
+				}
+				sleep(REQUEST_DELAY).await;
+			}
+			for command in delete {
+				match client
+					.delete_command(application_id, guild_id, &command)
+					.await
+				{
+					Ok(_) => {
+						debug!("Deleted '{}'", command.name);
+						state.lock().unwrap().synced.insert(command.name, None);
+					}
+					Err(e) => {
+						warn!("Unable to delete '{}': {}", command.name, e);
+						failed = true;
+					}
+				}
+				sleep(REQUEST_DELAY).await;
+			}
+
+			// Try again later, unless the declarations changed in the meantime anyway
+			let mut state = state.lock().unwrap();
+			if failed {
+				state.failures += 1;
+				state.changed.get_or_insert_with(Instant::now);
+			} else {
+				state.failures = 0;
+			}
+		});
+	}
+}
+
+impl State {
+	/// Commands that have to be created or updated, and commands that have to be deleted
+	fn diff(&self, guild: &Guild) -> (Vec<CommandSpec>, Vec<ApplicationCommand>) {
+		let mut names = HashSet::new();
+		let mut create = Vec::new();
+		let mut modules: Vec<_> = self.declared.iter().collect();
+		modules.sort_by_key(|&(m, _)| *m);
+		for (module, commands) in modules {
+			for spec in commands {
+				if !names.insert(spec.name.as_str()) {
+					warn!(
+						"Command '{}' of module '{}' is already declared elsewhere",
+						spec.name, module
+					);
+					continue;
+				}
+				let differs = match self.synced.get(&spec.name) {
+					Some(Some(s)) => spec.differs(&s.description, &s.options),
+					Some(None) => true,
+					None => match guild.command(&spec.name) {
+						Some(c) => spec.differs(&c.description, &c.options),
+						None => true,
+					},
+				};
+				if differs {
+					create.push(spec.clone());
+				}
+			}
+		}
+
+		let waiting = self
+			.expected
+			.iter()
+			.filter(|m| !self.declared.contains_key(*m))
+			.count();
+		if waiting > 0 {
+			debug!("Waiting for {} more modules to declare commands", waiting);
+			return (create, Vec::new());
+		}
+
+		let delete = guild
+			.commands()
+			.filter(|c| !names.contains(c.name.as_str()))
+			.filter(|c| !matches!(self.synced.get(&c.name), Some(None)))
+			.cloned()
+			.collect();
+		(create, delete)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use common::testing::{GuildState, StandIn};
+	use warp::http::Method;
+
+	#[tokio::test]
+	async fn syncs() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.command(3, "hello", "Say hello")
+			.command(4, "stale", "No longer used")
+			.command(5, "outdated", "Old description");
+		let guild = state.guild(&stand_in);
+
+		let registry = CommandRegistry::new(Supervisor::new().tracker());
+		assert!(registry.deadline().is_none());
+		registry.declare(
+			"a",
+			vec![
+				CommandSpec::new("hello", "Say hello", Vec::new()),
+				CommandSpec::new("outdated", "New description", Vec::new()),
+			],
+		);
+		registry.declare("b", vec![CommandSpec::new("new", "Brand new", Vec::new())]);
+		assert!(registry.deadline().is_some());
+
+		registry.sync(&guild);
+		assert!(registry.deadline().is_none());
+		let requests = stand_in.wait_for(3, Duration::from_secs(10)).await;
+		assert_eq!(requests.len(), 3);
+		assert!(requests[0].is(Method::POST, "/applications/2/guilds/1/commands"));
+		assert_eq!(requests[0].json().unwrap()["name"], "outdated");
+		assert!(requests[1].is(Method::POST, "/applications/2/guilds/1/commands"));
+		assert_eq!(requests[1].json().unwrap()["name"], "new");
+		assert!(requests[2].is(Method::DELETE, "/applications/2/guilds/1/commands/4"));
+		sleep(Duration::from_millis(100)).await;
+		stand_in.take_requests();
+
+		// Nothing left to do, even though the guild doesn't know about the changes
+		registry.sync(&guild);
+		sleep(Duration::from_millis(100)).await;
+		assert!(stand_in.requests().is_empty());
+	}
+
+	#[tokio::test]
+	async fn waits_for_modules() {
+		let stand_in = StandIn::start().await;
+		let guild = GuildState::new(1, 2, "Test")
+			.command(3, "hello", "Say hello")
+			.command(4, "later", "Declared late")
+			.command(5, "stale", "No longer used")
+			.guild(&stand_in);
+
+		let registry = CommandRegistry::new(Supervisor::new().tracker());
+		registry.expect("a");
+		registry.expect("b");
+		registry.declare(
+			"a",
+			vec![CommandSpec::new("hello", "Say hello", Vec::new())],
+		);
+		registry.sync(&guild);
+		sleep(Duration::from_millis(100)).await;
+		assert!(stand_in.requests().is_empty());
+
+		// Stale commands are deleted once every module has declared
+		registry.declare(
+			"b",
+			vec![CommandSpec::new("later", "Declared late", Vec::new())],
+		);
+		registry.sync(&guild);
+		let requests = stand_in.wait_for(1, Duration::from_secs(10)).await;
+		sleep(Duration::from_millis(100)).await;
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].is(Method::DELETE, "/applications/2/guilds/1/commands/5"));
+		assert_eq!(stand_in.take_requests().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn retries_failures() {
+		let stand_in = StandIn::start().await;
+		let guild = GuildState::new(1, 2, "Test")
+			.command(3, "stale", "No longer used")
+			.guild(&stand_in);
+		stand_in.respond(Method::POST, "/applications/2/guilds/1/commands", 500, "");
+		stand_in.respond(
+			Method::DELETE,
+			"/applications/2/guilds/1/commands/3",
+			500,
+			"",
+		);
+
+		let registry = CommandRegistry::new(Supervisor::new().tracker());
+		registry.declare("a", vec![CommandSpec::new("new", "Brand new", Vec::new())]);
+		registry.sync(&guild);
+		assert_eq!(stand_in.wait_for(2, Duration::from_secs(10)).await.len(), 2);
+		// The state is updated after the delay of the last request
+		sleep(REQUEST_DELAY + Duration::from_millis(100)).await;
+		stand_in.take_requests();
+
+		// Failed requests are retried after a backoff
+		let deadline = registry.deadline().unwrap();
+		assert!(deadline > Instant::now() + SETTLE + RETRY_MIN - Duration::from_secs(5));
+		stand_in.respond(Method::POST, "/applications/2/guilds/1/commands", 200, "{}");
+		stand_in.respond(
+			Method::DELETE,
+			"/applications/2/guilds/1/commands/3",
+			204,
+			"",
+		);
+		registry.sync(&guild);
+		assert_eq!(stand_in.wait_for(2, Duration::from_secs(10)).await.len(), 2);
+		sleep(REQUEST_DELAY + Duration::from_millis(100)).await;
+		assert!(registry.deadline().is_none());
+	}
+}
@@ -16,6 +16,9 @@ pub struct Config {
 	pub db_uri: String,
 	#[serde(default)]
 	pub module_config_dir: Option<PathBuf>,
+	/// Comma separated list of modules to load, in order of priority
+	#[serde(default)]
+	pub modules: Option<Vec<String>>,
 }
 
 impl Config {
@@ -0,0 +1,234 @@
+use common::discord::types::{ChannelId, RoleId, UserId};
+use common::interaction::Interaction;
+use common::Guild;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CooldownScope {
+	#[default]
+	Global,
+	Channel,
+	User,
+}
+
+/// How often a command can be used. Either a number of seconds, which applies
+/// to everyone at once, or a table with the scope, burst and bypass role
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(from = "CooldownRepr")]
+pub struct CooldownConfig {
+	seconds: u32,
+	scope: CooldownScope,
+	// Uses allowed within `seconds` before the cooldown kicks in
+	burst: Option<u32>,
+	bypass_minimum_role: Option<RoleId>,
+}
+
+impl CooldownConfig {
+	fn window(&self) -> Duration {
+		Duration::from_secs(self.seconds as u64)
+	}
+
+	fn burst(&self) -> usize {
+		self.burst.unwrap_or(1).max(1) as usize
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CooldownRepr {
+	Seconds(u32),
+	Full {
+		seconds: u32,
+		#[serde(default)]
+		scope: CooldownScope,
+		#[serde(default)]
+		burst: Option<u32>,
+		#[serde(default)]
+		bypass_minimum_role: Option<RoleId>,
+	},
+}
+
+impl From<CooldownRepr> for CooldownConfig {
+	fn from(repr: CooldownRepr) -> Self {
+		match repr {
+			CooldownRepr::Seconds(seconds) => Self {
+				seconds,
+				..Default::default()
+			},
+			CooldownRepr::Full {
+				seconds,
+				scope,
+				burst,
+				bypass_minimum_role,
+			} => Self {
+				seconds,
+				scope,
+				burst,
+				bypass_minimum_role,
+			},
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+	Global,
+	Channel(ChannelId),
+	User(UserId),
+}
+
+#[derive(Debug)]
+struct Uses {
+	window: Duration,
+	times: VecDeque<Instant>,
+}
+
+impl Uses {
+	fn prune(&mut self, now: Instant) {
+		while let Some(&t) = self.times.front() {
+			if now.duration_since(t) < self.window {
+				break;
+			}
+			self.times.pop_front();
+		}
+	}
+}
+
+/// Keeps track of recent uses of commands, to put them on cooldown
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+	uses: HashMap<(String, Key), Uses>,
+}
+
+impl Cooldowns {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Register a use of `command` through `interaction`. If the command is on
+	/// cooldown nothing is registered and the time left is returned instead
+	pub fn check(
+		&mut self,
+		guild: &Guild,
+		config: &CooldownConfig,
+		command: &str,
+		interaction: &Interaction,
+	) -> Option<Duration> {
+		if config.seconds == 0 {
+			return None;
+		}
+
+		let member = interaction.member.as_ref();
+		if let Some(bypass_position) = config
+			.bypass_minimum_role
+			.and_then(|r| guild.role(r))
+			.map(|r| r.position)
+		{
+			if let Some(m) = member {
+				if guild.member_role_position(m) >= bypass_position {
+					return None;
+				}
+			}
+		}
+
+		let key = match config.scope {
+			CooldownScope::Global => Key::Global,
+			CooldownScope::Channel => Key::Channel(interaction.channel_id?),
+			CooldownScope::User => Key::User(member?.user.as_ref()?.id),
+		};
+
+		let now = Instant::now();
+		self.uses.retain(|_, u| {
+			u.prune(now);
+			!u.times.is_empty()
+		});
+		let uses = self
+			.uses
+			.entry((command.to_owned(), key))
+			.or_insert_with(|| Uses {
+				window: config.window(),
+				times: VecDeque::new(),
+			});
+		uses.window = config.window();
+		uses.prune(now);
+
+		if uses.times.len() >= config.burst() {
+			let oldest = uses.times.front().copied().unwrap_or(now);
+			return Some(uses.window.saturating_sub(now.duration_since(oldest)));
+		}
+		uses.times.push_back(now);
+		None
+	}
+}
+
+/// Response to a command that is on cooldown
+pub fn cooldown_message(left: Duration) -> String {
+	// Round up, so we never claim there are 0 seconds left
+	let secs = left.as_secs() + (left.subsec_nanos() > 0) as u64;
+	format!("Command on cooldown for {} more seconds", secs)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::testing::{GuildState, StandIn};
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn scopes() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Moderator", 1)
+			.text_channel(4, "commands")
+			.text_channel(5, "general")
+			.member(6, "someone", &[])
+			.member(7, "other", &[])
+			.member(8, "moderator", &[3]);
+		let guild = state.guild(&stand_in);
+		let interaction = |channel_id, user_id| -> Interaction {
+			let interaction = state.command_interaction(10, channel_id, user_id, "hello", &[]);
+			serde_json::from_value(interaction).unwrap()
+		};
+
+		// A plain number is a global cooldown
+		let config: CooldownConfig = serde_json::from_value(json!(60)).unwrap();
+		let mut cooldowns = Cooldowns::new();
+		assert!(cooldowns
+			.check(&guild, &config, "hello", &interaction(4, 6))
+			.is_none());
+		let left = cooldowns.check(&guild, &config, "hello", &interaction(5, 7));
+		assert!(left.unwrap() > Duration::from_secs(58));
+		assert!(cooldowns
+			.check(&guild, &config, "other", &interaction(5, 7))
+			.is_none());
+
+		let config: CooldownConfig = serde_json::from_value(json!({
+			"seconds": 60,
+			"scope": "user",
+			"burst": 2,
+			"bypass_minimum_role": "3",
+		}))
+		.unwrap();
+		let mut cooldowns = Cooldowns::new();
+		let check = |cooldowns: &mut Cooldowns, channel_id, user_id| {
+			cooldowns
+				.check(&guild, &config, "hello", &interaction(channel_id, user_id))
+				.is_none()
+		};
+		assert!(check(&mut cooldowns, 4, 6));
+		assert!(check(&mut cooldowns, 5, 6));
+		assert!(!check(&mut cooldowns, 4, 6));
+		assert!(check(&mut cooldowns, 4, 7));
+		for _ in 0..3 {
+			assert!(check(&mut cooldowns, 4, 8));
+		}
+
+		assert_eq!(
+			cooldown_message(Duration::from_millis(1500)),
+			"Command on cooldown for 2 more seconds"
+		);
+	}
+}
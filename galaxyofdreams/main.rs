@@ -1,24 +1,39 @@
 #![recursion_limit = "1024"]
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
+use command_registry::CommandRegistry;
 use common::discord::types::Event;
 use common::discord::{Builder, GatewayError, GatewayEvent};
-use common::{EventHandler, Storage};
+use common::{EventHandler, Guild, Storage};
 use config::Config;
 use futures::channel::mpsc;
+use futures::lock::Mutex as AsyncMutex;
 use futures::{SinkExt, StreamExt};
-use log::{info, warn, LevelFilter};
+use log::{debug, info, warn, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Config as LogConfig, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use modules::Configurator;
+use modules::{Configurator, Registry};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use supervisor::Supervisor;
 use tokio::select;
-use warp::Filter;
+use tokio::time::{sleep_until, Instant};
+use usage::Usage;
 
+mod command_registry;
 mod config;
+mod cooldown;
 mod modules;
+mod pattern;
+mod supervisor;
+mod template;
+mod usage;
+mod websub;
+
+// Time given to background tasks to finish their work when shutting down
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(15);
 
 #[tokio::main]
 async fn main() {
@@ -53,7 +68,7 @@ async fn real_main() -> Result<()> {
 
 	let (ev_send, mut ev_recv) = mpsc::unbounded();
 	let mut ev_send = Some(ev_send);
-	let discord = Builder::new(config.discord_api_token.clone(), move |ev| {
+	let mut discord = Builder::new(config.discord_api_token.clone(), move |ev| {
 		if ev.is_none() {
 			// Shutting down: drop the sender
 			ev_send = None;
@@ -76,76 +91,117 @@ async fn real_main() -> Result<()> {
 	.build()
 	.await?;
 
-	// Discard events until we find our guild
+	// Discard events until we find our guild, remembering our own user from READY
+	let mut user_id = None;
 	let mut gc = None;
 	while let Some(ev) = ev_recv.next().await {
-		if let GatewayEvent::Event(Event::GuildCreate(c)) = ev {
-			gc = Some(c);
-			break;
+		match ev {
+			GatewayEvent::Event(Event::Ready(r)) => user_id = Some(r.user.id),
+			GatewayEvent::Event(Event::GuildCreate(c)) => {
+				gc = Some(c);
+				break;
+			}
+			_ => (),
 		}
 	}
 
-	let mut guild = match gc {
-		Some(gc) => discord.guild(ev_recv, gc).await?,
-		None => bail!("Connection closed before receiving our guild"),
+	let mut guild = match (gc, user_id) {
+		(Some(gc), Some(user_id)) => Guild::new(discord.guild(ev_recv, gc).await?, user_id),
+		(Some(_), None) => bail!("Received our guild before READY"),
+		(None, _) => bail!("Connection closed before receiving our guild"),
 	};
 
-	// Configurator allows on-the-fly changes to module configuration
-	let module_config_dir = config
-		.module_config_dir()
-		.ok_or_else(|| anyhow!("Module configuration directory not found"))?;
-	let mut configurator = Configurator::new(&storage, module_config_dir)?;
+	let discord_shutdown = discord.shutdown().expect("shutdown signal missing");
+	let mut supervisor = Supervisor::new();
+
+	// Configurator allows on-the-fly changes to module configuration.
+	// SQLite deployments read from files, Postgres from the `module_config` table
+	let configurator = Configurator::new(&storage, config.module_config_dir())?;
+	let configurator = Arc::new(AsyncMutex::new(configurator));
 
 	// The configurator is not necessarily cancellation safe so we have to
 	// move it to its own task and use a channel to receive its events.
 	// See: https://docs.rs/tokio/1.11.0/tokio/macro.select.html#cancellation-safety
-	let (mut config_send, mut config_recv) = mpsc::channel(16);
-	tokio::spawn(async move {
-		loop {
-			let item = match configurator.next().await {
-				Ok(c) => c,
-				Err(e) => {
-					warn!("Configurator: {}", e);
-					continue;
+	let (config_send, mut config_recv) = mpsc::channel(16);
+	supervisor.spawn("configurator", move |mut shutdown| {
+		let configurator = Arc::clone(&configurator);
+		let mut config_send = config_send.clone();
+		async move {
+			let mut configurator = configurator.lock().await;
+			loop {
+				let item = select! {
+					c = configurator.next() => c,
+					_ = shutdown.wait() => break,
+				};
+				let item = match item {
+					Ok(c) => c,
+					Err(e) => {
+						warn!("Configurator: {}", e);
+						continue;
+					}
+				};
+				if config_send.send(item).await.is_err() {
+					// Main task went away
+					break;
 				}
-			};
-			if config_send.send(item).await.is_err() {
-				// Main task went away
-				break;
 			}
+			Ok(())
 		}
 	});
 
-	// Set up our modules
-	let youtube = modules::Youtube::new(discord.client(), &config.http_ext_url());
-	let astronauts = modules::Astronauts::new(&guild, storage.clone()).await?;
-	// let collab = modules::CollabPlaylist::new(storage.clone()).await?;
-	// let routes = youtube.routes().or(collab.routes()).or(astronauts.routes());
-	let routes = youtube.routes().or(astronauts.routes());
-
-	let mut chain = modules::Filter::new()
-		// .chain(modules::Automod::new())
-		// .chain(modules::DJ::new())
-		// .chain(modules::Levels::new(storage.clone()).await?)
-		.chain(modules::Joined::new())
-		.chain(modules::Commands::new())
-		.chain(modules::LinkOnly::new())
-		.chain(modules::RoleAssign::new(discord.client(), storage.clone()).await?)
-		// .chain(collab)
-		.chain(astronauts)
-		.chain(youtube);
-
-	// HTTP server
-	let addr: SocketAddr = format!("0.0.0.0:{}", config.http_port()).parse()?;
-	let http = warp::serve(routes).bind(addr);
-	tokio::spawn(http);
+	// Set up our modules. Their slash commands are registered through a shared
+	// registry, which also removes the ones no module declares anymore
+	let commands = CommandRegistry::new(supervisor.tracker());
+	let usage = Usage::new(storage.clone(), supervisor.tracker()).await?;
+	let mut context = modules::Context::new(
+		&guild,
+		storage.clone(),
+		commands.clone(),
+		usage,
+		&mut supervisor,
+		config.http_ext_url(),
+	);
+	let registry = Registry::new();
+	let mut chain = match &config.modules {
+		Some(names) => registry.build(names, &mut context).await?,
+		None => {
+			registry
+				.build(modules::DEFAULT_MODULES, &mut context)
+				.await?
+		}
+	};
+	let routes = context.into_routes();
+
+	// HTTP server, only needed when one of the modules has routes
+	if let Some(routes) = routes {
+		let addr: SocketAddr = format!("0.0.0.0:{}", config.http_port()).parse()?;
+		supervisor.spawn("http", move |mut shutdown| {
+			let server = warp::serve(routes.clone())
+				.try_bind_with_graceful_shutdown(addr, async move { shutdown.wait().await });
+			async move {
+				let (_, server) = server?;
+				server.await;
+				Ok(())
+			}
+		});
+	}
+
+	let terminate = supervisor::terminate();
+	tokio::pin!(terminate);
 
 	loop {
+		let sync_at = commands.deadline();
 		select! {
+			_ = &mut terminate => {
+				warn!("Received interrupt signal, shutting down..");
+				break;
+			}
 			c = config_recv.next() => {
 				match c {
 					Some((name, config)) => {
-						chain.config(&guild, &name, config);
+						if chain.config(&guild, &name, config).is_some() {
+							debug!("No module loaded for config '{}'", name);
+						}
 					}
 					None => break
 				}
@@ -156,9 +212,19 @@ async fn real_main() -> Result<()> {
 					None => break
 				}
 			}
+			_ = sleep_until(sync_at.unwrap_or_else(Instant::now)), if sync_at.is_some() => {
+				commands.sync(&guild);
+			}
 		};
 	}
 
+	// Stop the gateway and give the background tasks a chance to finish their work.
+	// Dropping the modules closes their channels, so queued events are drained first
+	discord_shutdown.send();
+	drop(chain);
+	supervisor.shutdown(SHUTDOWN_DEADLINE).await;
+	let _ = discord.handle().await;
+
 	info!("Done");
 	Ok(())
 }
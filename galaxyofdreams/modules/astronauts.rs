@@ -1,10 +1,12 @@
 use super::MapConfig;
-use anyhow::{anyhow, ensure, Result};
+use crate::supervisor::Supervisor;
+use anyhow::{anyhow, Result};
 use common::discord::client::ButtonComponent;
 use common::discord::types::{ChannelId, DateTime, GuildId, RoleId, UserId};
-use common::discord::Client;
-use common::{EventHandler, Guild, Storage};
+use common::Client;
+use common::{EventHandler, Guild, Migration, Storage};
 use futures::channel::{mpsc, oneshot};
+use futures::lock::Mutex as AsyncMutex;
 use futures::{SinkExt, StreamExt};
 use http::{Method, StatusCode};
 use log::{debug, info, warn};
@@ -20,25 +22,48 @@ use warp::Filter;
 
 type SharedConfig = Arc<Mutex<AstronautsConfig>>;
 
-const CREATE_TABLES_SQLITE: &'static str = r#"
-	CREATE TABLE IF NOT EXISTS astronauts (
-		user_id INTEGER PRIMARY KEY,
-		is_active INTEGER NOT NULL,
-		created_timestamp INTEGER NOT NULL,
-		updated_timestamp INTEGER NOT NULL,
-		counter INTEGER NOT NULL
-	);
-	
-	CREATE TABLE IF NOT EXISTS astronaut_log (
-		astronaut_log_id INTEGER PRIMARY KEY AUTOINCREMENT,
-		user_id INTEGER NOT NULL,
-		is_active INTEGER NOT NULL,
-		created_timestamp INTEGER NOT NULL,
-		origin TEXT NOT NULL
-	);
-	
-	CREATE INDEX IF NOT EXISTS astronaut_log_user ON astronaut_log (user_id);
-"#;
+// Tables created before migrations existed use `IF NOT EXISTS` so existing deployments are adopted
+const MIGRATIONS: &[Migration] = &[Migration {
+	name: "create_astronauts",
+	sqlite: r#"
+		CREATE TABLE IF NOT EXISTS astronauts (
+			user_id INTEGER PRIMARY KEY,
+			is_active INTEGER NOT NULL,
+			created_timestamp INTEGER NOT NULL,
+			updated_timestamp INTEGER NOT NULL,
+			counter INTEGER NOT NULL
+		);
+
+		CREATE TABLE IF NOT EXISTS astronaut_log (
+			astronaut_log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+			user_id INTEGER NOT NULL,
+			is_active INTEGER NOT NULL,
+			created_timestamp INTEGER NOT NULL,
+			origin TEXT NOT NULL
+		);
+
+		CREATE INDEX IF NOT EXISTS astronaut_log_user ON astronaut_log (user_id);
+	"#,
+	postgres: r#"
+		CREATE TABLE IF NOT EXISTS astronauts (
+			user_id BIGINT PRIMARY KEY,
+			is_active BOOLEAN NOT NULL,
+			created_timestamp BIGINT NOT NULL,
+			updated_timestamp BIGINT NOT NULL,
+			counter INTEGER NOT NULL
+		);
+
+		CREATE TABLE IF NOT EXISTS astronaut_log (
+			astronaut_log_id BIGSERIAL PRIMARY KEY,
+			user_id BIGINT NOT NULL,
+			is_active BOOLEAN NOT NULL,
+			created_timestamp BIGINT NOT NULL,
+			origin TEXT NOT NULL
+		);
+
+		CREATE INDEX IF NOT EXISTS astronaut_log_user ON astronaut_log (user_id);
+	"#,
+}];
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AstronautsConfig {
@@ -83,7 +108,7 @@ pub struct Astronauts {
 }
 
 impl Astronauts {
-	pub async fn new(guild: &Guild, storage: Storage) -> Result<Self> {
+	pub async fn new(guild: &Guild, storage: Storage, supervisor: &mut Supervisor) -> Result<Self> {
 		let (sender, recv) = mpsc::channel(8);
 		let config = Arc::new(Mutex::new(Default::default()));
 
@@ -94,14 +119,20 @@ impl Astronauts {
 		};
 		astronauts.init_storage().await?;
 
-		let shuttle = Shuttle {
-			config,
-			guild_id: guild.id(),
-			client: guild.client(),
-			storage,
-			recv,
-		};
-		shuttle.spawn();
+		// The receiver outlives the shuttle so it can be restarted after a crash
+		let recv = Arc::new(AsyncMutex::new(recv));
+		let guild_id = guild.id();
+		let client = guild.client();
+		supervisor.spawn("astronauts shuttle", move |_| {
+			let shuttle = Shuttle {
+				config: Arc::clone(&config),
+				guild_id,
+				client: client.clone(),
+				storage: storage.clone(),
+				recv: Arc::clone(&recv),
+			};
+			shuttle.run()
+		});
 
 		Ok(astronauts)
 	}
@@ -124,17 +155,7 @@ impl Astronauts {
 	}
 
 	async fn init_storage(&self) -> Result<()> {
-		ensure!(self.storage.kind().is_sqlite(), "Unsupported db type");
-		let mut tx = self.storage.begin().await?;
-		{
-			let mut res = query(CREATE_TABLES_SQLITE).execute_many(&mut tx).await;
-			while let Some(r) = res.next().await {
-				r?;
-			}
-		}
-		tx.commit().await?;
-
-		Ok(())
+		self.storage.migrate("astronauts", MIGRATIONS).await
 	}
 }
 
@@ -162,7 +183,7 @@ pub struct Shuttle {
 	guild_id: GuildId,
 	client: Client,
 	storage: Storage,
-	recv: mpsc::Receiver<Event>,
+	recv: Arc<AsyncMutex<mpsc::Receiver<Event>>>,
 }
 
 impl Shuttle {
@@ -222,7 +243,7 @@ impl Shuttle {
 		let mut tx = self.storage.begin().await?;
 
 		let was_active =
-			query_scalar::<_, bool>("SELECT is_active FROM astronauts WHERE user_id = ?")
+			query_scalar::<_, bool>("SELECT is_active FROM astronauts WHERE user_id = $1")
 				.bind(event.user_id)
 				.fetch_optional(&mut tx)
 				.await?;
@@ -232,7 +253,7 @@ impl Shuttle {
 		match was_active {
 			None => {
 				// Insert
-				query("INSERT INTO astronauts (user_id, is_active, created_timestamp, updated_timestamp, counter) VALUES (?, ?, ?, ?, 1)")
+				query("INSERT INTO astronauts (user_id, is_active, created_timestamp, updated_timestamp, counter) VALUES ($1, $2, $3, $4, 1)")
 					.bind(event.user_id)
 					.bind(event.add)
 					.bind(&now)
@@ -242,7 +263,7 @@ impl Shuttle {
 			}
 			Some(x) if x != event.add => {
 				// Update
-				query("UPDATE astronauts SET is_active = ?, updated_timestamp = ?, counter = counter + 1 WHERE user_id = ?")
+				query("UPDATE astronauts SET is_active = $1, updated_timestamp = $2, counter = counter + 1 WHERE user_id = $3")
 					.bind(event.add)
 					.bind(&now)
 					.bind(event.user_id)
@@ -254,7 +275,7 @@ impl Shuttle {
 
 		// Add log entry
 		query(
-			"INSERT INTO astronaut_log (user_id, is_active, created_timestamp, origin) VALUES (?, ?, ?, ?)",
+			"INSERT INTO astronaut_log (user_id, is_active, created_timestamp, origin) VALUES ($1, $2, $3, $4)",
 		)
 			.bind(event.user_id)
 			.bind(event.add)
@@ -269,8 +290,11 @@ impl Shuttle {
 		Ok(())
 	}
 
-	async fn run(mut self) {
-		while let Some(event) = self.recv.next().await {
+	async fn run(mut self) -> Result<()> {
+		// Keep processing until all senders are gone, which allows queued events to drain on shutdown
+		let recv = Arc::clone(&self.recv);
+		let mut recv = recv.lock().await;
+		while let Some(event) = recv.next().await {
 			let mut res = self.update_db(&event).await;
 			let _ = event.send.send(res.is_ok());
 			if res.is_ok() {
@@ -280,10 +304,8 @@ impl Shuttle {
 				warn!("Shuttle: {}", e);
 			}
 		}
-	}
 
-	fn spawn(self) {
-		tokio::spawn(self.run());
+		Ok(())
 	}
 }
 
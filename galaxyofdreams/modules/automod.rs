@@ -0,0 +1,669 @@
+use crate::pattern::Pattern;
+use crate::supervisor::Tracker;
+use chrono::Utc;
+use common::discord::types::event;
+use common::discord::types::{AllowedMentions, ChannelId, Event, Member, Message, RoleId, UserId};
+use common::{EventHandler, Guild};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::time::{Duration, Instant};
+
+// Longest part of a message that is quoted in the log
+const MAX_QUOTE_LENGTH: usize = 1500;
+// Recent messages are remembered at most this long, for the repeat check
+const MAX_REPEAT_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AutomodConfig {
+	enabled: bool,
+	// Checked in order, the first rule that is broken applies
+	#[serde(default)]
+	rules: Vec<AutomodRule>,
+	#[serde(default)]
+	log_channel: Option<ChannelId>,
+	// Exempt from every rule
+	#[serde(default)]
+	exempt_channels: HashSet<ChannelId>,
+	#[serde(default)]
+	exempt_roles: HashSet<RoleId>,
+	#[serde(default)]
+	bypass_minimum_role: Option<RoleId>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AutomodRule {
+	// Shown in the log and warnings, defaults to the type of check
+	#[serde(default)]
+	name: Option<String>,
+	#[serde(flatten)]
+	check: Check,
+	#[serde(default = "default_actions")]
+	actions: Vec<Action>,
+	// If given, the rule only applies in these channels
+	#[serde(default)]
+	channels: HashSet<ChannelId>,
+	#[serde(default)]
+	exempt_channels: HashSet<ChannelId>,
+	#[serde(default)]
+	exempt_roles: HashSet<RoleId>,
+}
+
+fn default_actions() -> Vec<Action> {
+	vec![Action::Delete, Action::Log]
+}
+
+impl AutomodRule {
+	fn name(&self) -> &str {
+		self.name.as_deref().unwrap_or_else(|| self.check.name())
+	}
+
+	fn applies(&self, channel_id: ChannelId, member: Option<&Member>) -> bool {
+		if !self.channels.is_empty() && !self.channels.contains(&channel_id) {
+			return false;
+		}
+		!self.exempt_channels.contains(&channel_id) && !has_role(member, &self.exempt_roles)
+	}
+}
+
+/// What a rule looks for in a message
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Check {
+	// Words and phrases are matched case insensitively, as whole words. Patterns are
+	// matched as is
+	Words {
+		#[serde(default)]
+		words: Vec<String>,
+		#[serde(default)]
+		patterns: Vec<Pattern>,
+	},
+	// Users and roles mentioned, @everyone and @here count as well
+	Mentions {
+		limit: usize,
+	},
+	Caps {
+		// Share of the letters in uppercase, in percent
+		#[serde(default)]
+		percent: Option<u32>,
+		// Messages with fewer letters are ignored
+		#[serde(default)]
+		min_letters: Option<usize>,
+	},
+	// The same message posted more than `limit` times within `seconds` by one user
+	Repeats {
+		limit: usize,
+		seconds: u32,
+	},
+	Invites {
+		// Invite codes that can be posted
+		#[serde(default)]
+		allowed: Vec<String>,
+	},
+	Zalgo {
+		// Combining marks allowed on a single character
+		#[serde(default)]
+		limit: Option<usize>,
+	},
+}
+
+impl Check {
+	fn name(&self) -> &'static str {
+		match self {
+			Check::Words { .. } => "banned words",
+			Check::Mentions { .. } => "mass mentions",
+			Check::Caps { .. } => "excessive caps",
+			Check::Repeats { .. } => "repeated messages",
+			Check::Invites { .. } => "invite links",
+			Check::Zalgo { .. } => "zalgo",
+		}
+	}
+
+	/// Whether `content` breaks the rule. `history` are the earlier messages of the
+	/// author, oldest first, or `None` for edits
+	fn matches(&self, content: &str, history: Option<&VecDeque<(Instant, String)>>) -> bool {
+		match self {
+			Check::Words { words, patterns } => {
+				let found = split_words(content);
+				words.iter().any(|w| {
+					let phrase = split_words(w);
+					!phrase.is_empty() && found.windows(phrase.len()).any(|f| f == phrase)
+				}) || patterns.iter().any(|p| p.is_match(content))
+			}
+			Check::Mentions { limit } => mention_count(content) > *limit,
+			Check::Caps {
+				percent,
+				min_letters,
+			} => {
+				let letters = content.chars().filter(|c| c.is_alphabetic()).count();
+				let upper = content.chars().filter(|c| c.is_uppercase()).count();
+				letters > 0
+					&& letters >= min_letters.unwrap_or(10)
+					&& upper * 100 >= letters * percent.unwrap_or(70) as usize
+			}
+			Check::Repeats { limit, seconds } => {
+				let history = match history {
+					Some(h) => h,
+					None => return false,
+				};
+				let window = Duration::from_secs(*seconds as u64);
+				let content = normalize(content);
+				let repeats = history
+					.iter()
+					.filter(|(t, c)| t.elapsed() < window && *c == content)
+					.count();
+				repeats + 1 > *limit
+			}
+			Check::Invites { allowed } => invite_codes(content)
+				.iter()
+				.any(|code| !allowed.iter().any(|a| a.eq_ignore_ascii_case(code))),
+			Check::Zalgo { limit } => {
+				let limit = limit.unwrap_or(3);
+				let mut marks = 0;
+				for c in content.chars() {
+					if is_combining(c) {
+						marks += 1;
+						if marks > limit {
+							return true;
+						}
+					} else {
+						marks = 0;
+					}
+				}
+				false
+			}
+		}
+	}
+}
+
+/// Done to a message that breaks a rule, and to its author
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+	Delete,
+	// Reply to the author in the channel
+	Warn,
+	// Time the author out for a number of minutes
+	Timeout(u32),
+	// Report in the log channel
+	Log,
+}
+
+fn has_role(member: Option<&Member>, roles: &HashSet<RoleId>) -> bool {
+	member
+		.map(|m| m.roles.iter().any(|r| roles.contains(r)))
+		.unwrap_or(false)
+}
+
+/// Lowercase words of `content`, anything but letters and digits separates them
+fn split_words(content: &str) -> Vec<String> {
+	content
+		.split(|c: char| !c.is_alphanumeric())
+		.filter(|w| !w.is_empty())
+		.map(|w| w.to_lowercase())
+		.collect()
+}
+
+/// Form under which messages are compared for the repeat check
+fn normalize(content: &str) -> String {
+	content
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+		.to_lowercase()
+}
+
+/// Distinct users and roles mentioned in `content`, plus @everyone and @here
+fn mention_count(content: &str) -> usize {
+	let mut mentions = HashSet::new();
+	let mut rest = content;
+	while let Some(start) = rest.find("<@") {
+		rest = &rest[start + 2..];
+		let end = match rest.find('>') {
+			Some(e) => e,
+			None => break,
+		};
+		let id = &rest[..end];
+		let digits = id.trim_start_matches(&['!', '&'][..]);
+		if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+			mentions.insert(id.replace('!', ""));
+		}
+	}
+	mentions.len() + content.matches("@everyone").count() + content.matches("@here").count()
+}
+
+/// Codes of the Discord invite links in `content`
+fn invite_codes(content: &str) -> Vec<String> {
+	let content = content.to_ascii_lowercase();
+	let mut codes = Vec::new();
+	for prefix in [
+		"discord.gg/",
+		"discord.com/invite/",
+		"discordapp.com/invite/",
+	] {
+		for (i, _) in content.match_indices(prefix) {
+			let code: String = content[i + prefix.len()..]
+				.chars()
+				.take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+				.collect();
+			if !code.is_empty() {
+				codes.push(code);
+			}
+		}
+	}
+	codes
+}
+
+fn is_combining(c: char) -> bool {
+	matches!(c,
+		'\u{0300}'..='\u{036F}'
+		| '\u{1AB0}'..='\u{1AFF}'
+		| '\u{1DC0}'..='\u{1DFF}'
+		| '\u{20D0}'..='\u{20FF}'
+		| '\u{FE20}'..='\u{FE2F}')
+}
+
+pub struct Automod {
+	config: AutomodConfig,
+	tracker: Tracker,
+	// Recent messages per author, oldest first
+	history: HashMap<UserId, VecDeque<(Instant, String)>>,
+}
+
+impl Automod {
+	pub fn new(tracker: Tracker) -> Self {
+		Self {
+			config: Default::default(),
+			tracker,
+			history: HashMap::new(),
+		}
+	}
+
+	/// How long messages have to be remembered for the repeat rules, if there are any
+	fn repeat_window(&self) -> Option<Duration> {
+		self.config
+			.rules
+			.iter()
+			.filter_map(|r| match r.check {
+				Check::Repeats { seconds, .. } => Some(Duration::from_secs(seconds as u64)),
+				_ => None,
+			})
+			.max()
+			.map(|w| w.min(MAX_REPEAT_WINDOW))
+	}
+
+	fn message(&mut self, guild: &Guild, message: &Message, edited: bool) -> bool {
+		if !self.config.enabled || self.config.exempt_channels.contains(&message.channel_id) {
+			return true;
+		}
+
+		let user_id = match &message.author {
+			Some(a) if !a.is_bot() && !a.is_system() => a.id,
+			_ => return true,
+		};
+		let member = message.member.as_ref();
+		if has_role(member, &self.config.exempt_roles) {
+			return true;
+		}
+		if let Some(bypass_position) = self
+			.config
+			.bypass_minimum_role
+			.and_then(|r| guild.role(r))
+			.map(|r| r.position)
+		{
+			if let Some(m) = member {
+				if guild.member_role_position(m) >= bypass_position {
+					return true;
+				}
+			}
+		}
+
+		// Only remember messages as long as a repeat rule needs them. Edits don't count
+		// as repeats
+		let window = self.repeat_window().filter(|_| !edited);
+		if let Some(window) = window {
+			self.history.retain(|_, history| {
+				while let Some((t, _)) = history.front() {
+					if t.elapsed() < window {
+						break;
+					}
+					history.pop_front();
+				}
+				!history.is_empty()
+			});
+		}
+		let empty = VecDeque::new();
+		let history = window.map(|_| self.history.get(&user_id).unwrap_or(&empty));
+		let rule = self
+			.config
+			.rules
+			.iter()
+			.filter(|r| r.applies(message.channel_id, member))
+			.find(|r| r.check.matches(&message.content, history))
+			.cloned();
+		if window.is_some() {
+			self.history
+				.entry(user_id)
+				.or_default()
+				.push_back((Instant::now(), normalize(&message.content)));
+		}
+
+		let rule = match rule {
+			Some(r) => r,
+			None => return true,
+		};
+		info!(
+			"Message {} from user {} breaks rule '{}'",
+			message.id,
+			user_id,
+			rule.name()
+		);
+
+		let delete = rule.actions.iter().any(|a| matches!(a, Action::Delete));
+		let client = guild.client();
+		let guild_id = guild.id();
+		let ids = (message.channel_id, message.id);
+		let log_message = self.config.log_channel.map(|id| {
+			let quote: String = message.content.chars().take(MAX_QUOTE_LENGTH).collect();
+			let msg = format!(
+				"{} {}message from <@{}> in <#{}> for {}:\n```{}```",
+				if delete { "Deleted" } else { "Flagged" },
+				if edited { "edited " } else { "" },
+				user_id,
+				message.channel_id,
+				rule.name(),
+				quote
+			);
+			(id, msg)
+		});
+
+		self.tracker.spawn(async move {
+			for action in &rule.actions {
+				match action {
+					Action::Delete => {
+						if let Err(e) = client.delete_message(ids).await {
+							warn!("Unable to delete message: {}", e);
+						}
+					}
+					Action::Warn => {
+						let res = client
+							.create_message(ids.0)
+							.content(format!(
+								"<@{}> your message broke the rule on {}",
+								user_id,
+								rule.name()
+							))
+							.allowed_mentions(AllowedMentions::none().users(vec![user_id]))
+							.send()
+							.await;
+						if let Err(e) = res {
+							warn!("Unable to warn user {}: {}", user_id, e);
+						}
+					}
+					Action::Timeout(minutes) => {
+						// Discord doesn't allow timeouts longer than 28 days
+						let minutes = (*minutes).min(28 * 24 * 60);
+						let until = Utc::now() + chrono::Duration::minutes(minutes as i64);
+						match client.timeout_guild_member(guild_id, user_id, until).await {
+							Ok(_) => info!("Timed out user {} for {} minutes", user_id, minutes),
+							Err(e) => warn!("Unable to time out user {}: {}", user_id, e),
+						}
+					}
+					Action::Log => {
+						let (id, msg) = match &log_message {
+							Some(l) => l.clone(),
+							None => continue,
+						};
+						if let Err(e) = client.create_message(id).content(msg).send().await {
+							warn!("Unable to log rule violation: {}", e);
+						}
+					}
+				}
+			}
+		});
+
+		// Deleted messages are of no use to the modules that come after us
+		!delete
+	}
+}
+
+impl EventHandler for Automod {
+	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
+		let config = load_config!(name, "automod", config);
+		let old = mem::replace(&mut self.config, config);
+		if self.repeat_window().is_none() {
+			self.history.clear();
+		}
+		if old.enabled != self.config.enabled {
+			if self.config.enabled {
+				info!("Module enabled with {} rules", self.config.rules.len());
+			} else {
+				info!("Module disabled");
+			}
+		} else {
+			info!("Config updated");
+		}
+
+		None
+	}
+
+	fn event(&mut self, guild: &Guild, event: &Event) -> bool {
+		match event {
+			Event::MessageCreate(event::MessageCreate { message }) => {
+				self.message(guild, message, false)
+			}
+			Event::MessageUpdate(event::MessageUpdate { message })
+				if message.edited_timestamp.is_some() =>
+			{
+				self.message(guild, message, true)
+			}
+			_ => true,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use common::testing::{feed, GuildState, StandIn};
+	use serde_json::json;
+	use warp::http::Method;
+
+	#[test]
+	fn checks() {
+		let check = |check: Value, content: &str| {
+			let check: Check = serde_json::from_value(check).unwrap();
+			check.matches(content, Some(&VecDeque::new()))
+		};
+
+		let words = json!({
+			"type": "words",
+			"words": ["Heck", "free nitro", " "],
+			"patterns": ["(?i)d[a4]rn"],
+		});
+		assert!(check(words.clone(), "what the heck!"));
+		assert!(check(words.clone(), "D4RN it"));
+		assert!(check(words.clone(), "Get FREE   nitro here"));
+		assert!(check(words.clone(), "free-nitro.example"));
+		assert!(!check(words.clone(), "free to use, no nitro"));
+		assert!(!check(words, "checking"));
+
+		let mentions = json!({ "type": "mentions", "limit": 2 });
+		assert!(!check(mentions.clone(), "<@1> <@!1> <@2> hi"));
+		assert!(check(mentions.clone(), "<@1> <@&2> @everyone"));
+		assert!(!check(mentions, "<@nope> <@> <@1"));
+
+		let caps = json!({ "type": "caps", "percent": 80 });
+		assert!(check(caps.clone(), "WHY IS NOBODY ANSWERING me"));
+		assert!(!check(caps.clone(), "Why is nobody answering me"));
+		assert!(!check(caps, "OK THEN"));
+
+		let invites = json!({ "type": "invites", "allowed": ["ours"] });
+		assert!(check(invites.clone(), "join https://discord.gg/Theirs"));
+		assert!(check(invites.clone(), "discord.com/invite/other-1"));
+		assert!(!check(invites, "https://discord.gg/ours"));
+
+		let zalgo = json!({ "type": "zalgo" });
+		assert!(check(
+			zalgo.clone(),
+			"he\u{0301}\u{0302}\u{0303}\u{0304}llo"
+		));
+		assert!(!check(zalgo, "café, naïve"));
+	}
+
+	#[tokio::test]
+	async fn moderates() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Moderator", 1)
+			.role(4, "Regular", 0)
+			.text_channel(5, "general")
+			.text_channel(6, "spam")
+			.text_channel(7, "log")
+			.member(8, "someone", &[])
+			.member(9, "moderator", &[3])
+			.member(10, "regular", &[4]);
+		let guild = state.guild(&stand_in);
+		let message = |id, channel_id, user_id, content| {
+			let message = state.message(id, channel_id, user_id, content);
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+
+		let mut automod = Automod::new(Supervisor::new().tracker());
+		let config = json!({
+			"enabled": true,
+			"log_channel": "7",
+			"exempt_channels": ["6"],
+			"bypass_minimum_role": "3",
+			"rules": [
+				{ "type": "invites" },
+				{
+					"name": "spam",
+					"type": "repeats",
+					"limit": 2,
+					"seconds": 60,
+					"actions": ["delete", "warn", { "timeout": 5 }],
+				},
+				{ "type": "caps", "exempt_roles": ["4"], "actions": ["log"] },
+			],
+		});
+		assert!(automod.config(&guild, "automod", config).is_none());
+
+		let events = [
+			message(20, 6, 8, "discord.gg/abc"),
+			message(21, 5, 9, "discord.gg/abc"),
+			message(22, 5, 8, "discord.gg/abc"),
+			message(23, 5, 8, "buy now"),
+			message(24, 5, 8, "Buy  now"),
+			message(25, 5, 8, "buy now"),
+			message(26, 5, 10, "I AM VERY EXCITED"),
+		];
+		assert_eq!(
+			feed(&mut automod, &guild, &events),
+			[true, true, false, true, true, false, true]
+		);
+		assert_eq!(
+			feed(
+				&mut automod,
+				&guild,
+				&[message(27, 5, 8, "I AM VERY EXCITED")]
+			),
+			[true]
+		);
+
+		let mut requests = stand_in.wait_for(6, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 6);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		assert!(requests[0].is(Method::POST, "/channels/5/messages"));
+		let warning = requests[0].json().unwrap();
+		assert_eq!(
+			warning["content"],
+			"<@8> your message broke the rule on spam"
+		);
+		assert!(requests[1].is(Method::DELETE, "/channels/5/messages/22"));
+		assert!(requests[2].is(Method::DELETE, "/channels/5/messages/25"));
+		assert!(requests[3].is(Method::POST, "/channels/7/messages"));
+		assert!(requests[4].is(Method::POST, "/channels/7/messages"));
+		let mut logs: Vec<_> = requests[3..5]
+			.iter()
+			.map(|r| r.json().unwrap()["content"].as_str().unwrap().to_owned())
+			.collect();
+		logs.sort();
+		assert!(logs[0].starts_with("Deleted message from <@8> in <#5> for invite links"));
+		assert!(logs[1].starts_with("Flagged message from <@8> in <#5> for excessive caps"));
+		assert!(requests[5].is(Method::PATCH, "/guilds/1/members/8"));
+	}
+
+	#[tokio::test]
+	async fn disabled() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "general")
+			.member(4, "someone", &[]);
+		let guild = state.guild(&stand_in);
+		let message = |id| {
+			let message = state.message(id, 3, 4, "discord.gg/abc");
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+
+		// Nothing is moderated before the first config, nor while disabled
+		let mut automod = Automod::new(Supervisor::new().tracker());
+		assert_eq!(feed(&mut automod, &guild, &[message(10)]), [true]);
+		let config = json!({ "enabled": false, "rules": [{ "type": "invites" }] });
+		assert!(automod.config(&guild, "automod", config).is_none());
+		assert_eq!(feed(&mut automod, &guild, &[message(11)]), [true]);
+
+		let requests = stand_in.wait_for(1, Duration::from_millis(200)).await;
+		assert!(requests.is_empty());
+	}
+
+	#[tokio::test]
+	async fn history() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "general")
+			.member(4, "someone", &[])
+			.member(5, "other", &[]);
+		let guild = state.guild(&stand_in);
+		let message = |id, user_id| {
+			let message = state.message(id, 3, user_id, "hello");
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+		let rules = |rules: Value| json!({ "enabled": true, "rules": rules });
+
+		let mut automod = Automod::new(Supervisor::new().tracker());
+		let repeats = json!([{ "type": "repeats", "limit": 5, "seconds": 60 }]);
+		assert!(automod.config(&guild, "automod", rules(repeats)).is_none());
+		assert_eq!(automod.repeat_window(), Some(Duration::from_secs(60)));
+		feed(&mut automod, &guild, &[message(10, 4), message(11, 5)]);
+		assert_eq!(automod.history.len(), 2);
+
+		// Messages are forgotten once no repeat rule needs them, for every author
+		let repeats = json!([
+			{ "type": "repeats", "limit": 5, "seconds": 0 },
+			{ "type": "repeats", "limit": 5, "seconds": 7200 },
+		]);
+		assert!(automod.config(&guild, "automod", rules(repeats)).is_none());
+		assert_eq!(automod.repeat_window(), Some(MAX_REPEAT_WINDOW));
+		let repeats = json!([{ "type": "repeats", "limit": 5, "seconds": 0 }]);
+		assert!(automod.config(&guild, "automod", rules(repeats)).is_none());
+		feed(&mut automod, &guild, &[message(12, 4)]);
+		assert_eq!(automod.history.len(), 1);
+		assert!(automod.history.contains_key(&"4".parse().unwrap()));
+
+		let caps = json!([{ "type": "caps" }]);
+		assert!(automod.config(&guild, "automod", rules(caps)).is_none());
+		assert!(automod.history.is_empty());
+		feed(&mut automod, &guild, &[message(13, 4)]);
+		assert!(automod.history.is_empty());
+	}
+}
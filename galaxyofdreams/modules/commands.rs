@@ -1,14 +1,28 @@
-use common::discord::interaction::*;
-use common::discord::types::{ChannelId, Embed, Event};
+use crate::command_registry::{CommandRegistry, CommandSpec};
+use crate::cooldown::{cooldown_message, CooldownConfig, Cooldowns};
+use crate::modules::Permission;
+use crate::supervisor::Tracker;
+use crate::template::{self, EmbedTemplate, Values};
+use crate::usage::{Outcome, Usage};
+use anyhow::{bail, Context, Result};
+use common::discord::client::{ButtonComponent, RowComponent};
+use common::discord::types::{
+	AllowedMentions, ApplicationCommandOption, ApplicationCommandOptionChoice,
+	ApplicationCommandOptionType, ChannelId, Embed, Event, Member, UserId,
+};
 use common::display::MaybeDisplay;
+use common::interaction::*;
 use common::{EventHandler, Guild};
-use log::{debug, info, warn};
+use log::{info, warn};
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::mem;
-use std::time::{Duration, Instant};
-use tokio::time::sleep;
+use std::str::FromStr;
+
+const BUTTON_ID_PREFIX: &'static str = "commands";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CommandsConfig {
@@ -19,7 +33,9 @@ pub struct CommandsConfig {
 	channels: HashSet<ChannelId>,
 	commands: HashMap<String, Command>,
 	cdn_url: String,
-	cooldown: u32,
+	cooldown: CooldownConfig,
+	#[serde(default)]
+	permission: Permission,
 }
 
 impl CommandsConfig {
@@ -32,6 +48,21 @@ impl CommandsConfig {
 	fn allowed(&self, channel_id: ChannelId) -> bool {
 		self.channels.contains(&channel_id) == self.is_whitelist()
 	}
+
+	fn validate(&self) -> Result<()> {
+		for (name, command) in &self.commands {
+			if !valid_name(name) {
+				bail!(
+					"Command name '{}' must be 1-32 lowercase letters, digits, '-' or '_'",
+					name
+				);
+			}
+			command
+				.validate()
+				.with_context(|| format!("Command '{}'", name))?;
+		}
+		Ok(())
+	}
 }
 
 impl Default for CommandsConfig {
@@ -42,16 +73,165 @@ impl Default for CommandsConfig {
 			channels: HashSet::new(),
 			commands: HashMap::new(),
 			cdn_url: String::new(),
-			cooldown: 0,
+			cooldown: CooldownConfig::default(),
+			permission: Permission::default(),
 		}
 	}
 }
 
+/// Placeholders available to every response, next to the ones of the options
+const PLACEHOLDERS: &[&str] = &["USER", "USER_NAME", "CHANNEL", "CHANNEL_NAME", "SERVER"];
+
+/// Name of a command or option as accepted by Discord
+fn valid_name(name: &str) -> bool {
+	(1..=32).contains(&name.len())
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", content = "content", rename_all = "lowercase")]
 pub enum CommandType {
 	Text(String),
 	Image(String),
+	// One of the responses, picked at random
+	Random(Vec<RandomResponse>),
+	Embed(EmbedTemplate),
+	// Images that can be browsed with buttons below the embed
+	Gallery(Vec<String>),
+}
+
+impl CommandType {
+	/// All templates of the response
+	fn templates(&self) -> Box<dyn Iterator<Item = &String> + '_> {
+		match self {
+			CommandType::Text(t) | CommandType::Image(t) => Box::new(Some(t).into_iter()),
+			CommandType::Random(r) => Box::new(r.iter().map(|r| r.text())),
+			CommandType::Embed(e) => Box::new(e.templates()),
+			CommandType::Gallery(_) => Box::new(None.into_iter()),
+		}
+	}
+
+	fn validate(&self) -> Result<()> {
+		match self {
+			CommandType::Random(r) if r.iter().all(|r| r.weight() == 0) => {
+				bail!("Random needs at least one response with a non-zero weight")
+			}
+			CommandType::Random(r)
+				if r.iter().map(|r| u64::from(r.weight())).sum::<u64>() > u64::from(u32::MAX) =>
+			{
+				bail!("The weights of Random add up to more than {}", u32::MAX)
+			}
+			CommandType::Gallery(g) if g.is_empty() => bail!("Gallery needs at least one image"),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RandomResponse {
+	Text(String),
+	Weighted { text: String, weight: u32 },
+}
+
+impl RandomResponse {
+	fn text(&self) -> &String {
+		match self {
+			RandomResponse::Text(text) | RandomResponse::Weighted { text, .. } => text,
+		}
+	}
+
+	fn weight(&self) -> u32 {
+		match self {
+			RandomResponse::Text(_) => 1,
+			RandomResponse::Weighted { weight, .. } => *weight,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionKind {
+	User,
+	String,
+	Integer,
+	Choice,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CommandOption {
+	name: String,
+	#[serde(rename = "type")]
+	kind: OptionKind,
+	description: String,
+	#[serde(default)]
+	required: bool,
+	// Values that can be picked for a `choice` option
+	#[serde(default)]
+	choices: Vec<String>,
+}
+
+impl CommandOption {
+	/// Placeholder with the value of the option. User options also get a
+	/// `_NAME` placeholder with the display name of the user
+	fn placeholder(&self) -> String {
+		self.name.to_ascii_uppercase().replace('-', "_")
+	}
+
+	fn name_placeholder(&self) -> String {
+		format!("{}_NAME", self.placeholder())
+	}
+
+	fn validate(&self) -> Result<()> {
+		if !valid_name(&self.name) {
+			bail!(
+				"Option name '{}' must be 1-32 lowercase letters, digits, '-' or '_'",
+				self.name
+			);
+		}
+		if !(1..=100).contains(&self.description.chars().count()) {
+			bail!(
+				"Option '{}' needs a description of 1-100 characters",
+				self.name
+			);
+		}
+		match self.kind {
+			OptionKind::Choice if self.choices.is_empty() || self.choices.len() > 25 => {
+				bail!("Option '{}' needs 1-25 choices", self.name)
+			}
+			OptionKind::Choice => (),
+			_ if !self.choices.is_empty() => {
+				bail!("Only choice options can have choices, not '{}'", self.name)
+			}
+			_ => (),
+		}
+		Ok(())
+	}
+
+	fn to_application_option(&self) -> ApplicationCommandOption {
+		let option_type = match self.kind {
+			OptionKind::User => ApplicationCommandOptionType::User,
+			OptionKind::String | OptionKind::Choice => ApplicationCommandOptionType::String,
+			OptionKind::Integer => ApplicationCommandOptionType::Integer,
+		};
+		ApplicationCommandOption {
+			option_type,
+			name: self.name.clone(),
+			description: self.description.clone(),
+			required: self.required,
+			choices: self
+				.choices
+				.iter()
+				.map(|c| ApplicationCommandOptionChoice {
+					name: c.clone(),
+					value: Value::String(c.clone()),
+				})
+				.collect(),
+			options: Vec::new(),
+		}
+	}
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -65,6 +245,14 @@ pub struct Command {
 	#[serde(flatten)]
 	command_type: CommandType,
 	description: String,
+	#[serde(default)]
+	options: Vec<CommandOption>,
+	// Overrides the cooldown of the module
+	#[serde(default)]
+	cooldown: Option<CooldownConfig>,
+	// Applies on top of the permission of the module
+	#[serde(default)]
+	permission: Permission,
 }
 
 impl Command {
@@ -82,52 +270,107 @@ impl Command {
 	fn allowed(&self, channel_id: ChannelId) -> bool {
 		self.channels.contains(&channel_id) == self.is_whitelist()
 	}
+
+	fn validate(&self) -> Result<()> {
+		let mut known: Vec<String> = PLACEHOLDERS.iter().map(|p| p.to_string()).collect();
+		let mut optional = false;
+		for option in &self.options {
+			option.validate()?;
+			if option.required && optional {
+				bail!("Required option '{}' follows an optional one", option.name);
+			}
+			optional |= !option.required;
+
+			let mut placeholders = vec![option.placeholder()];
+			if option.kind == OptionKind::User {
+				placeholders.push(option.name_placeholder());
+			}
+			for placeholder in placeholders {
+				if known.contains(&placeholder) {
+					bail!(
+						"Option '{}' clashes with placeholder %{}%",
+						option.name,
+						placeholder
+					);
+				}
+				known.push(placeholder);
+			}
+		}
+		if self.options.len() > 25 {
+			bail!("At most 25 options are allowed");
+		}
+		self.command_type.validate()?;
+		let known: Vec<&str> = known.iter().map(|k| k.as_str()).collect();
+		for t in self.command_type.templates() {
+			template::validate(t, &known)?;
+		}
+		Ok(())
+	}
+
+	fn spec(&self, name: &str) -> CommandSpec {
+		let options = self
+			.options
+			.iter()
+			.map(|o| o.to_application_option())
+			.collect();
+		CommandSpec::new(name, &self.description, options)
+	}
+}
+
+/// Name of the member as shown in the server
+fn display_name(member: &Member) -> Option<&str> {
+	member
+		.nick
+		.as_deref()
+		.or_else(|| member.user.as_ref().map(|u| u.username.as_str()))
+}
+
+/// Percent-encode everything but unreserved characters, so `value` stays within
+/// one segment of a URL path
+fn encode_path(value: &str) -> String {
+	let mut encoded = String::with_capacity(value.len());
+	for b in value.bytes() {
+		if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+			encoded.push(b as char);
+		} else {
+			encoded.push_str(&format!("%{:02X}", b));
+		}
+	}
+	encoded
 }
 
-#[derive(Debug)]
 pub struct Commands {
 	config: CommandsConfig,
-	last: HashMap<String, Instant>,
+	registry: CommandRegistry,
+	cooldowns: Cooldowns,
+	usage: Usage,
+	tracker: Tracker,
 }
 
 impl Commands {
-	pub fn new() -> Self {
+	pub fn new(registry: CommandRegistry, usage: Usage, tracker: Tracker) -> Self {
+		registry.expect("commands");
 		Self {
 			config: Default::default(),
-			last: HashMap::new(),
+			registry,
+			cooldowns: Cooldowns::new(),
+			usage,
+			tracker,
 		}
 	}
 
-	fn register_commands(&self, guild: &Guild) {
-		let commands: Vec<_> = self
-			.config
-			.commands
-			.iter()
-			.filter(|&(n, c)| match guild.command(n) {
-				Some(ac) => c.description != ac.description,
-				None => true,
-			})
-			.map(|(name, command)| (name.clone(), command.description.clone()))
-			.collect();
-		if commands.is_empty() {
-			return;
-		}
-		let client = guild.client();
-		let guild_id = guild.id();
-		let application_id = guild.application_id();
-
-		tokio::spawn(async move {
-			for (name, description) in commands {
-				match client
-					.create_command(application_id, guild_id, &name, &description, Vec::new())
-					.await
-				{
-					Ok(_) => debug!("Registered '{}'", name),
-					Err(e) => warn!("Unable to register '{}': {}", name, e),
-				}
-				sleep(Duration::from_secs(5)).await;
-			}
-		});
+	/// Declare the commands of the config, or none when the module is disabled
+	fn declare_commands(&self) {
+		let specs = if self.config.enabled {
+			self.config
+				.commands
+				.iter()
+				.map(|(name, command)| command.spec(name))
+				.collect()
+		} else {
+			Vec::new()
+		};
+		self.registry.declare("commands", specs);
 	}
 
 	fn interaction(&mut self, guild: &Guild, interaction: &Interaction) -> bool {
@@ -135,6 +378,10 @@ impl Commands {
 			return true;
 		}
 
+		if interaction.interaction_type.is_component_interaction() {
+			return self.button(guild, interaction);
+		}
+
 		/*// Check if first word matches a known command
 		let first = match message.content.split(" ").next() {
 			Some(w) => w,
@@ -177,46 +424,210 @@ impl Commands {
 				.respond(guild)
 				.content("Command not allowed in this channel")
 				.ephemeral()
-				.spawn();
+				.spawn(&self.tracker);
+			self.usage
+				.record(command_name, interaction, Outcome::Denied);
+			return false;
+		}
+
+		// Check if the member is allowed to use the command
+		if !self
+			.config
+			.permission
+			.check(guild, interaction, &self.tracker)
+			|| !command.permission.check(guild, interaction, &self.tracker)
+		{
+			self.usage
+				.record(command_name, interaction, Outcome::Denied);
 			return false;
 		}
 
 		// Check cooldown
-		if let Some(last) = self.last.get(command_name) {
-			let left = (self.config.cooldown as u64).saturating_sub(last.elapsed().as_secs());
-			if left > 1 {
-				interaction
-					.respond(guild)
-					.content(format!("Command on cooldown for {} more seconds", left))
-					.ephemeral()
-					.spawn();
-				return false;
-			}
+		let cooldown = command.cooldown.as_ref().unwrap_or(&self.config.cooldown);
+		if let Some(left) = self
+			.cooldowns
+			.check(guild, cooldown, command_name, interaction)
+		{
+			interaction
+				.respond(guild)
+				.content(cooldown_message(left))
+				.ephemeral()
+				.spawn(&self.tracker);
+			self.usage
+				.record(command_name, interaction, Outcome::Cooldown);
+			return false;
 		}
 
-		self.last.insert(command_name.to_string(), Instant::now());
+		self.usage
+			.record(command_name, interaction, Outcome::Allowed);
 		info!(
 			"Triggered '{}'{}",
 			command_name,
 			guild.channel(channel_id).display(" in #{}")
 		);
 
+		// Fill in the placeholders of the invoking member, the channel and the options
+		let mut owned = Vec::new();
+		let mut mentioned = Vec::new();
+		if let Some(member) = &interaction.member {
+			if let Some(user) = &member.user {
+				owned.push(("USER".to_string(), format!("<@{}>", user.id)));
+			}
+			if let Some(name) = display_name(member) {
+				owned.push(("USER_NAME".to_string(), name.to_string()));
+			}
+		}
+		owned.push(("CHANNEL".to_string(), format!("<#{}>", channel_id)));
+		if let Some(name) = guild.channel(channel_id).and_then(|c| c.name.clone()) {
+			owned.push(("CHANNEL_NAME".to_string(), name));
+		}
+		owned.push(("SERVER".to_string(), guild.name().to_string()));
+		for option in &command.options {
+			let value = interaction
+				.data
+				.options
+				.iter()
+				.find(|o| o.name == option.name)
+				.and_then(|o| o.value.clone())
+				.unwrap_or_default();
+			if option.kind == OptionKind::User {
+				let user_id = UserId::from_str(&value).ok();
+				let name = user_id
+					.and_then(|id| guild.member(id))
+					.and_then(display_name)
+					.unwrap_or_default()
+					.to_string();
+				owned.push((option.name_placeholder(), name));
+				match user_id {
+					Some(id) => {
+						mentioned.push(id);
+						owned.push((option.placeholder(), format!("<@{}>", id)));
+					}
+					None => owned.push((option.placeholder(), String::new())),
+				}
+			} else {
+				owned.push((option.placeholder(), value));
+			}
+		}
+		let values: Values = owned
+			.iter()
+			.map(|(k, v)| (k.as_str(), v.as_str()))
+			.collect();
+
 		let res = match &command.command_type {
-			CommandType::Text(text) => interaction.respond(guild).content(text.clone()),
+			CommandType::Text(text) => interaction
+				.respond(guild)
+				.content(template::render(text, &values)),
 			CommandType::Image(name) => {
+				// Values end up in the path of the URL
+				let encoded: Vec<_> = owned
+					.iter()
+					.map(|(k, v)| (k.as_str(), encode_path(v)))
+					.collect();
+				let encoded: Values = encoded.iter().map(|(k, v)| (*k, v.as_str())).collect();
+				let name = template::render(name, &encoded);
 				let embed = Embed::new().image(format!("{}{}", self.config.cdn_url, name));
 				interaction.respond(guild).embed(embed)
 			}
+			CommandType::Random(responses) => {
+				// Validated to have a positive total weight that fits in a u32
+				let dist = WeightedIndex::new(responses.iter().map(|r| r.weight())).unwrap();
+				let text = responses[dist.sample(&mut thread_rng())].text();
+				interaction
+					.respond(guild)
+					.content(template::render(text, &values))
+			}
+			CommandType::Embed(embed) => interaction.respond(guild).embed(embed.render(&values)),
+			CommandType::Gallery(images) => {
+				let (embed, rows) = self.gallery_page(command_name, images, 0);
+				interaction.respond(guild).embed(embed).component_rows(rows)
+			}
 		};
-		res.spawn();
+		res.allowed_mentions(AllowedMentions::none().users(mentioned))
+			.spawn(&self.tracker);
 
 		false
 	}
+
+	/// Go to another page of a gallery. The page is part of the button id, so
+	/// galleries keep working after a restart
+	fn button(&self, guild: &Guild, interaction: &Interaction) -> bool {
+		let id = interaction
+			.data
+			.custom_id
+			.as_deref()
+			.and_then(|id| id.strip_prefix(BUTTON_ID_PREFIX))
+			.and_then(|id| id.strip_prefix('_'));
+		let id = match id {
+			Some(id) => id,
+			None => return true,
+		};
+		let gallery = id.rsplit_once('_').and_then(|(command_name, page)| {
+			let images = match &self.config.commands.get(command_name)?.command_type {
+				CommandType::Gallery(images) => images,
+				_ => return None,
+			};
+			let page = page.parse::<usize>().ok()?.min(images.len() - 1);
+			Some((command_name, images, page))
+		});
+
+		match gallery {
+			Some((command_name, images, page)) => {
+				let (embed, rows) = self.gallery_page(command_name, images, page);
+				interaction
+					.respond(guild)
+					.content("")
+					.embed(embed)
+					.component_rows(rows)
+					.spawn(&self.tracker);
+			}
+			None => {
+				// Gallery has been removed or changed since the message was sent
+				interaction
+					.respond(guild)
+					.content("This gallery is no longer available")
+					.component_rows(Vec::new())
+					.spawn(&self.tracker);
+			}
+		}
+
+		false
+	}
+
+	fn gallery_page(
+		&self,
+		command_name: &str,
+		images: &[String],
+		page: usize,
+	) -> (Embed, Vec<RowComponent>) {
+		let embed = Embed::new()
+			.image(format!("{}{}", self.config.cdn_url, images[page]))
+			.footer(format!("{}/{}", page + 1, images.len()));
+		if images.len() == 1 {
+			return (embed, Vec::new());
+		}
+
+		let id = |page| format!("{}_{}_{}", BUTTON_ID_PREFIX, command_name, page);
+		let mut previous = ButtonComponent::secondary(id(page.saturating_sub(1))).label("◀");
+		if page == 0 {
+			previous = previous.disabled();
+		}
+		let mut next = ButtonComponent::secondary(id(page + 1)).label("▶");
+		if page + 1 == images.len() {
+			next = next.disabled();
+		}
+		let row = RowComponent::new().button(previous).button(next);
+		(embed, vec![row])
+	}
 }
 
 impl EventHandler for Commands {
-	fn config(&mut self, guild: &Guild, name: &str, config: Value) -> Option<Value> {
-		let config = load_config!(name, "commands", config);
+	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
+		let config: CommandsConfig = load_config!(name, "commands", config);
+		if let Err(e) = config.validate() {
+			warn!("Invalid commands config: {:#}", e);
+			return None;
+		}
 		let old = mem::replace(&mut self.config, config);
 		if old.enabled != self.config.enabled {
 			if self.config.enabled {
@@ -230,7 +641,7 @@ impl EventHandler for Commands {
 		} else {
 			info!("Config updated");
 		}
-		self.register_commands(guild);
+		self.declare_commands();
 
 		None
 	}
@@ -243,3 +654,369 @@ impl EventHandler for Commands {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use crate::usage::temp_usage;
+	use common::discord::types::event;
+	use common::testing::{feed, GuildState, StandIn};
+	use serde_json::json;
+	use std::time::Duration;
+	use warp::http::Method;
+
+	#[tokio::test]
+	async fn responds() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "commands")
+			.text_channel(4, "general")
+			.member(5, "someone", &[])
+			.command(6, "hello", "Say hello");
+		let guild = state.guild(&stand_in);
+		let interaction = |id, channel_id, name| {
+			let interaction = state.command_interaction(id, channel_id, 5, name, &[]);
+			Event::InteractionCreate(event::InteractionCreate {
+				interaction: serde_json::from_value(interaction).unwrap(),
+			})
+		};
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let usage = temp_usage("commands").await;
+		let mut commands = Commands::new(registry.clone(), usage, tracker);
+		let config = json!({
+			"enabled": true,
+			"channels": ["3"],
+			"commands": {
+				"hello": { "type": "text", "content": "Hello!", "description": "Say hello" },
+				"logo": { "type": "image", "content": "logo.png", "description": "Show the logo" },
+			},
+			"cdn_url": "https://cdn.example/",
+			"cooldown": 60,
+		});
+		assert!(commands.config(&guild, "commands", config).is_none());
+		registry.sync(&guild);
+
+		// Only the command that isn't registered yet is created
+		let requests = stand_in.wait_for(1, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].is(Method::POST, "/applications/2/guilds/1/commands"));
+		assert_eq!(requests[0].json().unwrap()["name"], "logo");
+		stand_in.take_requests();
+
+		let events = [
+			interaction(10, 3, "hello"),
+			interaction(11, 3, "hello"),
+			interaction(12, 4, "logo"),
+			interaction(13, 3, "logo"),
+			interaction(14, 3, "other"),
+		];
+		assert_eq!(
+			feed(&mut commands, &guild, &events),
+			[false, false, false, false, true]
+		);
+
+		let mut requests = stand_in.wait_for(4, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 4);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		let response = |i: usize, path: &str| {
+			assert!(requests[i].is(Method::POST, path));
+			let response = requests[i].json().unwrap();
+			assert_eq!(response["type"], 4);
+			response["data"].clone()
+		};
+		let hello = response(0, "/interactions/10/token10/callback");
+		assert_eq!(hello["content"], "Hello!");
+		assert!(hello["flags"].is_null());
+		let cooldown = response(1, "/interactions/11/token11/callback");
+		assert!(cooldown["content"]
+			.as_str()
+			.unwrap()
+			.contains("on cooldown"));
+		assert_eq!(cooldown["flags"], 64);
+		let not_allowed = response(2, "/interactions/12/token12/callback");
+		assert_eq!(
+			not_allowed["content"],
+			"Command not allowed in this channel"
+		);
+		assert_eq!(not_allowed["flags"], 64);
+		let logo = response(3, "/interactions/13/token13/callback");
+		assert_eq!(
+			logo["embeds"][0]["image"]["url"],
+			"https://cdn.example/logo.png"
+		);
+	}
+
+	#[tokio::test]
+	async fn response_types() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "commands")
+			.member(5, "someone", &[]);
+		let guild = state.guild(&stand_in);
+		let event = |interaction| {
+			Event::InteractionCreate(event::InteractionCreate {
+				interaction: serde_json::from_value(interaction).unwrap(),
+			})
+		};
+		let command = |id, name| event(state.command_interaction(id, 3, 5, name, &[]));
+		let button = |id, custom_id| event(state.button_interaction(id, 3, 5, custom_id));
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let usage = temp_usage("commands_response_types").await;
+		let mut commands = Commands::new(registry, usage, tracker);
+		let config = json!({
+			"enabled": true,
+			"channels": ["3"],
+			"commands": {
+				"coin": {
+					"type": "random",
+					"content": ["Heads", { "text": "Tails", "weight": 0 }],
+					"description": "Flip a coin",
+				},
+				"card": {
+					"type": "embed",
+					"content": {
+						"title": "Hi %USER_NAME%",
+						"fields": [{ "name": "Channel", "value": "%CHANNEL%" }],
+					},
+					"description": "Show a card",
+				},
+				"cats": {
+					"type": "gallery",
+					"content": ["1.png", "2.png"],
+					"description": "Show cats",
+				},
+			},
+			"cdn_url": "https://cdn.example/",
+			"cooldown": 0,
+		});
+		assert!(commands.config(&guild, "commands", config).is_none());
+
+		let events = [
+			command(10, "coin"),
+			command(11, "card"),
+			command(12, "cats"),
+			button(13, "commands_cats_1"),
+			button(14, "commands_cats_5"),
+			button(15, "commands_coin_1"),
+			button(16, "commands_cats_x"),
+			button(17, "other_1"),
+		];
+		assert_eq!(
+			feed(&mut commands, &guild, &events),
+			[false, false, false, false, false, false, false, true]
+		);
+
+		let mut requests = stand_in.wait_for(7, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 7);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		let response = |i: usize, kind: u8| {
+			let path = format!("/interactions/{0}/token{0}/callback", 10 + i);
+			assert!(requests[i].is(Method::POST, &path));
+			let response = requests[i].json().unwrap();
+			assert_eq!(response["type"], kind);
+			response["data"].clone()
+		};
+		let buttons = |data: &Value| {
+			data["components"][0]["components"]
+				.as_array()
+				.unwrap()
+				.iter()
+				.map(|b| (b["custom_id"].clone(), b["disabled"] == true))
+				.collect::<Vec<_>>()
+		};
+
+		// Responses with a weight of zero are never picked
+		assert_eq!(response(0, 4)["content"], "Heads");
+		let card = response(1, 4);
+		assert_eq!(card["embeds"][0]["title"], "Hi someone");
+		assert_eq!(card["embeds"][0]["fields"][0]["value"], "<#3>");
+
+		let first = response(2, 4);
+		assert_eq!(
+			first["embeds"][0]["image"]["url"],
+			"https://cdn.example/1.png"
+		);
+		assert_eq!(first["embeds"][0]["footer"]["text"], "1/2");
+		assert_eq!(
+			buttons(&first),
+			[
+				(json!("commands_cats_0"), true),
+				(json!("commands_cats_1"), false)
+			]
+		);
+
+		// Pages past the end show the last image
+		for i in [3, 4] {
+			let last = response(i, 7);
+			assert_eq!(
+				last["embeds"][0]["image"]["url"],
+				"https://cdn.example/2.png"
+			);
+			assert_eq!(last["embeds"][0]["footer"]["text"], "2/2");
+			assert_eq!(
+				buttons(&last),
+				[
+					(json!("commands_cats_0"), false),
+					(json!("commands_cats_2"), true)
+				]
+			);
+		}
+
+		// Buttons of commands that aren't galleries (anymore) are removed
+		for i in [5, 6] {
+			let stale = response(i, 7);
+			assert_eq!(stale["content"], "This gallery is no longer available");
+			assert_eq!(stale["components"], json!([]));
+		}
+	}
+
+	#[tokio::test]
+	async fn options() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "commands")
+			.member(5, "someone", &[])
+			.member(7, "friend", &[]);
+		let guild = state.guild(&stand_in);
+		let command = |id, name, options: &[(&str, ApplicationCommandOptionType, &str)]| {
+			let interaction = state.command_interaction(id, 3, 5, name, options);
+			Event::InteractionCreate(event::InteractionCreate {
+				interaction: serde_json::from_value(interaction).unwrap(),
+			})
+		};
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let usage = temp_usage("commands_options").await;
+		let mut commands = Commands::new(registry, usage, tracker);
+		let config = json!({
+			"enabled": true,
+			"channels": ["3"],
+			"commands": {
+				"hug": {
+					"type": "text",
+					"content": "%USER_NAME% hugs %TARGET% (%TARGET_NAME%) in %CHANNEL_NAME% (%TIMES%x)",
+					"description": "Hug someone",
+					"options": [
+						{ "name": "target", "type": "user", "description": "Who", "required": true },
+						{ "name": "times", "type": "integer", "description": "How often" },
+					],
+				},
+				"sticker": {
+					"type": "image",
+					"content": "stickers/%NAME%.png",
+					"description": "Show a sticker",
+					"options": [{ "name": "name", "type": "string", "description": "Sticker" }],
+				},
+			},
+			"cdn_url": "https://cdn.example/",
+			"cooldown": 0,
+		});
+		assert!(commands.config(&guild, "commands", config).is_none());
+
+		let events = [
+			command(
+				10,
+				"hug",
+				&[
+					("target", ApplicationCommandOptionType::User, "7"),
+					("times", ApplicationCommandOptionType::Integer, "3"),
+				],
+			),
+			command(
+				11,
+				"hug",
+				&[("target", ApplicationCommandOptionType::User, "nobody")],
+			),
+			command(
+				12,
+				"sticker",
+				&[("name", ApplicationCommandOptionType::String, "../a b?")],
+			),
+		];
+		assert_eq!(feed(&mut commands, &guild, &events), [false, false, false]);
+
+		let mut requests = stand_in.wait_for(3, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 3);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		let data = |i: usize| requests[i].json().unwrap()["data"].clone();
+
+		// Only the user of a user option may be mentioned
+		let hug = data(0);
+		assert_eq!(
+			hug["content"],
+			"someone hugs <@7> (friend) in commands (3x)"
+		);
+		assert_eq!(hug["allowed_mentions"]["users"], json!(["7"]));
+		let invalid = data(1);
+		assert_eq!(invalid["content"], "someone hugs  () in commands (x)");
+
+		// Values can't break out of the path of the image
+		assert_eq!(
+			data(2)["embeds"][0]["image"]["url"],
+			"https://cdn.example/stickers/..%2Fa%20b%3F.png"
+		);
+	}
+
+	#[test]
+	fn validates() {
+		let command = |options: Value, content: &str| {
+			let command = json!({
+				"type": "text",
+				"content": content,
+				"description": "Test",
+				"options": options,
+			});
+			serde_json::from_value::<Command>(command)
+				.unwrap()
+				.validate()
+		};
+		let option = |name: &str, kind: &str, required: bool| json!({ "name": name, "type": kind, "description": "Option", "required": required });
+
+		let target = option("target", "user", true);
+		let note = option("note-text", "string", false);
+		let options = json!([target.clone(), note.clone()]);
+		assert!(command(options.clone(), "%USER% %TARGET% %TARGET_NAME% %NOTE_TEXT%").is_ok());
+		assert!(command(options, "%TARGET% %NOTE%").is_err());
+
+		// Required options can't follow optional ones, or clash with other placeholders
+		assert!(command(json!([note, target.clone()]), "").is_err());
+		assert!(command(json!([option("channel", "string", true)]), "").is_err());
+		let clash = json!([target, option("target_name", "string", true)]);
+		assert!(command(clash, "").is_err());
+		assert!(command(json!([option("Target", "user", true)]), "").is_err());
+		let options = (0..26)
+			.map(|i| option(&format!("o{}", i), "string", false))
+			.collect::<Vec<_>>();
+		assert!(command(json!(options), "").is_err());
+
+		// Only choice options have choices
+		let mut choice = option("color", "choice", true);
+		assert!(command(json!([choice.clone()]), "").is_err());
+		choice["choices"] = json!(["red", "blue"]);
+		assert!(command(json!([choice]), "%COLOR%").is_ok());
+		let mut string = option("color", "string", true);
+		string["choices"] = json!(["red"]);
+		assert!(command(json!([string]), "").is_err());
+	}
+
+	#[test]
+	fn random_weights() {
+		let command = |content| {
+			let command = json!({ "type": "random", "content": content, "description": "Pick" });
+			serde_json::from_value::<Command>(command)
+				.unwrap()
+				.validate()
+		};
+		assert!(command(json!(["a", { "text": "b", "weight": 3 }])).is_ok());
+		assert!(command(json!([{ "text": "a", "weight": 0 }])).is_err());
+		let max = json!({ "text": "a", "weight": u32::MAX });
+		assert!(command(json!([max.clone(), { "text": "b", "weight": 0 }])).is_ok());
+		assert!(command(json!([max, "b"])).is_err());
+	}
+}
@@ -0,0 +1,1290 @@
+use crate::supervisor::{Shutdown, Supervisor, Tracker};
+use crate::template::{self, EmbedTemplate, Values};
+use crate::websub::{self, Rejected, Verification};
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::{DateTime, Utc};
+use common::discord;
+use common::discord::types::{AllowedMentions, ChannelId, RoleId};
+use common::Client;
+use common::{EventHandler, Guild, Migration, Storage};
+use futures::channel::mpsc;
+use futures::lock::Mutex as AsyncMutex;
+use futures::{SinkExt, StreamExt};
+use http::StatusCode;
+use log::{debug, info, warn};
+use roxmltree::Node;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{query, query_as};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::DerefMut;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use warp::filters::BoxedFilter;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Reply};
+
+type Secrets = Arc<Mutex<HashMap<String, String>>>;
+type Unsubscribing = Arc<Mutex<HashSet<String>>>;
+type Leases = Arc<Mutex<HashMap<String, Lease>>>;
+
+const CREATE_FEEDS_SQLITE: &str = r#"
+	CREATE TABLE feed_entries (
+		feed_url TEXT NOT NULL,
+		entry_id TEXT NOT NULL,
+		created_timestamp INTEGER NOT NULL,
+		PRIMARY KEY (feed_url, entry_id)
+	);
+	CREATE TABLE feed_secrets (
+		feed TEXT PRIMARY KEY NOT NULL,
+		secret TEXT NOT NULL
+	);
+"#;
+
+const CREATE_FEEDS_POSTGRES: &str = r#"
+	CREATE TABLE feed_entries (
+		feed_url TEXT NOT NULL,
+		entry_id TEXT NOT NULL,
+		created_timestamp BIGINT NOT NULL,
+		PRIMARY KEY (feed_url, entry_id)
+	);
+	CREATE TABLE feed_secrets (
+		feed TEXT PRIMARY KEY NOT NULL,
+		secret TEXT NOT NULL
+	);
+"#;
+
+// Feeds whose backlog has been recorded. Feeds with entries already are seeded
+const CREATE_FEED_SEEDS: &str = r#"
+	CREATE TABLE feed_seeds (
+		feed_url TEXT PRIMARY KEY NOT NULL
+	);
+	INSERT INTO feed_seeds (feed_url) SELECT DISTINCT feed_url FROM feed_entries;
+"#;
+
+const MIGRATIONS: &[Migration] = &[
+	Migration {
+		name: "create_feeds",
+		sqlite: CREATE_FEEDS_SQLITE,
+		postgres: CREATE_FEEDS_POSTGRES,
+	},
+	Migration {
+		name: "create_feed_seeds",
+		sqlite: CREATE_FEED_SEEDS,
+		postgres: CREATE_FEED_SEEDS,
+	},
+];
+
+// Placeholders available in the templates of a feed
+const PLACEHOLDERS: &[&str] = &["FEED", "TITLE", "URL", "AUTHOR", "SUMMARY", "DATE", "ROLE"];
+// Maximum length of %SUMMARY%
+const SUMMARY_LENGTH: usize = 300;
+const POLL_INTERVAL_MIN: u64 = 60;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FeedConfig {
+	url: String,
+	channel_id: ChannelId,
+	// Message template, see `PLACEHOLDERS`. May be empty if an embed is set
+	#[serde(default)]
+	text: String,
+	#[serde(default)]
+	embed: Option<EmbedTemplate>,
+	// Role that may be pinged with %ROLE%
+	#[serde(default)]
+	role: Option<RoleId>,
+	// Subscribe to the hub if the feed advertises one
+	#[serde(default = "default_websub")]
+	websub: bool,
+}
+
+fn default_websub() -> bool {
+	true
+}
+
+impl FeedConfig {
+	fn validate(&self) -> Result<()> {
+		if self.embed.is_none() && self.text.is_empty() {
+			bail!("Either text or embed is required");
+		}
+		let embed = self.embed.iter().flat_map(|e| e.templates());
+		for t in embed.chain([&self.text]) {
+			template::validate(t, PLACEHOLDERS)?;
+			if self.role.is_none() && t.contains("%ROLE%") {
+				bail!("%ROLE% is used without setting a role");
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FeedsConfig {
+	enabled: bool,
+	// Feeds by name, which is used in the callback URL for the hub
+	feeds: HashMap<String, FeedConfig>,
+	#[serde(default)]
+	log_channel: Option<ChannelId>,
+	// Interval in seconds at which every feed is fetched, also when subscribed to a hub
+	#[serde(default = "default_poll_interval")]
+	poll_interval: u64,
+}
+
+fn default_poll_interval() -> u64 {
+	15 * 60
+}
+
+impl FeedsConfig {
+	fn validate(&self) -> Result<()> {
+		for (name, feed) in &self.feeds {
+			let valid_name = !name.is_empty()
+				&& name
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+			if !valid_name {
+				bail!(
+					"Feed name '{}' may only contain letters, digits, '-' and '_'",
+					name
+				);
+			}
+			feed.validate()
+				.with_context(|| format!("Feed '{}'", name))?;
+		}
+		Ok(())
+	}
+}
+
+impl Default for FeedsConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			feeds: HashMap::new(),
+			log_channel: None,
+			poll_interval: default_poll_interval(),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct Feeds {
+	// Safe to use std Mutex since we only need to keep the lock for a very short time and
+	// don't need to hold it across await points
+	config: Arc<Mutex<FeedsConfig>>,
+	// Secrets the hubs use to sign their content, per feed
+	secrets: Secrets,
+	// Feeds we requested to unsubscribe from, awaiting verification by the hub
+	unsubscribing: Unsubscribing,
+	leases: Leases,
+	sender: mpsc::Sender<Event>,
+	// Wakes up the poller when the config changes
+	updates: mpsc::Sender<()>,
+}
+
+impl Feeds {
+	pub async fn new(
+		client: Client,
+		storage: Storage,
+		ext_url: &str,
+		supervisor: &mut Supervisor,
+	) -> Result<Self> {
+		storage.migrate("feeds", MIGRATIONS).await?;
+
+		// Load the secrets before the HTTP server starts, so content
+		// for existing subscriptions can be verified straight away
+		let secrets = query_as::<_, (String, String)>("SELECT feed, secret FROM feed_secrets")
+			.fetch_all(&*storage)
+			.await?
+			.into_iter()
+			.collect();
+		let secrets = Arc::new(Mutex::new(secrets));
+
+		let (sender, recv) = mpsc::channel(8);
+		let (updates, updates_recv) = mpsc::channel(1);
+		let config = Arc::new(Mutex::new(FeedsConfig::default()));
+		let unsubscribing = Arc::new(Mutex::new(HashSet::new()));
+		let leases = Arc::new(Mutex::new(HashMap::new()));
+
+		// The receivers outlive the tasks so they can be restarted after a crash
+		let recv = Arc::new(AsyncMutex::new(recv));
+		let updates_recv = Arc::new(AsyncMutex::new(updates_recv));
+		let tracker = supervisor.tracker();
+		let follower_config = Arc::clone(&config);
+		let follower_storage = storage.clone();
+		supervisor.spawn("feeds follower", move |shutdown| {
+			let follower = Follower {
+				config: Arc::clone(&follower_config),
+				recv: Arc::clone(&recv),
+				client: client.clone(),
+				storage: follower_storage.clone(),
+				tracker: tracker.clone(),
+			};
+			follower.run(shutdown)
+		});
+
+		let ext_url = format!("{}/feeds", ext_url);
+		let poller_config = Arc::clone(&config);
+		let poller_secrets = Arc::clone(&secrets);
+		let poller_unsubscribing = Arc::clone(&unsubscribing);
+		let poller_leases = Arc::clone(&leases);
+		let poller_sender = sender.clone();
+		supervisor.spawn("feeds poller", move |shutdown| {
+			let poller = Poller {
+				config: Arc::clone(&poller_config),
+				secrets: Arc::clone(&poller_secrets),
+				unsubscribing: Arc::clone(&poller_unsubscribing),
+				leases: Arc::clone(&poller_leases),
+				ext_url: ext_url.clone(),
+				updates: Arc::clone(&updates_recv),
+				sender: poller_sender.clone(),
+				storage: storage.clone(),
+			};
+			poller.run(shutdown)
+		});
+
+		Ok(Self {
+			config,
+			secrets,
+			unsubscribing,
+			leases,
+			sender,
+			updates,
+		})
+	}
+
+	pub fn routes(&self) -> BoxedFilter<(impl Reply,)> {
+		let config = Arc::clone(&self.config);
+		let secrets = Arc::clone(&self.secrets);
+		let unsubscribing = Arc::clone(&self.unsubscribing);
+		let leases = Arc::clone(&self.leases);
+		let sender = self.sender.clone();
+		let sender2 = self.sender.clone();
+		let rejected = Arc::new(Rejected::default());
+
+		let post = warp::path!("feeds" / String)
+			.and(warp::post())
+			.and(warp::header::optional::<String>("x-hub-signature"))
+			.and(warp::body::content_length_limit(1024 * 1024))
+			.and(warp::body::bytes())
+			.map(move |name, signature, bytes| {
+				http_post(&secrets, &sender, &rejected, name, signature, bytes)
+			});
+		let get = warp::path!("feeds" / String)
+			.and(warp::get())
+			.and(warp::query::<HashMap<String, String>>())
+			.map(move |name, query| {
+				http_get(&config, &unsubscribing, &leases, &sender2, name, query)
+					.unwrap_or(Box::new(StatusCode::BAD_REQUEST))
+			});
+		post.or(get).boxed()
+	}
+}
+
+impl EventHandler for Feeds {
+	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
+		let config: FeedsConfig = load_config!(name, "feeds", config);
+		if let Err(e) = config.validate() {
+			warn!("Invalid feeds config: {:#}", e);
+			return None;
+		}
+		let mut inner = self.config.lock().unwrap();
+		let old = mem::replace(inner.deref_mut(), config);
+		if old.enabled != inner.enabled {
+			if inner.enabled {
+				info!("Module enabled with {} feeds", inner.feeds.len());
+			} else {
+				info!("Module disabled");
+			}
+		} else {
+			info!("Config updated");
+		}
+
+		// Wake up the poller to update subscriptions and fetch new feeds. If the channel is
+		// full, an update is pending already
+		if inner.enabled || old.enabled {
+			let _ = self.updates.try_send(());
+		}
+
+		None
+	}
+
+	fn event(&mut self, _guild: &Guild, _event: &discord::types::Event) -> bool {
+		true
+	}
+}
+
+#[derive(Debug)]
+pub enum Event {
+	// Feed fetched by the poller, or pushed by the hub after verifying its signature
+	Content(String, Feed),
+	Unsubscribed(String),
+	SubscriptionDenied(String, Option<String>),
+	// Summary of deliveries with an invalid signature
+	Rejected(String),
+}
+
+// Undo recording an entry that couldn't be announced
+async fn forget_entry(storage: &Storage, feed_url: &str, entry_id: &str) -> Result<()> {
+	query("DELETE FROM feed_entries WHERE feed_url = $1 AND entry_id = $2")
+		.bind(feed_url)
+		.bind(entry_id)
+		.execute(&**storage)
+		.await?;
+	Ok(())
+}
+
+// Subscription to the hub of a feed
+#[derive(Debug)]
+struct Lease {
+	hub_url: String,
+	topic: String,
+	// Unset while waiting for verification
+	expires: Option<Instant>,
+}
+
+// Announces the entries of the feeds
+struct Follower {
+	config: Arc<Mutex<FeedsConfig>>,
+	recv: Arc<AsyncMutex<mpsc::Receiver<Event>>>,
+	client: Client,
+	storage: Storage,
+	tracker: Tracker,
+}
+
+impl Follower {
+	fn log(&self, message: String) {
+		let channel_id = match self.config.lock().unwrap().log_channel {
+			Some(c) => c,
+			None => return,
+		};
+		let client = self.client.clone();
+		self.tracker.spawn(async move {
+			if let Err(e) = client
+				.create_message(channel_id)
+				.content(message)
+				.send()
+				.await
+			{
+				warn!("Unable to create log message: {}", e)
+			}
+		});
+	}
+
+	async fn run(self, mut shutdown: Shutdown) -> Result<()> {
+		// Hold on to the receiver for as long as we're running
+		let recv = Arc::clone(&self.recv);
+		let mut recv = recv.lock().await;
+
+		loop {
+			let event = tokio::select! {
+				e = recv.next() => match e {
+					Some(e) => e,
+					None => break,
+				},
+				_ = shutdown.wait() => break,
+			};
+
+			match event {
+				Event::Content(name, parsed) => {
+					let feed = {
+						let inner = self.config.lock().unwrap();
+						inner.feeds.get(&name).filter(|_| inner.enabled).cloned()
+					};
+					let feed = match feed {
+						Some(f) => f,
+						None => continue,
+					};
+					if let Err(e) = self.process(&name, &feed, parsed).await {
+						warn!("Unable to process feed '{}': {}", name, e);
+					}
+				}
+				Event::Unsubscribed(name) => {
+					info!("Unsubscribed from '{}'", name);
+				}
+				Event::SubscriptionDenied(name, reason) => {
+					let reason = reason.unwrap_or_default();
+					warn!("Subscription to '{}' denied: {}", name, reason);
+					self.log(format!(
+						"Subscription to `{}` denied:\n```{}```",
+						name, reason
+					));
+				}
+				Event::Rejected(summary) => self.log(summary),
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Announce the entries of a feed that haven't been seen before
+	async fn process(&self, name: &str, feed: &FeedConfig, parsed: Feed) -> Result<()> {
+		// Don't flood the channel with the backlog of a feed we haven't seen before, even
+		// if that backlog was empty
+		let seeded = query("SELECT feed_url FROM feed_seeds WHERE feed_url = $1")
+			.bind(&feed.url)
+			.fetch_optional(&*self.storage)
+			.await?
+			.is_some();
+
+		// Feeds list the most recent entries first. Entries are recorded before sending, so
+		// they aren't announced twice, and forgotten again if sending fails
+		for entry in parsed.entries.iter().rev() {
+			let res = query(
+				"INSERT INTO feed_entries (feed_url, entry_id, created_timestamp) \
+				VALUES ($1, $2, $3) ON CONFLICT (feed_url, entry_id) DO NOTHING",
+			)
+			.bind(&feed.url)
+			.bind(&entry.id)
+			.bind(Utc::now().timestamp())
+			.execute(&*self.storage)
+			.await?;
+			if res.rows_affected() == 0 {
+				continue;
+			}
+			if !seeded {
+				debug!("Skipping '{}' from '{}': new feed", entry.title, name);
+				continue;
+			}
+			self.announce(feed, &parsed, entry);
+		}
+
+		if !seeded {
+			query(
+				"INSERT INTO feed_seeds (feed_url) VALUES ($1) ON CONFLICT (feed_url) DO NOTHING",
+			)
+			.bind(&feed.url)
+			.execute(&*self.storage)
+			.await?;
+		}
+
+		Ok(())
+	}
+
+	fn announce(&self, feed: &FeedConfig, parsed: &Feed, entry: &Entry) {
+		let role = feed.role.map(|r| format!("<@&{}>", r)).unwrap_or_default();
+		let summary = entry
+			.summary
+			.as_deref()
+			.map(|s| plain_text(s, SUMMARY_LENGTH))
+			.unwrap_or_default();
+		let date = entry
+			.published
+			.map(|d| format!("<t:{}:f>", d.timestamp()))
+			.unwrap_or_default();
+		let values = Values::from([
+			("FEED", parsed.title.as_str()),
+			("TITLE", &entry.title),
+			("URL", entry.url.as_deref().unwrap_or_default()),
+			("AUTHOR", entry.author.as_deref().unwrap_or_default()),
+			("SUMMARY", &summary),
+			("DATE", &date),
+			("ROLE", &role),
+		]);
+		let content = template::render(&feed.text, &values);
+		let embed = feed.embed.as_ref().map(|e| e.render(&values));
+		// Only ping the configured role, regardless of what the entry contains
+		let mentions = match feed.role {
+			Some(role) => AllowedMentions::none().roles(vec![role]),
+			None => AllowedMentions::none(),
+		};
+
+		let channel_id = feed.channel_id;
+		let feed_url = feed.url.clone();
+		let entry_id = entry.id.clone();
+		let title = entry.title.clone();
+		let client = self.client.clone();
+		let storage = self.storage.clone();
+		self.tracker.spawn(async move {
+			let mut message = client
+				.create_message(channel_id)
+				.content(content)
+				.allowed_mentions(mentions);
+			if let Some(embed) = embed {
+				message = message.embed(embed);
+			}
+			match message.send().await {
+				Ok(_) => info!("Announced '{}'", title),
+				Err(e) => {
+					warn!("Failed to announce '{}': {}", title, e);
+					// Let the next fetch or delivery announce it again
+					if let Err(e) = forget_entry(&storage, &feed_url, &entry_id).await {
+						warn!("Unable to forget entry '{}': {}", entry_id, e);
+					}
+				}
+			}
+		});
+	}
+}
+
+// Fetches the feeds and keeps the subscriptions to their hubs up to date
+struct Poller {
+	config: Arc<Mutex<FeedsConfig>>,
+	secrets: Secrets,
+	unsubscribing: Unsubscribing,
+	leases: Leases,
+	ext_url: String,
+	updates: Arc<AsyncMutex<mpsc::Receiver<()>>>,
+	sender: mpsc::Sender<Event>,
+	storage: Storage,
+}
+
+impl Poller {
+	/// Secret for the subscription to `feed`, generated and stored the first time
+	async fn secret(&self, feed: &str) -> Result<String> {
+		let secret = self.secrets.lock().unwrap().get(feed).cloned();
+		if let Some(secret) = secret {
+			return Ok(secret);
+		}
+
+		let secret = hex::encode(rand::random::<[u8; 32]>());
+		query("INSERT INTO feed_secrets (feed, secret) VALUES ($1, $2)")
+			.bind(feed)
+			.bind(&secret)
+			.execute(&*self.storage)
+			.await?;
+		self.secrets
+			.lock()
+			.unwrap()
+			.insert(feed.to_owned(), secret.clone());
+		Ok(secret)
+	}
+
+	async fn run(self, mut shutdown: Shutdown) -> Result<()> {
+		// Hold on to the receiver for as long as we're running
+		let updates = Arc::clone(&self.updates);
+		let mut updates = updates.lock().await;
+		let http = reqwest::ClientBuilder::new()
+			.timeout(Duration::from_secs(10))
+			.user_agent(concat!("dnbot/", env!("CARGO_PKG_VERSION")))
+			.use_rustls_tls()
+			.build()?;
+
+		loop {
+			let enabled;
+			let feeds;
+			let interval;
+			{
+				let inner = self.config.lock().unwrap();
+				enabled = inner.enabled;
+				feeds = inner.feeds.clone();
+				interval = Duration::from_secs(inner.poll_interval.max(POLL_INTERVAL_MIN));
+			}
+
+			// Unsubscribe from feeds that are no longer configured
+			let removed = self
+				.leases
+				.lock()
+				.unwrap()
+				.keys()
+				.filter(|name| {
+					let feed = feeds.get(*name).filter(|f| enabled && f.websub);
+					feed.is_none()
+				})
+				.cloned()
+				.collect::<Vec<_>>();
+			for name in removed {
+				let lease = self.leases.lock().unwrap().remove(&name);
+				if let Some(lease) = lease {
+					self.unsubscribe(&http, &name, &lease).await;
+				}
+			}
+
+			if enabled {
+				for (name, feed) in &feeds {
+					if let Err(e) = self.poll(&http, name, feed, interval).await {
+						warn!("Unable to fetch feed '{}': {:#}", name, e);
+					}
+				}
+			}
+
+			tokio::select! {
+				_ = sleep(interval) => {}
+				u = updates.next() => if u.is_none() {
+					break;
+				},
+				_ = shutdown.wait() => break,
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Fetch a feed, pass it on to the follower and make sure we're subscribed to its hub
+	async fn poll(
+		&self,
+		http: &reqwest::Client,
+		name: &str,
+		feed: &FeedConfig,
+		interval: Duration,
+	) -> Result<()> {
+		let bytes = http
+			.get(&feed.url)
+			.send()
+			.await?
+			.error_for_status()?
+			.bytes()
+			.await?;
+		let parsed = Feed::from_str(std::str::from_utf8(&bytes)?)?;
+
+		let hub = match (&parsed.hub, feed.websub) {
+			(Some(hub_url), true) => {
+				let topic = parsed.topic.clone().unwrap_or_else(|| feed.url.clone());
+				Some((hub_url.clone(), topic))
+			}
+			_ => None,
+		};
+		// Wait for room in the channel rather than dropping the fetched feed
+		self.sender
+			.clone()
+			.send(Event::Content(name.to_owned(), parsed))
+			.await?;
+
+		let (hub_url, topic) = match hub {
+			Some(hub) => hub,
+			None => {
+				// Feed doesn't advertise a hub (anymore)
+				let lease = self.leases.lock().unwrap().remove(name);
+				if let Some(lease) = lease {
+					self.unsubscribe(http, name, &lease).await;
+				}
+				return Ok(());
+			}
+		};
+
+		// Renew well before the lease expires, since we only check once per interval.
+		// Requests that still haven't been verified are sent again
+		let renew_before = Instant::now() + interval * 2;
+		let moved = {
+			let leases = self.leases.lock().unwrap();
+			match leases.get(name) {
+				Some(lease) => {
+					let moved = lease.hub_url != hub_url || lease.topic != topic;
+					if !moved && lease.expires.map_or(false, |e| e > renew_before) {
+						return Ok(());
+					}
+					moved
+				}
+				None => false,
+			}
+		};
+		if moved {
+			let lease = self.leases.lock().unwrap().remove(name);
+			if let Some(lease) = lease {
+				self.unsubscribe(http, name, &lease).await;
+			}
+		}
+
+		debug!("Subscribing to '{}' at '{}'", name, hub_url);
+		self.unsubscribing.lock().unwrap().remove(name);
+		let callback = format!("{}/{}", self.ext_url, name);
+		let secret = self.secret(name).await?;
+		// Record the lease first, the hub may verify it before answering the request
+		let renewing = {
+			let mut leases = self.leases.lock().unwrap();
+			let renewing = leases.contains_key(name);
+			leases.entry(name.to_owned()).or_insert_with(|| Lease {
+				hub_url: hub_url.clone(),
+				topic: topic.clone(),
+				expires: None,
+			});
+			renewing
+		};
+		let res = websub::subscribe(http, &hub_url, &topic, &callback, &secret).await;
+		if res.is_err() && !renewing {
+			self.leases.lock().unwrap().remove(name);
+		}
+		res.with_context(|| format!("Unable to subscribe at '{}'", hub_url))
+	}
+
+	async fn unsubscribe(&self, http: &reqwest::Client, name: &str, lease: &Lease) {
+		debug!("Unsubscribing from '{}'", name);
+		self.unsubscribing.lock().unwrap().insert(name.to_owned());
+		let callback = format!("{}/{}", self.ext_url, name);
+		if let Err(e) = websub::unsubscribe(http, &lease.hub_url, &lease.topic, &callback).await {
+			warn!("Unable to unsubscribe from '{}': {}", name, e);
+		}
+	}
+}
+
+// HTTP server
+fn http_get(
+	config: &Arc<Mutex<FeedsConfig>>,
+	unsubscribing: &Unsubscribing,
+	leases: &Leases,
+	sender: &mpsc::Sender<Event>,
+	name: String,
+	query: HashMap<String, String>,
+) -> Option<Box<dyn warp::Reply>> {
+	debug!("HTTP GET");
+	let verification = Verification::parse(&query)?;
+
+	// The feed may have been removed from the config, so only confirm if we asked for it
+	if let Verification::Unsubscribe { challenge, .. } = verification {
+		if !unsubscribing.lock().ok()?.remove(&name) {
+			return Some(Box::new(StatusCode::NOT_FOUND));
+		}
+		let _ = sender.clone().try_send(Event::Unsubscribed(name));
+		return Some(Box::new(challenge.to_string()));
+	}
+
+	if !config.lock().ok()?.feeds.contains_key(&name) {
+		return Some(Box::new(StatusCode::NOT_FOUND));
+	}
+
+	match verification {
+		Verification::Denied { reason, .. } => {
+			// Try again at the next poll, the feed is still fetched in the meantime
+			leases.lock().ok()?.remove(&name);
+			let reason = reason.map(|r| r.to_string());
+			let _ = sender
+				.clone()
+				.try_send(Event::SubscriptionDenied(name, reason));
+			Some(Box::new(StatusCode::OK))
+		}
+		Verification::Subscribe {
+			challenge,
+			lease_seconds,
+			..
+		} => {
+			// Only confirm subscriptions we requested
+			let mut leases = leases.lock().ok()?;
+			let lease = match leases.get_mut(&name) {
+				Some(l) => l,
+				None => return Some(Box::new(StatusCode::NOT_FOUND)),
+			};
+			info!("Subscribed to '{}'", name);
+			lease.expires = Some(Instant::now() + Duration::from_secs(lease_seconds));
+			Some(Box::new(challenge.to_string()))
+		}
+		Verification::Unsubscribe { .. } => None,
+	}
+}
+
+fn http_post(
+	secrets: &Secrets,
+	sender: &mpsc::Sender<Event>,
+	rejected: &Rejected,
+	name: String,
+	signature: Option<String>,
+	bytes: Bytes,
+) -> StatusCode {
+	debug!("HTTP POST");
+	let secret = secrets.lock().unwrap().get(&name).cloned();
+	let valid = match (secret, signature) {
+		(Some(secret), Some(signature)) => websub::verify_signature(&secret, &signature, &bytes),
+		_ => false,
+	};
+	if valid {
+		let parsed = std::str::from_utf8(&bytes)
+			.map_err(|e| anyhow!(e))
+			.and_then(Feed::from_str);
+		match parsed {
+			Ok(parsed) => {
+				let _ = sender.clone().try_send(Event::Content(name, parsed));
+			}
+			Err(e) => warn!("Invalid content for '{}': {}", name, e),
+		}
+	} else if let Some(summary) = rejected.reject(&name) {
+		// Forged deliveries are never queued, so they can't crowd out real ones. Only the
+		// occasional summary is
+		let _ = sender.clone().try_send(Event::Rejected(summary));
+	}
+
+	// Deliveries have to be acknowledged, even if we can't process them
+	StatusCode::OK
+}
+
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+
+/// RSS 2.0 or Atom feed
+#[derive(Debug)]
+pub struct Feed {
+	title: String,
+	// Hub and topic URL, if the feed supports WebSub
+	hub: Option<String>,
+	topic: Option<String>,
+	entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+	// Unique id, used to recognize entries we've seen before
+	id: String,
+	title: String,
+	url: Option<String>,
+	author: Option<String>,
+	summary: Option<String>,
+	published: Option<DateTime<Utc>>,
+}
+
+impl FromStr for Feed {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let doc = roxmltree::Document::parse(s)?;
+		let root = doc.root_element();
+		match (root.tag_name().name(), root.tag_name().namespace()) {
+			("feed", Some(ATOM_NS)) => Ok(parse_atom(root)),
+			("rss", None) => {
+				let channel =
+					child(root, "channel", None).ok_or_else(|| anyhow!("Missing channel"))?;
+				Ok(parse_rss(channel))
+			}
+			(name, _) => bail!("Unsupported feed format '{}'", name),
+		}
+	}
+}
+
+fn parse_atom(feed: Node) -> Feed {
+	let entries = feed
+		.children()
+		.filter(|n| n.has_tag_name((ATOM_NS, "entry")))
+		.map(|entry| {
+			let title = text(entry, "title", Some(ATOM_NS)).unwrap_or_default();
+			let url = link(entry, "alternate");
+			let id = text(entry, "id", Some(ATOM_NS))
+				.or_else(|| url.clone())
+				.unwrap_or_else(|| title.clone());
+			let author =
+				child(entry, "author", Some(ATOM_NS)).and_then(|a| text(a, "name", Some(ATOM_NS)));
+			let summary = text(entry, "summary", Some(ATOM_NS))
+				.or_else(|| text(entry, "content", Some(ATOM_NS)));
+			let published = text(entry, "published", Some(ATOM_NS))
+				.or_else(|| text(entry, "updated", Some(ATOM_NS)))
+				.and_then(|d| DateTime::parse_from_rfc3339(&d).ok())
+				.map(|d| d.with_timezone(&Utc));
+			Entry {
+				id,
+				title,
+				url,
+				author,
+				summary,
+				published,
+			}
+		})
+		.collect();
+
+	Feed {
+		title: text(feed, "title", Some(ATOM_NS)).unwrap_or_default(),
+		hub: link(feed, "hub"),
+		topic: link(feed, "self"),
+		entries,
+	}
+}
+
+fn parse_rss(channel: Node) -> Feed {
+	let entries = channel
+		.children()
+		.filter(|n| n.has_tag_name("item"))
+		.map(|item| {
+			let title = text(item, "title", None).unwrap_or_default();
+			let url = text(item, "link", None);
+			let id = text(item, "guid", None)
+				.or_else(|| url.clone())
+				.unwrap_or_else(|| title.clone());
+			let author = text(item, "author", None).or_else(|| {
+				item.children()
+					.find(|n| n.tag_name().name() == "creator")
+					.and_then(|n| n.text())
+					.map(|t| t.trim().to_owned())
+			});
+			let published = text(item, "pubDate", None)
+				.and_then(|d| DateTime::parse_from_rfc2822(&d).ok())
+				.map(|d| d.with_timezone(&Utc));
+			Entry {
+				id,
+				title,
+				url,
+				author,
+				summary: text(item, "description", None),
+				published,
+			}
+		})
+		.collect();
+
+	// Hubs are advertised with Atom links
+	Feed {
+		title: text(channel, "title", None).unwrap_or_default(),
+		hub: link(channel, "hub"),
+		topic: link(channel, "self"),
+		entries,
+	}
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, name: &str, namespace: Option<&str>) -> Option<Node<'a, 'i>> {
+	node.children()
+		.find(|n| n.tag_name().name() == name && n.tag_name().namespace() == namespace)
+}
+
+// Shorthand function to get the trimmed inner text of a child element
+fn text(node: Node, name: &str, namespace: Option<&str>) -> Option<String> {
+	let text = child(node, name, namespace)?.text()?.trim();
+	(!text.is_empty()).then(|| text.to_owned())
+}
+
+// URL of the Atom link with the given relation
+fn link(node: Node, rel: &str) -> Option<String> {
+	node.children()
+		.filter(|n| n.has_tag_name((ATOM_NS, "link")))
+		.find(|n| n.attribute("rel").unwrap_or("alternate") == rel)
+		.and_then(|n| n.attribute("href"))
+		.map(|href| href.to_owned())
+}
+
+// Strip the markup of an HTML fragment, shortening it to at most `max` characters
+fn plain_text(html: &str, max: usize) -> String {
+	let mut text = String::with_capacity(html.len());
+	let mut in_tag = false;
+	for c in html.chars() {
+		match c {
+			'<' => in_tag = true,
+			'>' if in_tag => {
+				in_tag = false;
+				text.push(' ');
+			}
+			_ if !in_tag => text.push(c),
+			_ => {}
+		}
+	}
+
+	let words = text.split_whitespace().collect::<Vec<_>>().join(" ");
+	if words.chars().count() <= max {
+		return words;
+	}
+	let mut short = words.chars().take(max - 1).collect::<String>();
+	short.push('…');
+	short
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::testing::{temp_storage, GuildState, StandIn};
+	use serde_json::json;
+	use warp::http::Method;
+
+	#[test]
+	fn atom() {
+		const DATA: &str = r#"
+			<feed xmlns="http://www.w3.org/2005/Atom">
+				<title>Blog</title>
+				<link rel="hub" href="https://hub.example.com/"/>
+				<link rel="self" href="https://example.com/feed.atom"/>
+				<entry>
+					<id>tag:example.com,2023:2</id>
+					<title>Second post</title>
+					<link href="https://example.com/2"/>
+					<author><name>Someone</name></author>
+					<summary>&lt;p&gt;Hello   &lt;b&gt;world&lt;/b&gt;&lt;/p&gt;</summary>
+					<updated>2023-03-09T19:05:24Z</updated>
+				</entry>
+				<entry>
+					<id>tag:example.com,2023:1</id>
+					<title>First post</title>
+				</entry>
+			</feed>
+		"#;
+
+		let feed = Feed::from_str(DATA).unwrap();
+		assert_eq!(feed.title, "Blog");
+		assert_eq!(feed.hub.as_deref(), Some("https://hub.example.com/"));
+		assert_eq!(feed.topic.as_deref(), Some("https://example.com/feed.atom"));
+		assert_eq!(feed.entries.len(), 2);
+		let entry = &feed.entries[0];
+		assert_eq!(entry.id, "tag:example.com,2023:2");
+		assert_eq!(entry.url.as_deref(), Some("https://example.com/2"));
+		assert_eq!(entry.author.as_deref(), Some("Someone"));
+		assert_eq!(
+			plain_text(entry.summary.as_deref().unwrap(), 100),
+			"Hello world"
+		);
+		assert!(entry.published.is_some());
+	}
+
+	#[test]
+	fn rss() {
+		const DATA: &str = r#"
+			<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/">
+				<channel>
+					<title>Podcast</title>
+					<atom:link rel="hub" href="https://hub.example.com/"/>
+					<item>
+						<title>Episode 1</title>
+						<link>https://example.com/1</link>
+						<dc:creator>Host</dc:creator>
+						<pubDate>Thu, 09 Mar 2023 19:05:24 +0000</pubDate>
+					</item>
+				</channel>
+			</rss>
+		"#;
+
+		let feed = Feed::from_str(DATA).unwrap();
+		assert_eq!(feed.title, "Podcast");
+		assert_eq!(feed.hub.as_deref(), Some("https://hub.example.com/"));
+		assert_eq!(feed.topic, None);
+		let entry = &feed.entries[0];
+		assert_eq!(entry.id, "https://example.com/1");
+		assert_eq!(entry.author.as_deref(), Some("Host"));
+		assert!(entry.published.is_some());
+		assert_eq!(plain_text("Twelve chars", 6), "Twelv…");
+	}
+
+	fn atom_feed(entries: &[(&str, &str)]) -> Feed {
+		let entries = entries
+			.iter()
+			.map(|(id, title)| format!("<entry><id>{}</id><title>{}</title></entry>", id, title))
+			.collect::<String>();
+		let feed = format!(
+			r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Blog</title>{}</feed>"#,
+			entries
+		);
+		Feed::from_str(&feed).unwrap()
+	}
+
+	async fn follower(stand_in: &StandIn, storage: &str) -> Follower {
+		let storage = temp_storage(storage).await;
+		storage.migrate("feeds", MIGRATIONS).await.unwrap();
+		let (_, recv) = mpsc::channel(8);
+		Follower {
+			config: Arc::new(Mutex::new(FeedsConfig::default())),
+			recv: Arc::new(AsyncMutex::new(recv)),
+			client: stand_in.client(),
+			storage,
+			tracker: Supervisor::new().tracker(),
+		}
+	}
+
+	fn feed_config() -> FeedConfig {
+		FeedConfig {
+			url: "https://example.com/feed.atom".to_owned(),
+			channel_id: "4".parse().unwrap(),
+			text: "New post: %TITLE%".to_owned(),
+			embed: None,
+			role: None,
+			websub: true,
+		}
+	}
+
+	// Announcements sent to the stand-in once `count` requests arrived, clearing the record.
+	// Waits a little longer to catch unexpected ones
+	async fn announcements(stand_in: &StandIn, count: usize) -> Vec<String> {
+		stand_in.wait_for(count, Duration::from_secs(5)).await;
+		sleep(Duration::from_millis(100)).await;
+		stand_in
+			.take_requests()
+			.into_iter()
+			.filter(|r| r.is(Method::POST, "/channels/4/messages"))
+			.map(|r| r.json().unwrap()["content"].as_str().unwrap().to_owned())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn process() {
+		let stand_in = StandIn::start().await;
+		let follower = follower(&stand_in, "feeds_process").await;
+		let feed = feed_config();
+
+		// The backlog of a new feed is only recorded
+		let parsed = atom_feed(&[("2", "Second post"), ("1", "First post")]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+		let requests = stand_in.wait_for(1, Duration::from_millis(200)).await;
+		assert!(requests.is_empty());
+
+		// Entries seen before are skipped, new ones announced oldest first
+		let parsed = atom_feed(&[
+			("4", "Fourth post"),
+			("3", "Third post"),
+			("2", "Second post"),
+		]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+		let requests = stand_in.wait_for(3, Duration::from_millis(500)).await;
+		assert_eq!(requests.len(), 2);
+		let mut contents = requests
+			.iter()
+			.map(|r| {
+				assert!(r.is(Method::POST, "/channels/4/messages"));
+				r.json().unwrap()["content"].as_str().unwrap().to_owned()
+			})
+			.collect::<Vec<_>>();
+		contents.sort();
+		assert_eq!(contents, ["New post: Fourth post", "New post: Third post"]);
+	}
+
+	#[tokio::test]
+	async fn seeds_empty_feed() {
+		let stand_in = StandIn::start().await;
+		let follower = follower(&stand_in, "feeds_seeds_empty_feed").await;
+		let feed = feed_config();
+
+		// A feed without entries is seeded all the same, so its first entry is announced
+		follower
+			.process("blog", &feed, atom_feed(&[]))
+			.await
+			.unwrap();
+		let parsed = atom_feed(&[("1", "First post")]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+		assert_eq!(announcements(&stand_in, 1).await, ["New post: First post"]);
+	}
+
+	#[tokio::test]
+	async fn announces_again_after_failure() {
+		let stand_in = StandIn::start().await;
+		let follower = follower(&stand_in, "feeds_announces_again_after_failure").await;
+		let feed = feed_config();
+		let parsed = atom_feed(&[("1", "First post")]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+
+		stand_in.respond(Method::POST, "/channels/4/messages", 500, "");
+		let parsed = atom_feed(&[("2", "Second post"), ("1", "First post")]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+		assert_eq!(announcements(&stand_in, 1).await, ["New post: Second post"]);
+
+		// Forgotten, so the next fetch announces it
+		let message =
+			GuildState::new(1, 2, "Test")
+				.member(2, "bot", &[])
+				.message(10, 4, 2, "Announcement");
+		stand_in.respond_json(Method::POST, "/channels/4/messages", 200, message);
+		let parsed = atom_feed(&[("2", "Second post"), ("1", "First post")]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+		assert_eq!(announcements(&stand_in, 1).await, ["New post: Second post"]);
+
+		let parsed = atom_feed(&[("2", "Second post"), ("1", "First post")]);
+		follower.process("blog", &feed, parsed).await.unwrap();
+		assert!(announcements(&stand_in, 1).await.is_empty());
+	}
+
+	#[test]
+	fn rejects_invalid_signature() {
+		let secrets = Arc::new(Mutex::new(HashMap::from([(
+			"blog".to_owned(),
+			"secret".to_owned(),
+		)])));
+		let (sender, mut recv) = mpsc::channel(8);
+		let rejected = Rejected::default();
+		let body = Bytes::from_static(br#"<feed xmlns="http://www.w3.org/2005/Atom"/>"#);
+		for (name, signature) in [("blog", None), ("blog", Some("sha1=00")), ("other", None)] {
+			let signature = signature.map(|s| s.to_owned());
+			let status = http_post(
+				&secrets,
+				&sender,
+				&rejected,
+				name.to_owned(),
+				signature,
+				body.clone(),
+			);
+			assert_eq!(status, StatusCode::OK);
+		}
+		// Only the first rejection is summarized within the interval
+		assert!(matches!(
+			recv.try_next(),
+			Ok(Some(Event::Rejected(s)))
+				if s == "1 delivery with an invalid signature rejected for `blog`"
+		));
+		assert!(recv.try_next().is_err());
+
+		let signature = websub::sign("secret", &body);
+		http_post(
+			&secrets,
+			&sender,
+			&rejected,
+			"blog".to_owned(),
+			Some(signature),
+			body,
+		);
+		assert!(matches!(
+			recv.try_next(),
+			Ok(Some(Event::Content(name, _))) if name == "blog"
+		));
+	}
+
+	#[tokio::test]
+	async fn disabled() {
+		let stand_in = StandIn::start().await;
+		let mut supervisor = Supervisor::new();
+		let storage = temp_storage("feeds_disabled").await;
+		let mut feeds = Feeds::new(
+			stand_in.client(),
+			storage,
+			"http://localhost",
+			&mut supervisor,
+		)
+		.await
+		.unwrap();
+		let guild = GuildState::new(1, 2, "Test").guild(&stand_in);
+		let config = json!({
+			"enabled": false,
+			"feeds": {
+				"blog": {
+					"url": format!("{}/feed.atom", stand_in.url()),
+					"channel_id": "4",
+					"text": "%TITLE%",
+				},
+			},
+		});
+		assert!(feeds.config(&guild, "feeds", config).is_none());
+
+		// Nothing is fetched or subscribed to
+		let requests = stand_in.wait_for(1, Duration::from_millis(500)).await;
+		assert!(requests.is_empty());
+	}
+
+	#[tokio::test]
+	async fn subscribes() {
+		let stand_in = StandIn::start().await;
+		let feed_url = format!("{}/feed.atom", stand_in.url());
+		let feed = format!(
+			r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Blog</title><link rel="hub" href="{}/hub"/></feed>"#,
+			stand_in.url()
+		);
+		stand_in.respond(Method::GET, "/feed.atom", 200, feed);
+
+		let mut supervisor = Supervisor::new();
+		let storage = temp_storage("feeds_subscribes").await;
+		let mut feeds = Feeds::new(
+			stand_in.client(),
+			storage,
+			"http://localhost",
+			&mut supervisor,
+		)
+		.await
+		.unwrap();
+		let guild = GuildState::new(1, 2, "Test").guild(&stand_in);
+		let config = json!({
+			"enabled": true,
+			"feeds": {
+				"blog": {"url": feed_url, "channel_id": "4", "text": "%TITLE%"},
+			},
+		});
+		assert!(feeds.config(&guild, "feeds", config).is_none());
+
+		stand_in.wait_for(2, Duration::from_secs(5)).await;
+		let requests = stand_in.take_requests();
+		let hub = requests
+			.iter()
+			.find(|r| r.is(Method::POST, "/hub"))
+			.unwrap()
+			.form();
+		assert_eq!(hub["hub.mode"], "subscribe");
+		assert_eq!(hub["hub.callback"], "http://localhost/feeds/blog");
+
+		// The verification is answered by the HTTP handler itself
+		let query = url::form_urlencoded::Serializer::new(String::new())
+			.extend_pairs([
+				("hub.mode", "subscribe"),
+				("hub.topic", feed_url.as_str()),
+				("hub.challenge", "c"),
+				("hub.lease_seconds", "86400"),
+			])
+			.finish();
+		let res = warp::test::request()
+			.path(&format!("/feeds/blog?{}", query))
+			.reply(&feeds.routes())
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+		assert_eq!(res.body().as_ref(), b"c");
+		assert!(feeds.leases.lock().unwrap()["blog"].expires.is_some());
+	}
+}
@@ -1,18 +1,22 @@
+use crate::command_registry::{CommandRegistry, CommandSpec};
+use crate::cooldown::{cooldown_message, CooldownConfig, Cooldowns};
+use crate::modules::Permission;
+use crate::supervisor::Tracker;
+use crate::usage::{Outcome, Usage};
 use chrono::Utc;
 use chronoutil::{shift_months, shift_years};
-use common::discord::interaction::*;
 use common::discord::types::{
 	AllowedMentions, ApplicationCommandOption, ApplicationCommandOptionType, ChannelId, Event,
 	UserId,
 };
 use common::display::MaybeDisplay;
+use common::interaction::*;
 use common::{EventHandler, Guild};
-use log::{debug, info, warn};
+use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashSet;
 use std::str::FromStr;
-use std::time::Instant;
 use std::{fmt, mem};
 
 type DateTime = chrono::DateTime<chrono::Utc>;
@@ -28,7 +32,9 @@ pub struct JoinedConfig {
 	#[serde(default)]
 	channels: HashSet<ChannelId>,
 	#[serde(default)]
-	cooldown: u32,
+	cooldown: CooldownConfig,
+	#[serde(default)]
+	permission: Permission,
 }
 
 impl JoinedConfig {
@@ -49,33 +55,36 @@ impl Default for JoinedConfig {
 			enabled: false,
 			whitelist: Some(true),
 			channels: HashSet::new(),
-			cooldown: 0,
+			cooldown: CooldownConfig::default(),
+			permission: Permission::default(),
 		}
 	}
 }
 
-#[derive(Debug)]
 pub struct Joined {
 	config: JoinedConfig,
-	last: Option<Instant>,
+	registry: CommandRegistry,
+	cooldowns: Cooldowns,
+	usage: Usage,
+	tracker: Tracker,
 }
 
 impl Joined {
-	pub fn new() -> Self {
+	pub fn new(registry: CommandRegistry, usage: Usage, tracker: Tracker) -> Self {
+		registry.expect("joined");
 		Self {
 			config: Default::default(),
-			last: None,
+			registry,
+			cooldowns: Cooldowns::new(),
+			usage,
+			tracker,
 		}
 	}
 
-	fn register_command(&self, guild: &Guild) {
-		if guild.command(COMMAND_NAME).is_some() {
-			return;
-		}
-		let client = guild.client();
-		let application_id = guild.application_id();
-		let guild_id = guild.id();
-		tokio::spawn(async move {
+	/// Declare the command, or none when the module is disabled
+	fn declare_command(&self) {
+		let mut specs = Vec::new();
+		if self.config.enabled {
 			let option = ApplicationCommandOption {
 				option_type: ApplicationCommandOptionType::User,
 				name: USER_OPTION_NAME.into(),
@@ -84,20 +93,13 @@ impl Joined {
 				choices: Vec::new(),
 				options: Vec::new(),
 			};
-			match client
-				.create_command(
-					application_id,
-					guild_id,
-					COMMAND_NAME,
-					"See how long ago a user joined the server",
-					vec![option],
-				)
-				.await
-			{
-				Ok(_) => debug!("Registered command"),
-				Err(e) => warn!("Unable to register command: {}", e),
-			}
-		});
+			specs.push(CommandSpec::new(
+				COMMAND_NAME,
+				"See how long ago a user joined the server",
+				vec![option],
+			));
+		}
+		self.registry.declare("joined", specs);
 	}
 
 	fn interaction(&mut self, guild: &Guild, interaction: &Interaction) -> bool {
@@ -141,24 +143,40 @@ impl Joined {
 				.respond(guild)
 				.content("Command not allowed in this channel")
 				.ephemeral()
-				.spawn();
+				.spawn(&self.tracker);
+			self.usage
+				.record(COMMAND_NAME, interaction, Outcome::Denied);
+			return false;
+		}
+
+		// Check if the member is allowed to use the command
+		if !self
+			.config
+			.permission
+			.check(guild, interaction, &self.tracker)
+		{
+			self.usage
+				.record(COMMAND_NAME, interaction, Outcome::Denied);
 			return false;
 		}
 
 		// Check cooldown
-		if let Some(last) = self.last {
-			let left = (self.config.cooldown as u64).saturating_sub(last.elapsed().as_secs());
-			if left > 1 {
-				interaction
-					.respond(guild)
-					.content(format!("Command on cooldown for {} more seconds", left))
-					.ephemeral()
-					.spawn();
-				return false;
-			}
+		if let Some(left) =
+			self.cooldowns
+				.check(guild, &self.config.cooldown, COMMAND_NAME, interaction)
+		{
+			interaction
+				.respond(guild)
+				.content(cooldown_message(left))
+				.ephemeral()
+				.spawn(&self.tracker);
+			self.usage
+				.record(COMMAND_NAME, interaction, Outcome::Cooldown);
+			return false;
 		}
 
-		self.last = Some(Instant::now());
+		self.usage
+			.record(COMMAND_NAME, interaction, Outcome::Allowed);
 		info!("Triggered{}", guild.channel(channel_id).display(" in #{}"));
 
 		match guild.member(user_id) {
@@ -172,14 +190,14 @@ impl Joined {
 					.respond(guild)
 					.content(content)
 					.allowed_mentions(AllowedMentions::none())
-					.spawn();
+					.spawn(&self.tracker);
 			}
 			None => {
 				interaction
 					.respond(guild)
 					.content("Unable to determine user join date")
 					.ephemeral()
-					.spawn();
+					.spawn(&self.tracker);
 			}
 		}
 
@@ -188,7 +206,7 @@ impl Joined {
 }
 
 impl EventHandler for Joined {
-	fn config(&mut self, guild: &Guild, name: &str, config: Value) -> Option<Value> {
+	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
 		let config = load_config!(name, "joined", config);
 		let old = mem::replace(&mut self.config, config);
 		if old.enabled != self.config.enabled {
@@ -200,7 +218,7 @@ impl EventHandler for Joined {
 		} else {
 			info!("Config updated");
 		}
-		self.register_command(guild);
+		self.declare_command();
 
 		None
 	}
@@ -338,9 +356,15 @@ impl fmt::Display for ReadableDuration {
 
 #[cfg(test)]
 mod tests {
-	use super::DateTime;
-	use crate::modules::joined::MakeReadableDuration;
-	use chrono::{NaiveDateTime, Utc};
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use crate::usage::temp_usage;
+	use chrono::NaiveDateTime;
+	use common::discord::types::event;
+	use common::testing::{feed, GuildState, StandIn};
+	use serde_json::json;
+	use std::time::Duration;
+	use warp::http::Method;
 
 	#[test]
 	fn readable_duration() {
@@ -358,4 +382,60 @@ mod tests {
 			assert_eq!(format!("{}", dt.readable().set_now(now)), d);
 		}
 	}
+
+	#[tokio::test]
+	async fn responds() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "commands")
+			.text_channel(4, "general")
+			.member(5, "someone", &[])
+			.member(6, "other", &[]);
+		let guild = state.guild(&stand_in);
+		let interaction =
+			|id, channel_id, options: &[(&str, ApplicationCommandOptionType, &str)]| {
+				let interaction = state.command_interaction(id, channel_id, 5, "joined", options);
+				Event::InteractionCreate(event::InteractionCreate {
+					interaction: serde_json::from_value(interaction).unwrap(),
+				})
+			};
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let usage = temp_usage("joined").await;
+		let mut joined = Joined::new(registry.clone(), usage, tracker);
+		let config = json!({ "enabled": true, "channels": ["3"] });
+		assert!(joined.config(&guild, "joined", config).is_none());
+		registry.sync(&guild);
+
+		let requests = stand_in.wait_for(1, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].is(Method::POST, "/applications/2/guilds/1/commands"));
+		let command = requests[0].json().unwrap();
+		assert_eq!(command["name"], "joined");
+		assert_eq!(command["options"].as_array().map(|o| o.len()), Some(1));
+		stand_in.take_requests();
+
+		let events = [
+			interaction(10, 3, &[]),
+			interaction(11, 3, &[("user", ApplicationCommandOptionType::User, "6")]),
+			interaction(12, 3, &[("user", ApplicationCommandOptionType::User, "7")]),
+			interaction(13, 4, &[]),
+		];
+		assert_eq!(feed(&mut joined, &guild, &events), [false; 4]);
+
+		let mut requests = stand_in.wait_for(4, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 4);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		let content = |i: usize| {
+			let response = requests[i].json().unwrap();
+			assert_eq!(response["type"], 4);
+			response["data"]["content"].as_str().unwrap().to_owned()
+		};
+		assert!(content(0).starts_with("<@5> joined **"));
+		assert!(content(1).starts_with("<@6> joined **"));
+		assert!(content(1).contains("(<t:1577836800:d> <t:1577836800:T>)"));
+		assert_eq!(content(2), "Unable to determine user join date");
+		assert_eq!(content(3), "Command not allowed in this channel");
+	}
 }
@@ -0,0 +1,690 @@
+use crate::command_registry::{CommandRegistry, CommandSpec};
+use crate::cooldown::{cooldown_message, CooldownConfig, Cooldowns};
+use crate::modules::Permission;
+use crate::supervisor::Tracker;
+use crate::usage::{Outcome, Usage};
+use anyhow::Result;
+use chrono::Utc;
+use common::discord::types::event;
+use common::discord::types::{
+	AllowedMentions, ApplicationCommandOption, ApplicationCommandOptionType, ChannelId, Event,
+	Message, RoleId, UserId,
+};
+use common::interaction::*;
+use common::{EventHandler, Guild, Migration, Storage};
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{query, query_as, query_scalar};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RANK_COMMAND_NAME: &'static str = "rank";
+const LEADERBOARD_COMMAND_NAME: &'static str = "leaderboard";
+const USER_OPTION_NAME: &'static str = "user";
+// Members listed on the leaderboard
+const LEADERBOARD_SIZE: i64 = 10;
+// Time before a reward role that couldn't be granted is tried again
+const GRANT_RETRY: Duration = Duration::from_secs(60 * 60);
+
+const MIGRATIONS: &[Migration] = &[Migration {
+	name: "create_levels_xp",
+	sqlite: r#"
+		CREATE TABLE IF NOT EXISTS levels_xp (
+			user_id INTEGER PRIMARY KEY NOT NULL,
+			xp INTEGER NOT NULL,
+			updated_timestamp INTEGER NOT NULL
+		);
+
+		CREATE INDEX IF NOT EXISTS levels_xp_xp ON levels_xp (xp);
+	"#,
+	postgres: r#"
+		CREATE TABLE IF NOT EXISTS levels_xp (
+			user_id BIGINT PRIMARY KEY NOT NULL,
+			xp BIGINT NOT NULL,
+			updated_timestamp BIGINT NOT NULL
+		);
+
+		CREATE INDEX IF NOT EXISTS levels_xp_xp ON levels_xp (xp);
+	"#,
+}];
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct LevelsConfig {
+	enabled: bool,
+	// XP per message is picked at random between these
+	#[serde(default)]
+	min_xp: Option<u32>,
+	#[serde(default)]
+	max_xp: Option<u32>,
+	// Seconds before messages of the same member earn XP again
+	#[serde(default)]
+	xp_cooldown: Option<u32>,
+	// Messages in these channels, and threads in them, don't earn XP
+	#[serde(default)]
+	excluded_channels: HashSet<ChannelId>,
+	// Members with any of these roles don't earn XP
+	#[serde(default)]
+	excluded_roles: HashSet<RoleId>,
+	#[serde(default)]
+	rewards: Vec<LevelReward>,
+	// Applies to the commands
+	#[serde(default)]
+	cooldown: CooldownConfig,
+	#[serde(default)]
+	permission: Permission,
+}
+
+impl LevelsConfig {
+	fn xp_range(&self) -> (u32, u32) {
+		let max = self.max_xp.unwrap_or(25);
+		(self.min_xp.unwrap_or(15).min(max), max)
+	}
+
+	#[inline]
+	fn xp_cooldown(&self) -> Duration {
+		Duration::from_secs(self.xp_cooldown.unwrap_or(60) as u64)
+	}
+
+	fn excluded(&self, guild: &Guild, channel_id: ChannelId) -> bool {
+		self.excluded_channels.contains(&channel_id)
+			|| guild
+				.channel(channel_id)
+				.and_then(|c| c.parent_id)
+				.map(|p| self.excluded_channels.contains(&p))
+				.unwrap_or(false)
+	}
+}
+
+/// Role granted to members once they reach `level`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LevelReward {
+	level: u32,
+	role: RoleId,
+}
+
+/// XP needed to go from `level` to the next one
+fn level_xp(level: u32) -> i64 {
+	let level = level as i64;
+	5 * level * level + 50 * level + 100
+}
+
+/// Level reached with `xp`, and the XP earned towards the next level
+fn level(mut xp: i64) -> (u32, i64) {
+	let mut level = 0;
+	while xp >= level_xp(level) {
+		xp -= level_xp(level);
+		level += 1;
+	}
+	(level, xp)
+}
+
+/// Add `amount` XP to `user_id`, returning their new total
+async fn add_xp(storage: &Storage, user_id: UserId, amount: i64) -> Result<i64> {
+	let now = Utc::now().timestamp();
+	query("INSERT INTO levels_xp (user_id, xp, updated_timestamp) VALUES ($1, $2, $3) ON CONFLICT (user_id) DO UPDATE SET xp = levels_xp.xp + $4, updated_timestamp = $5")
+		.bind(user_id)
+		.bind(amount)
+		.bind(now)
+		.bind(amount)
+		.bind(now)
+		.execute(&**storage)
+		.await?;
+	let xp = query_scalar::<_, i64>("SELECT xp FROM levels_xp WHERE user_id = $1")
+		.bind(user_id)
+		.fetch_one(&**storage)
+		.await?;
+	Ok(xp)
+}
+
+/// XP and position on the leaderboard of `user_id`, if they have any XP
+async fn rank(storage: &Storage, user_id: UserId) -> Result<Option<(i64, i64)>> {
+	let xp = query_scalar::<_, i64>("SELECT xp FROM levels_xp WHERE user_id = $1")
+		.bind(user_id)
+		.fetch_optional(&**storage)
+		.await?;
+	let xp = match xp {
+		Some(xp) => xp,
+		None => return Ok(None),
+	};
+	let ahead = query_scalar::<_, i64>("SELECT COUNT(*) FROM levels_xp WHERE xp > $1")
+		.bind(xp)
+		.fetch_one(&**storage)
+		.await?;
+	Ok(Some((xp, ahead + 1)))
+}
+
+/// Members with the most XP, most first
+async fn leaderboard(storage: &Storage) -> Result<Vec<(UserId, i64)>> {
+	let rows = query_as::<_, (UserId, i64)>(
+		"SELECT user_id, xp FROM levels_xp ORDER BY xp DESC, user_id LIMIT $1",
+	)
+	.bind(LEADERBOARD_SIZE)
+	.fetch_all(&**storage)
+	.await?;
+	Ok(rows)
+}
+
+/// Members earn XP by talking, which makes them level up. Reaching a level
+/// can grant a role
+pub struct Levels {
+	config: LevelsConfig,
+	registry: CommandRegistry,
+	cooldowns: Cooldowns,
+	usage: Usage,
+	storage: Storage,
+	tracker: Tracker,
+	// When members last earned XP
+	earned: HashMap<UserId, Instant>,
+	// When reward roles failed to be granted
+	failed_grants: Arc<Mutex<HashMap<RoleId, Instant>>>,
+}
+
+impl Levels {
+	pub async fn new(
+		registry: CommandRegistry,
+		usage: Usage,
+		storage: Storage,
+		tracker: Tracker,
+	) -> Result<Self> {
+		storage.migrate("levels", MIGRATIONS).await?;
+		registry.expect("levels");
+		Ok(Self {
+			config: Default::default(),
+			registry,
+			cooldowns: Cooldowns::new(),
+			usage,
+			storage,
+			tracker,
+			earned: HashMap::new(),
+			failed_grants: Default::default(),
+		})
+	}
+
+	/// Declare the commands, or none when the module is disabled
+	fn declare_commands(&self) {
+		let mut specs = Vec::new();
+		if self.config.enabled {
+			let option = ApplicationCommandOption {
+				option_type: ApplicationCommandOptionType::User,
+				name: USER_OPTION_NAME.into(),
+				description: "User".into(),
+				required: false,
+				choices: Vec::new(),
+				options: Vec::new(),
+			};
+			specs.push(CommandSpec::new(
+				RANK_COMMAND_NAME,
+				"See the level and rank of a user",
+				vec![option],
+			));
+			specs.push(CommandSpec::new(
+				LEADERBOARD_COMMAND_NAME,
+				"See who has the most XP",
+				Vec::new(),
+			));
+		}
+		self.registry.declare("levels", specs);
+	}
+
+	fn message(&mut self, guild: &Guild, message: &Message) -> bool {
+		if !self.config.enabled || self.config.excluded(guild, message.channel_id) {
+			return true;
+		}
+
+		let user_id = match &message.author {
+			Some(a) if !a.is_bot() && !a.is_system() => a.id,
+			_ => return true,
+		};
+		let member = match &message.member {
+			Some(m) => m,
+			None => return true,
+		};
+		if member
+			.roles
+			.iter()
+			.any(|r| self.config.excluded_roles.contains(r))
+		{
+			return true;
+		}
+
+		let cooldown = self.config.xp_cooldown();
+		self.earned.retain(|_, t| t.elapsed() < cooldown);
+		if self.earned.contains_key(&user_id) {
+			return true;
+		}
+		self.earned.insert(user_id, Instant::now());
+
+		let (min, max) = self.config.xp_range();
+		let amount = rand::thread_rng().gen_range(min..=max) as i64;
+		let client = guild.client();
+		let guild_id = guild.id();
+		let storage = self.storage.clone();
+		let roles: HashSet<RoleId> = member.roles.iter().copied().collect();
+		let rewards: Vec<LevelReward> = {
+			let mut failed_grants = self.failed_grants.lock().unwrap();
+			failed_grants.retain(|_, t| t.elapsed() < GRANT_RETRY);
+			self.config
+				.rewards
+				.iter()
+				.filter(|r| guild.role(r.role).is_some() && !failed_grants.contains_key(&r.role))
+				.cloned()
+				.collect()
+		};
+		let failed_grants = self.failed_grants.clone();
+		self.tracker.spawn(async move {
+			let xp = match add_xp(&storage, user_id, amount).await {
+				Ok(xp) => xp,
+				Err(e) => {
+					warn!("Unable to add XP to user {}: {}", user_id, e);
+					return;
+				}
+			};
+			let (before, _) = level(xp - amount);
+			let (reached, _) = level(xp);
+			if reached > before {
+				info!("User {} reached level {}", user_id, reached);
+			}
+
+			// Rewards are granted whenever they are missing, so rewards
+			// added later on reach members that passed the level before
+			for reward in rewards {
+				if reward.level > reached || roles.contains(&reward.role) {
+					continue;
+				}
+				match client
+					.add_guild_member_role(guild_id, user_id, reward.role)
+					.await
+				{
+					Ok(_) => info!("Granted role {} to user {}", reward.role, user_id),
+					Err(e) => {
+						warn!("Unable to grant role {}: {}", reward.role, e);
+						failed_grants
+							.lock()
+							.unwrap()
+							.insert(reward.role, Instant::now());
+					}
+				}
+			}
+		});
+
+		// Other modules still get to see the message
+		true
+	}
+
+	fn interaction(&mut self, guild: &Guild, interaction: &Interaction) -> bool {
+		if !self.config.enabled {
+			return true;
+		}
+
+		let command = match interaction.data.name.as_deref() {
+			Some(RANK_COMMAND_NAME) => RANK_COMMAND_NAME,
+			Some(LEADERBOARD_COMMAND_NAME) => LEADERBOARD_COMMAND_NAME,
+			_ => return true,
+		};
+
+		// From here on we consume the message: return `false`
+
+		if !self
+			.config
+			.permission
+			.check(guild, interaction, &self.tracker)
+		{
+			self.usage.record(command, interaction, Outcome::Denied);
+			return false;
+		}
+
+		if let Some(left) = self
+			.cooldowns
+			.check(guild, &self.config.cooldown, command, interaction)
+		{
+			interaction
+				.respond(guild)
+				.content(cooldown_message(left))
+				.ephemeral()
+				.spawn(&self.tracker);
+			self.usage.record(command, interaction, Outcome::Cooldown);
+			return false;
+		}
+		self.usage.record(command, interaction, Outcome::Allowed);
+
+		// User from the argument, or the user that sent the command
+		let user_id = interaction
+			.data
+			.options
+			.iter()
+			.find(|o| o.name == USER_OPTION_NAME)
+			.and_then(|o| o.value.as_deref())
+			.and_then(|v| UserId::from_str(v).ok())
+			.or_else(|| {
+				interaction
+					.member
+					.as_ref()
+					.and_then(|m| m.user.as_ref())
+					.map(|u| u.id)
+			});
+
+		let storage = self.storage.clone();
+		let response = interaction
+			.respond(guild)
+			.allowed_mentions(AllowedMentions::none());
+		self.tracker.spawn(async move {
+			let content = if command == RANK_COMMAND_NAME {
+				match user_id {
+					Some(id) => rank_content(&storage, id).await,
+					None => Ok("Unable to determine user".to_owned()),
+				}
+			} else {
+				leaderboard_content(&storage).await
+			};
+			let content = content.unwrap_or_else(|e| {
+				warn!("Unable to look up XP: {}", e);
+				"Unable to look up XP".to_owned()
+			});
+			if let Err(e) = response.content(content).send().await {
+				warn!("Unable to respond to interaction: {}", e);
+			}
+		});
+
+		false
+	}
+}
+
+async fn rank_content(storage: &Storage, user_id: UserId) -> Result<String> {
+	let (xp, position) = match rank(storage, user_id).await? {
+		Some(r) => r,
+		None => return Ok(format!("<@{}> has no XP yet", user_id)),
+	};
+	let (reached, progress) = level(xp);
+	Ok(format!(
+		"<@{}> is level {} with {} XP, rank #{}. {}/{} XP to level {}",
+		user_id,
+		reached,
+		xp,
+		position,
+		progress,
+		level_xp(reached),
+		reached + 1
+	))
+}
+
+async fn leaderboard_content(storage: &Storage) -> Result<String> {
+	let rows = leaderboard(storage).await?;
+	if rows.is_empty() {
+		return Ok("Nobody has earned XP yet".to_owned());
+	}
+	let mut content = "**Leaderboard**".to_owned();
+	for (i, (user_id, xp)) in rows.into_iter().enumerate() {
+		let _ = write!(
+			content,
+			"\n{}. <@{}>: level {}, {} XP",
+			i + 1,
+			user_id,
+			level(xp).0,
+			xp
+		);
+	}
+	Ok(content)
+}
+
+impl EventHandler for Levels {
+	fn config(&mut self, guild: &Guild, name: &str, config: Value) -> Option<Value> {
+		let config = load_config!(name, "levels", config);
+		let old = mem::replace(&mut self.config, config);
+		if old.enabled != self.config.enabled {
+			if self.config.enabled {
+				info!("Module enabled");
+			} else {
+				info!("Module disabled");
+			}
+		} else {
+			info!("Config updated");
+		}
+		for reward in &self.config.rewards {
+			if guild.role(reward.role).is_none() {
+				warn!("Reward role {} doesn't exist", reward.role);
+			}
+		}
+		self.failed_grants.lock().unwrap().clear();
+		self.declare_commands();
+
+		None
+	}
+
+	fn event(&mut self, guild: &Guild, event: &Event) -> bool {
+		match event {
+			Event::MessageCreate(event::MessageCreate { message }) => self.message(guild, message),
+			Event::InteractionCreate(ic) => self.interaction(guild, &ic.interaction),
+			_ => true,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use crate::usage::temp_usage;
+	use common::testing::{feed, temp_storage, GuildState, StandIn};
+	use serde_json::json;
+	use tokio::time::sleep;
+	use warp::http::Method;
+
+	#[test]
+	fn levels() {
+		assert_eq!(level(0), (0, 0));
+		assert_eq!(level(99), (0, 99));
+		assert_eq!(level(100), (1, 0));
+		assert_eq!(level(255), (2, 0));
+		assert_eq!(level(1000), (4, 230));
+	}
+
+	#[tokio::test]
+	async fn disabled() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Regular", 0)
+			.text_channel(4, "general")
+			.member(5, "someone", &[]);
+		let guild = state.guild(&stand_in);
+		let message = state.message(10, 4, 5, "hello");
+		let message = Event::MessageCreate(event::MessageCreate {
+			message: serde_json::from_value(message).unwrap(),
+		});
+		let interaction = state.command_interaction(11, 4, 5, "rank", &[]);
+		let interaction = Event::InteractionCreate(event::InteractionCreate {
+			interaction: serde_json::from_value(interaction).unwrap(),
+		});
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let storage = temp_storage("levels_disabled").await;
+		let mut levels = Levels::new(
+			registry.clone(),
+			temp_usage("levels_disabled_usage").await,
+			storage.clone(),
+			tracker,
+		)
+		.await
+		.unwrap();
+		let config = json!({
+			"enabled": false,
+			"min_xp": 120,
+			"max_xp": 120,
+			"rewards": [{ "level": 1, "role": "3" }],
+		});
+		assert!(levels.config(&guild, "levels", config).is_none());
+		registry.sync(&guild);
+
+		// No XP, roles, responses or commands
+		let events = [message, interaction];
+		assert_eq!(feed(&mut levels, &guild, &events), [true, true]);
+		let requests = stand_in.wait_for(1, Duration::from_millis(200)).await;
+		assert!(requests.is_empty());
+		let (count,) = query_as::<_, (i64,)>("SELECT COUNT(*) FROM levels_xp")
+			.fetch_one(&*storage)
+			.await
+			.unwrap();
+		assert_eq!(count, 0);
+	}
+
+	#[tokio::test]
+	async fn awards_and_ranks() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Muted", 0)
+			.role(4, "Regular", 0)
+			.text_channel(5, "general")
+			.text_channel(6, "bots")
+			.thread(7, 6, "games")
+			.member(8, "someone", &[])
+			.member(9, "other", &[4])
+			.member(10, "muted", &[3]);
+		let guild = state.guild(&stand_in);
+		let message = |id, channel_id, user_id| {
+			let message = state.message(id, channel_id, user_id, "hello");
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+		let interaction = |id, name, options: &[(&str, ApplicationCommandOptionType, &str)]| {
+			let interaction = state.command_interaction(id, 5, 8, name, options);
+			Event::InteractionCreate(event::InteractionCreate {
+				interaction: serde_json::from_value(interaction).unwrap(),
+			})
+		};
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let usage = temp_usage("levels_usage").await;
+		let storage = temp_storage("levels").await;
+		let mut levels = Levels::new(registry.clone(), usage, storage, tracker)
+			.await
+			.unwrap();
+		let config = json!({
+			"enabled": true,
+			"min_xp": 120,
+			"max_xp": 120,
+			"excluded_channels": ["6"],
+			"excluded_roles": ["3"],
+			"rewards": [{ "level": 1, "role": "4" }, { "level": 2, "role": "11" }],
+		});
+		assert!(levels.config(&guild, "levels", config).is_none());
+		registry.sync(&guild);
+
+		let requests = stand_in.wait_for(2, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 2);
+		assert_eq!(requests[0].json().unwrap()["name"], "rank");
+		assert_eq!(requests[1].json().unwrap()["name"], "leaderboard");
+		stand_in.take_requests();
+
+		let events = [
+			message(20, 5, 8),
+			message(21, 5, 8),
+			message(22, 5, 9),
+			message(23, 6, 8),
+			message(24, 7, 8),
+			message(25, 5, 10),
+		];
+		assert_eq!(feed(&mut levels, &guild, &events), [true; 6]);
+
+		// Only the first message earns XP, the other member already has the role
+		let requests = stand_in.wait_for(1, Duration::from_secs(5)).await;
+		sleep(Duration::from_millis(200)).await;
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].is(Method::PUT, "/guilds/1/members/8/roles/4"));
+		assert_eq!(stand_in.take_requests().len(), 1);
+
+		let events = [
+			interaction(30, "rank", &[]),
+			interaction(
+				31,
+				"rank",
+				&[("user", ApplicationCommandOptionType::User, "10")],
+			),
+			interaction(32, "leaderboard", &[]),
+		];
+		assert_eq!(feed(&mut levels, &guild, &events), [false; 3]);
+
+		let mut requests = stand_in.wait_for(3, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 3);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		let content = |i: usize| {
+			let response = requests[i].json().unwrap();
+			response["data"]["content"].as_str().unwrap().to_owned()
+		};
+		assert_eq!(
+			content(0),
+			"<@8> is level 1 with 120 XP, rank #1. 20/155 XP to level 2"
+		);
+		assert_eq!(content(1), "<@10> has no XP yet");
+		assert_eq!(
+			content(2),
+			"**Leaderboard**\n1. <@8>: level 1, 120 XP\n2. <@9>: level 1, 120 XP"
+		);
+	}
+
+	#[tokio::test]
+	async fn rewards() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Regular", 0)
+			.role(4, "Staff", 0)
+			.text_channel(5, "general")
+			.member(8, "someone", &[])
+			.member(9, "other", &[]);
+		let guild = state.guild(&stand_in);
+		let message = |id, user_id| {
+			let message = state.message(id, 5, user_id, "hello");
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+
+		let tracker = Supervisor::new().tracker();
+		let storage = temp_storage("levels_rewards").await;
+		let mut levels = Levels::new(
+			CommandRegistry::new(tracker.clone()),
+			temp_usage("levels_rewards_usage").await,
+			storage,
+			tracker,
+		)
+		.await
+		.unwrap();
+		let config = json!({
+			"enabled": true,
+			"min_xp": 120,
+			"max_xp": 120,
+			"rewards": [
+				{ "level": 1, "role": "3" },
+				{ "level": 1, "role": "4" },
+				{ "level": 1, "role": "11" },
+			],
+		});
+		assert!(levels.config(&guild, "levels", config).is_none());
+		stand_in.respond(Method::PUT, "/guilds/1/members/8/roles/4", 403, "Forbidden");
+
+		// The role that doesn't exist is never requested
+		assert_eq!(feed(&mut levels, &guild, &[message(20, 8)]), [true]);
+		let mut requests = stand_in.wait_for(2, Duration::from_secs(5)).await;
+		sleep(Duration::from_millis(200)).await;
+		assert_eq!(requests.len(), 2);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		assert!(requests[0].is(Method::PUT, "/guilds/1/members/8/roles/3"));
+		assert!(requests[1].is(Method::PUT, "/guilds/1/members/8/roles/4"));
+		assert_eq!(stand_in.take_requests().len(), 2);
+
+		// The role that failed is left alone for a while
+		assert_eq!(feed(&mut levels, &guild, &[message(21, 9)]), [true]);
+		let requests = stand_in.wait_for(1, Duration::from_secs(5)).await;
+		sleep(Duration::from_millis(200)).await;
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].is(Method::PUT, "/guilds/1/members/9/roles/3"));
+		assert_eq!(stand_in.take_requests().len(), 1);
+	}
+}
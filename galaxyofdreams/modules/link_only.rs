@@ -1,21 +1,85 @@
+use crate::supervisor::Tracker;
+use anyhow::Result;
+use chrono::Utc;
 use common::discord::types::event;
-use common::discord::types::{ChannelId, Event, Message, RoleId};
+use common::discord::types::{AllowedMentions, ChannelId, Event, GuildId, Message, RoleId, UserId};
 use common::display::MaybeDisplay;
-use common::{EventHandler, Guild};
+use common::{Client, EventHandler, Guild, Migration, Storage};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashSet;
+use sqlx::{query, query_scalar};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 use std::mem;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use url::Url;
+
+// Longest part of the deleted message that is sent back to its author
+const MAX_QUOTE_LENGTH: usize = 1500;
+
+const MIGRATIONS: &[Migration] = &[Migration {
+	name: "create_link_only_strikes",
+	sqlite: r#"
+		CREATE TABLE IF NOT EXISTS link_only_strikes (
+			user_id INTEGER PRIMARY KEY NOT NULL,
+			strikes INTEGER NOT NULL,
+			updated_timestamp INTEGER NOT NULL
+		);
+	"#,
+	postgres: r#"
+		CREATE TABLE IF NOT EXISTS link_only_strikes (
+			user_id BIGINT PRIMARY KEY NOT NULL,
+			strikes INTEGER NOT NULL,
+			updated_timestamp BIGINT NOT NULL
+		);
+	"#,
+}];
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LinkOnlyConfig {
 	enabled: bool,
+	// Channels that use the default rule
+	#[serde(default)]
 	channels: HashSet<ChannelId>,
 	#[serde(default)]
+	rule: LinkRule,
+	// Channels with their own rule
+	#[serde(default)]
+	rules: HashMap<ChannelId, LinkRule>,
+	#[serde(default)]
 	log_channel: Option<ChannelId>,
 	#[serde(default)]
 	bypass_minimum_role: Option<RoleId>,
+	// Tell authors why their message was deleted
+	#[serde(default)]
+	notify: Option<NotifyConfig>,
+	// Time out authors that keep breaking the rules
+	#[serde(default)]
+	strikes: Option<StrikesConfig>,
+}
+
+impl LinkOnlyConfig {
+	fn channel_rule(&self, channel_id: ChannelId) -> Option<&LinkRule> {
+		self.rules.get(&channel_id).or_else(|| {
+			if self.channels.contains(&channel_id) {
+				Some(&self.rule)
+			} else {
+				None
+			}
+		})
+	}
+
+	/// Rule of the channel. Threads and forum posts without a rule of their own
+	/// follow the rule of their parent channel, if it covers threads
+	fn rule(&self, guild: &Guild, channel_id: ChannelId) -> Option<&LinkRule> {
+		if let Some(rule) = self.channel_rule(channel_id) {
+			return Some(rule);
+		}
+		let parent_id = guild.channel(channel_id)?.parent_id?;
+		self.channel_rule(parent_id).filter(|r| r.threads)
+	}
 }
 
 impl Default for LinkOnlyConfig {
@@ -23,41 +87,239 @@ impl Default for LinkOnlyConfig {
 		Self {
 			enabled: false,
 			channels: HashSet::new(),
+			rule: LinkRule::default(),
+			rules: HashMap::new(),
 			log_channel: None,
 			bypass_minimum_role: None,
+			notify: None,
+			strikes: None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NotifyConfig {
+	// Seconds before the same user is notified again
+	#[serde(default)]
+	cooldown: Option<u32>,
+	// Seconds the reply in the channel stays up when the author can't be messaged directly
+	#[serde(default)]
+	reply_seconds: Option<u32>,
+}
+
+impl NotifyConfig {
+	#[inline]
+	fn cooldown(&self) -> Duration {
+		Duration::from_secs(self.cooldown.unwrap_or(60) as u64)
+	}
+
+	#[inline]
+	fn reply_duration(&self) -> Duration {
+		Duration::from_secs(self.reply_seconds.unwrap_or(10) as u64)
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StrikesConfig {
+	// Strikes that lead to a timeout
+	limit: u32,
+	timeout_minutes: u32,
+	// Strikes are forgotten when the last one is older than this
+	#[serde(default)]
+	expire_hours: Option<u32>,
+}
+
+/// What happened to the author after a deletion
+enum Strike {
+	Counted(u32, u32),
+	TimedOut(u32),
+}
+
+impl Strike {
+	fn text(&self) -> String {
+		match self {
+			Strike::Counted(n, limit) => format!(
+				"This is strike {} of {}, at {} you will be timed out.",
+				n, limit, limit
+			),
+			Strike::TimedOut(minutes) => {
+				format!("You have been timed out for {} minutes.", minutes)
+			}
 		}
 	}
 }
 
-#[derive(Debug)]
+/// What a message in a link only channel has to contain. Domains also match their subdomains
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LinkRule {
+	// Uploaded files count as a link
+	#[serde(default)]
+	attachments: bool,
+	// Embeds count as a link
+	#[serde(default)]
+	embeds: bool,
+	// If given, every link has to go to one of these domains
+	#[serde(default)]
+	allowed_domains: Vec<String>,
+	// Links to these domains are never allowed
+	#[serde(default)]
+	blocked_domains: Vec<String>,
+	// If given, at least one link has to go to one of these domains
+	#[serde(default)]
+	required_domains: Vec<String>,
+	// Threads and forum posts in the channel follow the rule as well
+	#[serde(default)]
+	threads: bool,
+}
+
+impl LinkRule {
+	/// The rule, as explained to users
+	fn describe(&self, channel_id: ChannelId) -> String {
+		let mut text = format!("Messages in <#{}> have to contain a link", channel_id);
+		if !self.required_domains.is_empty() {
+			let _ = write!(text, " to {}", self.required_domains.join(", "));
+		}
+		if self.attachments {
+			text.push_str(" or an upload");
+		}
+		if self.embeds {
+			text.push_str(" or an embed");
+		}
+		text.push('.');
+		if !self.allowed_domains.is_empty() {
+			let _ = write!(
+				text,
+				" Links can only go to {}.",
+				self.allowed_domains.join(", ")
+			);
+		}
+		if !self.blocked_domains.is_empty() {
+			let _ = write!(
+				text,
+				" Links to {} are not allowed.",
+				self.blocked_domains.join(", ")
+			);
+		}
+		text
+	}
+
+	/// Why `message` breaks the rule, if it does
+	fn violation(&self, message: &Message) -> Option<&'static str> {
+		let hosts = link_hosts(&message.content);
+		if hosts
+			.iter()
+			.any(|h| matches_domain(h, &self.blocked_domains))
+		{
+			return Some("it linked to a blocked domain");
+		}
+		if !self.allowed_domains.is_empty()
+			&& hosts
+				.iter()
+				.any(|h| !matches_domain(h, &self.allowed_domains))
+		{
+			return Some("it linked to a domain that is not allowed");
+		}
+
+		let linked = if self.required_domains.is_empty() {
+			!hosts.is_empty()
+		} else {
+			hosts
+				.iter()
+				.any(|h| matches_domain(h, &self.required_domains))
+		};
+		if linked
+			|| (self.attachments && !message.attachments.is_empty())
+			|| (self.embeds && !message.embeds.is_empty())
+		{
+			return None;
+		}
+
+		if self.required_domains.is_empty() {
+			Some("it did not contain a link")
+		} else {
+			Some("it did not contain a link to a required domain")
+		}
+	}
+}
+
+/// Hosts of the http(s) links in `content`, lowercase
+fn link_hosts(content: &str) -> Vec<String> {
+	content
+		.split(|c: char| c.is_whitespace() || "<>()[]\"'`|".contains(c))
+		.filter(|w| {
+			let w = w.to_ascii_lowercase();
+			w.starts_with("https://") || w.starts_with("http://")
+		})
+		.filter_map(|w| Url::parse(w.trim_end_matches(&['.', ',', '!', '?', ':', ';'][..])).ok())
+		.filter_map(|u| {
+			u.host_str()
+				.map(|h| h.trim_end_matches('.').to_ascii_lowercase())
+		})
+		.collect()
+}
+
+fn matches_domain(host: &str, domains: &[String]) -> bool {
+	domains.iter().any(|d| {
+		let d = d.trim().trim_start_matches('.').to_ascii_lowercase();
+		host == d
+			|| host
+				.strip_suffix(d.as_str())
+				.map(|s| s.ends_with('.'))
+				.unwrap_or(false)
+	})
+}
+
 pub struct LinkOnly {
 	config: LinkOnlyConfig,
+	storage: Storage,
+	tracker: Tracker,
+	// When users were last told about a deletion
+	notified: HashMap<UserId, Instant>,
 }
 
 impl LinkOnly {
-	pub fn new() -> Self {
-		Self {
+	pub async fn new(storage: Storage, tracker: Tracker) -> Result<Self> {
+		storage.migrate("link_only", MIGRATIONS).await?;
+		Ok(Self {
 			config: Default::default(),
+			storage,
+			tracker,
+			notified: HashMap::new(),
+		})
+	}
+
+	/// Whether `user_id` can be notified, in which case the notification is counted
+	fn notify(&mut self, user_id: UserId) -> Option<NotifyConfig> {
+		let notify = self.config.notify.clone()?;
+		let cooldown = notify.cooldown();
+		self.notified.retain(|_, t| t.elapsed() < cooldown);
+		if self.notified.contains_key(&user_id) {
+			return None;
 		}
+		self.notified.insert(user_id, Instant::now());
+		Some(notify)
 	}
 
-	fn message(&mut self, guild: &Guild, message: &Message) -> bool {
+	fn message(&mut self, guild: &Guild, message: &Message, edited: bool) -> bool {
 		// Check if link only mode is enabled for this channel
-		if !self.config.channels.contains(&message.channel_id) {
-			return true;
-		}
+		let rule = match self.config.rule(guild, message.channel_id) {
+			Some(r) => r,
+			None => return true,
+		};
 
-		// Check if the message contains a link
-		if message.content.contains("https://") || message.content.contains("http://") {
-			return true;
-		}
+		// Check if the message follows the rule of the channel
+		let reason = match rule.violation(message) {
+			Some(r) => r,
+			None => return true,
+		};
 
-		// Bot and system messages are ignored
+		// Bot and system messages are ignored, including our own notices.
+		// The member in a message event doesn't include the user
+		let user_id = guild.user_id();
 		if message
-			.member
+			.author
 			.as_ref()
-			.and_then(|m| m.user.as_ref())
-			.map(|u| u.is_bot() || u.is_system())
+			.map(|u| u.is_bot() || u.is_system() || u.id == user_id)
 			.unwrap_or(false)
 		{
 			return true;
@@ -81,24 +343,40 @@ impl LinkOnly {
 			}
 		}
 
-		// Delete the message and optionally log it
+		// Delete the message, optionally log it and deal with the author
+		let description = rule.describe(message.channel_id);
 		let client = guild.client();
+		let storage = self.storage.clone();
+		let guild_id = guild.id();
 		let ids = (message.channel_id, message.id);
+		let user_id = message.author.as_ref().map(|a| a.id);
+		let strikes = self.config.strikes.clone();
+		let notify = user_id.and_then(|id| self.notify(id)).map(|notify| {
+			let explanation = format!(
+				"Your message in <#{}> was deleted because {}. {}",
+				message.channel_id, reason, description
+			);
+			let quote: String = message.content.chars().take(MAX_QUOTE_LENGTH).collect();
+			(notify, explanation, quote)
+		});
+		let log_channel = self.config.log_channel;
 		let log_message = self.config.log_channel.map(|id| {
 			let msg = format!(
-				"Deleted message{} in <#{}> because it did not contain a link:\n```{}```",
+				"Deleted {}message{} in <#{}> because {}:\n```{}```",
+				if edited { "edited " } else { "" },
 				message
 					.author
 					.as_ref()
 					.map(|a| &a.id)
 					.display(" from <@{}>"),
 				message.channel_id,
+				reason,
 				message
 			);
 			(id, msg)
 		});
 
-		tokio::spawn(async move {
+		self.tracker.spawn(async move {
 			match client.delete_message(ids).await {
 				Ok(_) => info!("Message deleted"),
 				Err(e) => {
@@ -112,19 +390,143 @@ impl LinkOnly {
 					warn!("Unable to log deletion: {}", e);
 				}
 			}
+
+			let user_id = match user_id {
+				Some(id) => id,
+				None => return,
+			};
+
+			let strike = match &strikes {
+				Some(strikes) => {
+					match add_strike(&client, &storage, guild_id, user_id, strikes).await {
+						Ok(s) => Some(s),
+						Err(e) => {
+							warn!("Unable to add strike: {}", e);
+							None
+						}
+					}
+				}
+				None => None,
+			};
+			if let (Some(Strike::TimedOut(minutes)), Some(id)) = (&strike, log_channel) {
+				let msg = format!("Timed out <@{}> for {} minutes", user_id, minutes);
+				if let Err(e) = client.create_message(id).content(msg).send().await {
+					warn!("Unable to log timeout: {}", e);
+				}
+			}
+
+			if let Some((notify, mut explanation, quote)) = notify {
+				if let Some(strike) = &strike {
+					let _ = write!(explanation, " {}", strike.text());
+				}
+				notify_author(&client, user_id, ids.0, &notify, explanation, quote).await;
+			}
 		});
 
 		false
 	}
 }
 
+/// Count a strike against `user_id` and time them out once they reach the limit
+async fn add_strike(
+	client: &Client,
+	storage: &Storage,
+	guild_id: GuildId,
+	user_id: UserId,
+	config: &StrikesConfig,
+) -> Result<Strike> {
+	let now = Utc::now().timestamp();
+	let expired = config
+		.expire_hours
+		.map(|h| now - h as i64 * 3600)
+		.unwrap_or(i64::MIN);
+	query("INSERT INTO link_only_strikes (user_id, strikes, updated_timestamp) VALUES ($1, 1, $2) ON CONFLICT (user_id) DO UPDATE SET strikes = CASE WHEN link_only_strikes.updated_timestamp < $3 THEN 1 ELSE link_only_strikes.strikes + 1 END, updated_timestamp = $4")
+		.bind(user_id)
+		.bind(now)
+		.bind(expired)
+		.bind(now)
+		.execute(&**storage)
+		.await?;
+	let strikes = query_scalar::<_, i32>("SELECT strikes FROM link_only_strikes WHERE user_id = $1")
+		.bind(user_id)
+		.fetch_one(&**storage)
+		.await? as u32;
+
+	let limit = config.limit.max(1);
+	if strikes < limit {
+		return Ok(Strike::Counted(strikes, limit));
+	}
+
+	// Discord doesn't allow timeouts longer than 28 days
+	let minutes = config.timeout_minutes.min(28 * 24 * 60);
+	let until = Utc::now() + chrono::Duration::minutes(minutes as i64);
+	client
+		.timeout_guild_member(guild_id, user_id, until)
+		.await?;
+	query("UPDATE link_only_strikes SET strikes = 0 WHERE user_id = $1")
+		.bind(user_id)
+		.execute(&**storage)
+		.await?;
+	info!("Timed out user {} for {} minutes", user_id, minutes);
+	Ok(Strike::TimedOut(minutes))
+}
+
+/// Explain the deletion to the author in a direct message. If that is not possible,
+/// reply in the channel instead and remove the reply after a while
+async fn notify_author(
+	client: &Client,
+	user_id: UserId,
+	channel_id: ChannelId,
+	config: &NotifyConfig,
+	explanation: String,
+	quote: String,
+) {
+	let dm = async {
+		let dm_id = client.create_dm(user_id).await?;
+		let mut content = explanation.clone();
+		if !quote.is_empty() {
+			let _ = write!(content, "\nYour message:\n```{}```", quote);
+		}
+		client.create_message(dm_id).content(content).send().await?;
+		Result::<_>::Ok(())
+	};
+	match dm.await {
+		Ok(_) => return,
+		Err(e) => info!("Unable to message user {} directly: {}", user_id, e),
+	}
+
+	let res = client
+		.create_message(channel_id)
+		.content(format!("<@{}> {}", user_id, explanation))
+		.allowed_mentions(AllowedMentions::none().users(vec![user_id]))
+		.send()
+		.await;
+	let message_id = match res {
+		Ok(id) => id,
+		Err(e) => {
+			warn!("Unable to notify user {}: {}", user_id, e);
+			return;
+		}
+	};
+	sleep(config.reply_duration()).await;
+	if let Err(e) = client.delete_message((channel_id, message_id)).await {
+		warn!("Unable to remove notification: {}", e);
+	}
+}
+
 impl EventHandler for LinkOnly {
 	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
 		let config = load_config!(name, "link_only", config);
 		let old = mem::replace(&mut self.config, config);
 		if old.enabled != self.config.enabled {
 			if self.config.enabled {
-				info!("Module enabled in {} channels", self.config.channels.len());
+				let channels = self
+					.config
+					.channels
+					.iter()
+					.chain(self.config.rules.keys())
+					.collect::<HashSet<_>>();
+				info!("Module enabled in {} channels", channels.len());
 			} else {
 				info!("Module disabled");
 			}
@@ -136,10 +538,306 @@ impl EventHandler for LinkOnly {
 	}
 
 	fn event(&mut self, guild: &Guild, event: &Event) -> bool {
-		if let Event::MessageCreate(event::MessageCreate { message }) = event {
-			self.message(guild, message)
-		} else {
-			true
+		match event {
+			Event::MessageCreate(event::MessageCreate { message }) => {
+				self.message(guild, message, false)
+			}
+			// Updates without an edit timestamp weren't made by the author,
+			// for example embeds being added to the message
+			Event::MessageUpdate(event::MessageUpdate { message })
+				if message.edited_timestamp.is_some() =>
+			{
+				self.message(guild, message, true)
+			}
+			_ => true,
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use chrono::DateTime;
+	use common::testing::{feed, temp_storage, GuildState, StandIn};
+	use serde_json::json;
+	use std::time::Duration;
+	use warp::http::Method;
+
+	async fn link_only(name: &str) -> LinkOnly {
+		let storage = temp_storage(name).await;
+		LinkOnly::new(storage, Supervisor::new().tracker())
+			.await
+			.unwrap()
+	}
+
+	#[tokio::test]
+	async fn removes_messages() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Moderator", 1)
+			.text_channel(4, "links")
+			.text_channel(5, "general")
+			.text_channel(8, "log")
+			.member(6, "someone", &[])
+			.member(7, "moderator", &[3]);
+		let guild = state.guild(&stand_in);
+		let message = |id, channel_id, user_id, content| {
+			let message = state.message(id, channel_id, user_id, content);
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+
+		let mut link_only = link_only("link_only_removes_messages").await;
+		let config = json!({
+			"enabled": true,
+			"channels": ["4"],
+			"log_channel": "8",
+			"bypass_minimum_role": "3",
+		});
+		assert!(link_only.config(&guild, "link_only", config).is_none());
+
+		let events = [
+			message(10, 4, 6, "https://example.com"),
+			message(11, 5, 6, "hello"),
+			message(12, 4, 7, "hello"),
+			message(13, 4, 6, "hello"),
+		];
+		assert_eq!(
+			feed(&mut link_only, &guild, &events),
+			[true, true, true, false]
+		);
+
+		let requests = stand_in.wait_for(2, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 2);
+		assert!(requests[0].is(Method::DELETE, "/channels/4/messages/13"));
+		assert!(requests[1].is(Method::POST, "/channels/8/messages"));
+		let log = requests[1].json().unwrap();
+		let log = log["content"].as_str().unwrap();
+		assert!(log.starts_with("Deleted message from <@6> in <#4>"));
+	}
+
+	#[tokio::test]
+	async fn rules() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "links")
+			.text_channel(4, "music")
+			.member(5, "someone", &[]);
+		let guild = state.guild(&stand_in);
+		let message = |id, channel_id, content, attachment: bool| {
+			let mut message = state.message(id, channel_id, 5, content);
+			if attachment {
+				message["attachments"] = json!([{
+					"id": "100",
+					"filename": "cat.png",
+					"size": 1024,
+					"url": "https://cdn.example/cat.png",
+					"proxy_url": "https://media.example/cat.png",
+				}]);
+			}
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+
+		let mut link_only = link_only("link_only_rules").await;
+		let config = json!({
+			"enabled": true,
+			"channels": ["3"],
+			"rule": {
+				"attachments": true,
+				"blocked_domains": ["scam.example"],
+			},
+			"rules": {
+				"4": {
+					"allowed_domains": ["youtube.com", "youtu.be", "bandcamp.com"],
+					"required_domains": ["youtube.com", "youtu.be", "bandcamp.com"],
+				},
+			},
+		});
+		assert!(link_only.config(&guild, "link_only", config).is_none());
+
+		let events = [
+			message(10, 3, "look", true),
+			message(11, 3, "see <https://example.com/page>.", false),
+			message(12, 3, "free stuff https://login.SCAM.example/x", false),
+			message(13, 3, "free stuff https://notscam.example/x", false),
+			message(14, 4, "https://artist.bandcamp.com/album/a", false),
+			message(15, 4, "[song](https://www.youtube.com/watch?v=1)", false),
+			message(16, 4, "https://youtu.be/1 https://example.com", false),
+			message(17, 4, "https://example.com", false),
+			message(18, 4, "look", true),
+		];
+		assert_eq!(
+			feed(&mut link_only, &guild, &events),
+			[true, true, false, true, true, true, false, false, false]
+		);
+
+		let mut requests = stand_in.wait_for(4, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 4);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		assert!(requests[0].is(Method::DELETE, "/channels/3/messages/12"));
+		assert!(requests[1].is(Method::DELETE, "/channels/4/messages/16"));
+		assert!(requests[2].is(Method::DELETE, "/channels/4/messages/17"));
+		assert!(requests[3].is(Method::DELETE, "/channels/4/messages/18"));
+	}
+
+	#[tokio::test]
+	async fn edits_and_threads() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "links")
+			.text_channel(4, "more-links")
+			.thread(5, 3, "discussion")
+			.thread(6, 4, "discussion")
+			.member(7, "someone", &[]);
+		let guild = state.guild(&stand_in);
+		let create = |id, channel_id, content| {
+			let message = state.message(id, channel_id, 7, content);
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+		let update = |id, channel_id, content, edited: bool| {
+			let mut message = state.message(id, channel_id, 7, content);
+			if edited {
+				message["edited_timestamp"] = json!("2020-01-01T00:01:00+00:00");
+			}
+			Event::MessageUpdate(event::MessageUpdate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+
+		let mut link_only = link_only("link_only_edits_and_threads").await;
+		let config = json!({
+			"enabled": true,
+			"channels": ["4"],
+			"rules": { "3": { "threads": true } },
+		});
+		assert!(link_only.config(&guild, "link_only", config).is_none());
+
+		let events = [
+			update(10, 3, "https://example.com", true),
+			update(11, 3, "hello", false),
+			update(12, 3, "hello", true),
+			create(13, 5, "hello"),
+			create(14, 6, "hello"),
+		];
+		assert_eq!(
+			feed(&mut link_only, &guild, &events),
+			[true, true, false, false, true]
+		);
+
+		let mut requests = stand_in.wait_for(2, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 2);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		assert!(requests[0].is(Method::DELETE, "/channels/3/messages/12"));
+		assert!(requests[1].is(Method::DELETE, "/channels/5/messages/13"));
+	}
+
+	#[tokio::test]
+	async fn notifies_and_strikes() {
+		let stand_in = StandIn::start().await;
+		// The bot user differs from the application for older bots
+		let state = GuildState::new(1, 2, "Test")
+			.bot_user(3)
+			.text_channel(4, "links")
+			.member(3, "bot", &[])
+			.member(6, "someone", &[])
+			.member(7, "other", &[]);
+		let guild = state.guild(&stand_in);
+		let message = |id, user_id| {
+			let message = state.message(id, 4, user_id, "hello");
+			Event::MessageCreate(event::MessageCreate {
+				message: serde_json::from_value(message).unwrap(),
+			})
+		};
+		stand_in.respond_json(
+			Method::POST,
+			"/users/@me/channels",
+			200,
+			json!({ "id": "50" }),
+		);
+		stand_in.respond_json(
+			Method::POST,
+			"/channels/50/messages",
+			200,
+			json!({ "id": "60" }),
+		);
+
+		let mut link_only = link_only("link_only_notifies").await;
+		let config = json!({
+			"enabled": true,
+			"channels": ["4"],
+			"notify": { "reply_seconds": 0 },
+			"strikes": { "limit": 2, "timeout_minutes": 10 },
+		});
+		assert!(link_only.config(&guild, "link_only", config).is_none());
+
+		// The author is told why in a direct message
+		assert_eq!(feed(&mut link_only, &guild, &[message(10, 6)]), [false]);
+		let requests = stand_in.wait_for(3, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 3);
+		assert!(requests[0].is(Method::DELETE, "/channels/4/messages/10"));
+		assert!(requests[1].is(Method::POST, "/users/@me/channels"));
+		assert!(requests[2].is(Method::POST, "/channels/50/messages"));
+		let dm = requests[2].json().unwrap();
+		assert_eq!(
+			dm["content"],
+			"Your message in <#4> was deleted because it did not contain a link. Messages in <#4> have to contain a link. This is strike 1 of 2, at 2 you will be timed out.\nYour message:\n```hello```"
+		);
+		stand_in.take_requests();
+
+		// The second strike leads to a timeout, but no new message within the cooldown
+		assert_eq!(feed(&mut link_only, &guild, &[message(11, 6)]), [false]);
+		let requests = stand_in.wait_for(2, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 2);
+		assert!(requests[0].is(Method::DELETE, "/channels/4/messages/11"));
+		assert!(requests[1].is(Method::PATCH, "/guilds/1/members/6"));
+		let until = requests[1].json().unwrap();
+		let until = until["communication_disabled_until"].as_str().unwrap();
+		let until = DateTime::parse_from_rfc3339(until).unwrap();
+		assert!(until > Utc::now() + chrono::Duration::minutes(9));
+		sleep(Duration::from_millis(200)).await;
+		assert_eq!(stand_in.take_requests().len(), 2);
+
+		// Without direct messages, the reply in the channel is removed again
+		stand_in.respond(Method::POST, "/users/@me/channels", 403, "Forbidden");
+		stand_in.respond_json(
+			Method::POST,
+			"/channels/4/messages",
+			200,
+			json!({ "id": "70" }),
+		);
+		assert_eq!(feed(&mut link_only, &guild, &[message(12, 7)]), [false]);
+		let requests = stand_in.wait_for(4, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 4);
+		assert!(requests[0].is(Method::DELETE, "/channels/4/messages/12"));
+		assert!(requests[1].is(Method::POST, "/users/@me/channels"));
+		assert!(requests[2].is(Method::POST, "/channels/4/messages"));
+		let reply = requests[2].json().unwrap();
+		let reply = reply["content"].as_str().unwrap();
+		assert!(reply.starts_with("<@7> Your message in <#4> was deleted"));
+		assert!(!reply.contains("```"));
+		assert!(requests[3].is(Method::DELETE, "/channels/4/messages/70"));
+		stand_in.take_requests();
+
+		// Our reply, and messages of other bots, are left alone
+		let reply = state.message(70, 4, 3, reply);
+		let mut other_bot = state.message(71, 4, 7, "hello");
+		other_bot["author"]["bot"] = json!(true);
+		let events: Vec<_> = [reply, other_bot]
+			.into_iter()
+			.map(|message| {
+				Event::MessageCreate(event::MessageCreate {
+					message: serde_json::from_value(message).unwrap(),
+				})
+			})
+			.collect();
+		assert_eq!(feed(&mut link_only, &guild, &events), [true, true]);
+		sleep(Duration::from_millis(200)).await;
+		assert!(stand_in.requests().is_empty());
+	}
+}
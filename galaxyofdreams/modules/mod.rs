@@ -1,29 +1,38 @@
 pub use self::astronauts::{Astronauts, AstronautsConfig};
-// pub use self::automod::{Automod, AutomodConfig};
+pub use self::automod::{Automod, AutomodConfig};
 // pub use self::collab_playlist::{CollabPlaylist, CollabPlaylistConfig};
 pub use self::commands::{Commands, CommandsConfig};
 // pub use self::dj::DJ;
+pub use self::feeds::{Feeds, FeedsConfig};
 pub use self::filter::Filter;
 pub use self::joined::{Joined, JoinedConfig};
+pub use self::levels::{Levels, LevelsConfig};
 pub use self::link_only::{LinkOnly, LinkOnlyConfig};
+pub use self::permission::Permission;
 pub use self::role_assign::{RoleAssign, RoleAssignConfig};
+pub use self::usage_stats::{UsageStats, UsageStatsConfig};
 pub use self::youtube::{Youtube, YoutubeConfig};
-use anyhow::{anyhow, Result};
-use common::{Storage, StorageKind};
+use crate::command_registry::CommandRegistry;
+use crate::supervisor::Supervisor;
+use crate::usage::Usage;
+use anyhow::{anyhow, bail, Result};
+use common::Client;
+use common::{EventHandler, Guild, Migration, Pipeline, Storage, StorageKind};
 use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
 use futures::StreamExt;
 use hotwatch::Hotwatch;
 use log::{info, warn};
 use serde_json::Value;
-use sqlx::{query, AnyPool, Row};
+use sqlx::{query, Row};
 use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::io::BufReader;
-use std::ops::Deref;
 use std::path::PathBuf;
 use std::time::Duration;
 use tokio::time::sleep;
-// pub use crate::levels::{Levels, LevelsConfig, LevelsStorageProvider};
+use warp::filters::BoxedFilter;
+use warp::{Filter as _, Reply};
 
 macro_rules! load_config {
 	($n:expr, $t:expr, $e:expr) => {{
@@ -56,27 +65,266 @@ macro_rules! shared_config {
 }
 
 mod astronauts;
-// mod automod;
+mod automod;
 // mod collab_playlist;
 mod commands;
 // mod dj;
+mod feeds;
 mod filter;
 mod joined;
-// mod levels;
+mod levels;
 mod link_only;
+mod permission;
 mod role_assign;
+mod usage_stats;
 pub mod youtube;
 
+/// Modules that are loaded when the configuration doesn't list any, in order of priority.
+/// Modules added since are left out, so existing deployments only load them once listed
+pub const DEFAULT_MODULES: &[&str] = &[
+	"filter",
+	// "dj",
+	"joined",
+	"commands",
+	"link_only",
+	"role_assign",
+	// "collab_playlist",
+	"astronauts",
+	"youtube",
+];
+
+pub type Route = BoxedFilter<(Box<dyn Reply>,)>;
+
+/// Constructs a module from the shared resources in the `Context`
+pub type Constructor =
+	for<'a, 'b> fn(&'a mut Context<'b>) -> LocalBoxFuture<'a, Result<Box<dyn EventHandler>>>;
+
+/// Everything a module might need during construction
+pub struct Context<'a> {
+	pub guild: &'a Guild,
+	pub client: Client,
+	pub storage: Storage,
+	pub commands: CommandRegistry,
+	pub usage: Usage,
+	pub supervisor: &'a mut Supervisor,
+	pub http_ext_url: String,
+	routes: Vec<Route>,
+}
+
+impl<'a> Context<'a> {
+	pub fn new(
+		guild: &'a Guild,
+		storage: Storage,
+		commands: CommandRegistry,
+		usage: Usage,
+		supervisor: &'a mut Supervisor,
+		http_ext_url: String,
+	) -> Self {
+		Self {
+			guild,
+			client: guild.client(),
+			storage,
+			commands,
+			usage,
+			supervisor,
+			http_ext_url,
+			routes: Vec::new(),
+		}
+	}
+
+	/// Serve `route` on the HTTP server
+	pub fn route(&mut self, route: Route) {
+		self.routes.push(route);
+	}
+
+	/// All routes registered by the modules, if any
+	pub fn into_routes(self) -> Option<Route> {
+		self.routes
+			.into_iter()
+			.reduce(|a, b| a.or(b).unify().boxed())
+	}
+}
+
+/// Known modules by name. The pipeline is assembled from these at startup
+pub struct Registry {
+	constructors: HashMap<&'static str, Constructor>,
+}
+
+impl Registry {
+	pub fn new() -> Self {
+		let mut registry = Self {
+			constructors: HashMap::new(),
+		};
+
+		registry.register("filter", |_| Box::pin(async { boxed(Filter::new()) }));
+		registry.register("automod", |ctx| {
+			Box::pin(async move { boxed(Automod::new(ctx.supervisor.tracker())) })
+		});
+		// registry.register("dj", |_| Box::pin(async { boxed(DJ::new()) }));
+		registry.register("levels", |ctx| {
+			Box::pin(async move {
+				let tracker = ctx.supervisor.tracker();
+				let levels = Levels::new(
+					ctx.commands.clone(),
+					ctx.usage.clone(),
+					ctx.storage.clone(),
+					tracker,
+				)
+				.await?;
+				boxed(levels)
+			})
+		});
+		registry.register("joined", |ctx| {
+			Box::pin(async move {
+				let tracker = ctx.supervisor.tracker();
+				boxed(Joined::new(
+					ctx.commands.clone(),
+					ctx.usage.clone(),
+					tracker,
+				))
+			})
+		});
+		registry.register("commands", |ctx| {
+			Box::pin(async move {
+				let tracker = ctx.supervisor.tracker();
+				boxed(Commands::new(
+					ctx.commands.clone(),
+					ctx.usage.clone(),
+					tracker,
+				))
+			})
+		});
+		registry.register("usage_stats", |ctx| {
+			Box::pin(async move {
+				let tracker = ctx.supervisor.tracker();
+				boxed(UsageStats::new(
+					ctx.commands.clone(),
+					ctx.usage.clone(),
+					tracker,
+				))
+			})
+		});
+		registry.register("link_only", |ctx| {
+			Box::pin(async move {
+				let tracker = ctx.supervisor.tracker();
+				boxed(LinkOnly::new(ctx.storage.clone(), tracker).await?)
+			})
+		});
+		registry.register("role_assign", |ctx| {
+			Box::pin(async move {
+				let tracker = ctx.supervisor.tracker();
+				boxed(RoleAssign::new(ctx.client.clone(), ctx.storage.clone(), tracker).await?)
+			})
+		});
+		registry.register("astronauts", |ctx| {
+			Box::pin(async move {
+				let astronauts =
+					Astronauts::new(ctx.guild, ctx.storage.clone(), ctx.supervisor).await?;
+				ctx.route(astronauts.routes().map(reply).boxed());
+				boxed(astronauts)
+			})
+		});
+		registry.register("youtube", |ctx| {
+			Box::pin(async move {
+				let youtube = Youtube::new(
+					ctx.client.clone(),
+					ctx.storage.clone(),
+					&ctx.http_ext_url,
+					ctx.supervisor,
+				)
+				.await?;
+				ctx.route(youtube.routes().map(reply).boxed());
+				boxed(youtube)
+			})
+		});
+		registry.register("feeds", |ctx| {
+			Box::pin(async move {
+				let feeds = Feeds::new(
+					ctx.client.clone(),
+					ctx.storage.clone(),
+					&ctx.http_ext_url,
+					ctx.supervisor,
+				)
+				.await?;
+				ctx.route(feeds.routes().map(reply).boxed());
+				boxed(feeds)
+			})
+		});
+
+		registry
+	}
+
+	pub fn register(&mut self, name: &'static str, constructor: Constructor) {
+		self.constructors.insert(name, constructor);
+	}
+
+	/// Construct the listed modules, in order. Events and configuration updates
+	/// are offered to the modules in this order
+	pub async fn build<S>(&self, names: &[S], ctx: &mut Context<'_>) -> Result<Pipeline>
+	where
+		S: AsRef<str>,
+	{
+		let mut pipeline = Pipeline::new();
+		let mut loaded = Vec::with_capacity(names.len());
+		for name in names {
+			let name = name.as_ref().trim();
+			let constructor = match self.constructors.get(name) {
+				Some(c) => c,
+				None => bail!("Unknown module '{}'", name),
+			};
+			if loaded.contains(&name) {
+				bail!("Module '{}' is listed more than once", name);
+			}
+			pipeline.push(constructor(ctx).await?);
+			loaded.push(name);
+		}
+		info!("Loaded modules: {}", loaded.join(", "));
+		Ok(pipeline)
+	}
+}
+
+fn boxed<T>(module: T) -> Result<Box<dyn EventHandler>>
+where
+	T: EventHandler + 'static,
+{
+	Ok(Box::new(module))
+}
+
+fn reply<T>(reply: T) -> Box<dyn Reply>
+where
+	T: Reply + 'static,
+{
+	Box::new(reply)
+}
+
+const CREATE_MODULE_CONFIG: &'static str = r#"
+	CREATE TABLE IF NOT EXISTS module_config (
+		name TEXT PRIMARY KEY NOT NULL,
+		version INTEGER NOT NULL,
+		data TEXT NOT NULL
+	);
+"#;
+
+const MODULE_CONFIG_MIGRATIONS: &[Migration] = &[Migration {
+	name: "create_module_config",
+	sqlite: CREATE_MODULE_CONFIG,
+	postgres: CREATE_MODULE_CONFIG,
+}];
+
 pub enum Configurator {
 	Db(DbConfigurator),
 	File(FileConfigurator),
 }
 
 impl Configurator {
-	pub fn new(storage: &Storage, module_config_dir: &PathBuf) -> Result<Self> {
+	pub fn new(storage: &Storage, module_config_dir: Option<&PathBuf>) -> Result<Self> {
 		let c = match storage.kind() {
-			StorageKind::Sqlite => Configurator::File(FileConfigurator::new(module_config_dir)?),
-			StorageKind::Postgres => Configurator::Db(DbConfigurator::new(storage.deref().clone())),
+			StorageKind::Sqlite => {
+				let module_config_dir = module_config_dir
+					.ok_or_else(|| anyhow!("Module configuration directory not found"))?;
+				Configurator::File(FileConfigurator::new(module_config_dir)?)
+			}
+			StorageKind::Postgres => Configurator::Db(DbConfigurator::new(storage.clone())),
 		};
 		Ok(c)
 	}
@@ -90,16 +338,16 @@ impl Configurator {
 }
 
 pub struct DbConfigurator {
-	pool: AnyPool,
+	storage: Storage,
 	init: bool,
 	configs: VecDeque<(String, Value)>,
 	versions: HashMap<String, i32>,
 }
 
 impl DbConfigurator {
-	pub fn new(pool: AnyPool) -> Self {
+	pub fn new(storage: Storage) -> Self {
 		Self {
-			pool,
+			storage,
 			init: true,
 			configs: VecDeque::with_capacity(4),
 			versions: HashMap::new(),
@@ -113,13 +361,16 @@ impl DbConfigurator {
 			}
 
 			if self.init {
+				self.storage
+					.migrate("module_config", MODULE_CONFIG_MIGRATIONS)
+					.await?;
 				self.init = false;
 				info!("Init configurator");
 			} else {
 				sleep(Duration::from_secs(10)).await;
 			}
 
-			let mut cursor = query("SELECT * FROM module_config").fetch(&self.pool);
+			let mut cursor = query("SELECT * FROM module_config").fetch(&*self.storage);
 			while let Some(row) = cursor.next().await {
 				let row = match row {
 					Ok(r) => r,
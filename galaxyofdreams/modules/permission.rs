@@ -0,0 +1,135 @@
+use crate::supervisor::Tracker;
+use common::discord::types::{Member, RoleId, UserId};
+use common::interaction::*;
+use common::Guild;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Who is allowed to use a module or command. Listed users are always allowed and
+/// members with a denied role never are. If any roles, a minimum role or users are
+/// given, everyone else needs one of the roles or a role at least as high as the
+/// minimum. Without any of those everyone is allowed
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Permission {
+	#[serde(default)]
+	roles: HashSet<RoleId>,
+	#[serde(default)]
+	denied_roles: HashSet<RoleId>,
+	#[serde(default)]
+	minimum_role: Option<RoleId>,
+	#[serde(default)]
+	users: HashSet<UserId>,
+}
+
+impl Permission {
+	#[inline]
+	fn is_restricted(&self) -> bool {
+		!self.roles.is_empty() || self.minimum_role.is_some() || !self.users.is_empty()
+	}
+
+	pub fn allows(&self, guild: &Guild, member: Option<&Member>) -> bool {
+		let member = match member {
+			Some(m) => m,
+			None => return !self.is_restricted() && self.denied_roles.is_empty(),
+		};
+
+		if let Some(user) = &member.user {
+			if self.users.contains(&user.id) {
+				return true;
+			}
+		}
+
+		if member.roles.iter().any(|r| self.denied_roles.contains(r)) {
+			return false;
+		}
+
+		if !self.is_restricted() {
+			return true;
+		}
+
+		if member.roles.iter().any(|r| self.roles.contains(r)) {
+			return true;
+		}
+
+		self.minimum_role
+			.and_then(|r| guild.role(r))
+			.map(|r| guild.member_role_position(member) >= r.position)
+			.unwrap_or(false)
+	}
+
+	/// Whether the member behind `interaction` is allowed. If not, the member
+	/// is told so with an ephemeral response, sent as a task of `tracker`
+	pub fn check(&self, guild: &Guild, interaction: &Interaction, tracker: &Tracker) -> bool {
+		if self.allows(guild, interaction.member.as_ref()) {
+			return true;
+		}
+		deny(guild, interaction, tracker);
+		false
+	}
+
+	/// Like `check`, but nobody is allowed when no roles, minimum role or users
+	/// are given. For commands that shouldn't be open to everyone by default
+	pub fn check_restricted(
+		&self,
+		guild: &Guild,
+		interaction: &Interaction,
+		tracker: &Tracker,
+	) -> bool {
+		if self.is_restricted() {
+			return self.check(guild, interaction, tracker);
+		}
+		deny(guild, interaction, tracker);
+		false
+	}
+}
+
+fn deny(guild: &Guild, interaction: &Interaction, tracker: &Tracker) {
+	interaction
+		.respond(guild)
+		.content("You are not allowed to use this command")
+		.ephemeral()
+		.spawn(tracker);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::testing::{GuildState, StandIn};
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn allows() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Moderator", 2)
+			.role(4, "Helper", 1)
+			.role(5, "Muted", 0)
+			.member(6, "someone", &[])
+			.member(7, "moderator", &[3])
+			.member(8, "helper", &[4])
+			.member(9, "muted", &[3, 5])
+			.member(10, "friend", &[5]);
+		let guild = state.guild(&stand_in);
+		let allows = |permission: &Permission, user_id: u64| {
+			permission.allows(&guild, guild.member(user_id.into()))
+		};
+
+		let everyone = Permission::default();
+		assert!(allows(&everyone, 6));
+		assert!(everyone.allows(&guild, None));
+
+		let permission: Permission = serde_json::from_value(json!({
+			"minimum_role": "3",
+			"roles": ["4"],
+			"denied_roles": ["5"],
+			"users": ["10"],
+		}))
+		.unwrap();
+		assert!(!allows(&permission, 6));
+		assert!(allows(&permission, 7));
+		assert!(allows(&permission, 8));
+		assert!(!allows(&permission, 9));
+		assert!(allows(&permission, 10));
+		assert!(!permission.allows(&guild, None));
+	}
+}
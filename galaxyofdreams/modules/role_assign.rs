@@ -1,11 +1,12 @@
+use crate::supervisor::Tracker;
 use anyhow::Result;
 use common::discord::client::{ButtonComponent, RowComponent};
-use common::discord::interaction::CanRespond;
 use common::discord::types::{
 	ChannelId, Color, Embed, Event, Interaction, MessageId, PartialEmoji, RoleId,
 };
-use common::discord::Client;
-use common::{EventHandler, Guild, Storage};
+use common::interaction::Respond;
+use common::Client;
+use common::{EventHandler, Guild, Migration, Storage};
 use log::{info, warn};
 use metrohash::MetroHash64;
 use serde::{Deserialize, Serialize};
@@ -17,14 +18,25 @@ use std::mem;
 
 const BUTTON_ID_PREFIX: &'static str = "roleassign";
 
-const CREATE_TABLE_SQLITE: &'static str = r#"
-	CREATE TABLE IF NOT EXISTS role_assign (
-		id INTEGER PRIMARY KEY NOT NULL,
-		hash INTEGER NOT NULL,
-		channel_id INTEGER NOT NULL,
-		message_id INTEGER NOT NULL
-	);
-"#;
+const MIGRATIONS: &[Migration] = &[Migration {
+	name: "create_role_assign",
+	sqlite: r#"
+		CREATE TABLE IF NOT EXISTS role_assign (
+			id INTEGER PRIMARY KEY NOT NULL,
+			hash INTEGER NOT NULL,
+			channel_id INTEGER NOT NULL,
+			message_id INTEGER NOT NULL
+		);
+	"#,
+	postgres: r#"
+		CREATE TABLE IF NOT EXISTS role_assign (
+			id INTEGER PRIMARY KEY NOT NULL,
+			hash BIGINT NOT NULL,
+			channel_id BIGINT NOT NULL,
+			message_id BIGINT NOT NULL
+		);
+	"#,
+}];
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RoleAssignConfig {
@@ -88,22 +100,23 @@ pub struct RoleAssign {
 	config: RoleAssignConfig,
 	client: Client,
 	storage: Storage,
+	tracker: Tracker,
 }
 
 impl RoleAssign {
-	pub async fn new(client: Client, storage: Storage) -> Result<Self> {
+	pub async fn new(client: Client, storage: Storage, tracker: Tracker) -> Result<Self> {
 		let r = Self {
 			config: Default::default(),
 			client,
 			storage,
+			tracker,
 		};
 		r.init_storage().await?;
 		Ok(r)
 	}
 
 	async fn init_storage(&self) -> Result<()> {
-		query(CREATE_TABLE_SQLITE).execute(&*self.storage).await?;
-		Ok(())
+		self.storage.migrate("role_assign", MIGRATIONS).await
 	}
 
 	fn update_messages(&self, guild: &Guild) {
@@ -131,7 +144,7 @@ impl RoleAssign {
 			let storage = &*storage;
 
 			let mut ids = query_as::<_, (i64, ChannelId, MessageId)>(
-				"SELECT hash, channel_id, message_id FROM role_assign WHERE id = ?",
+				"SELECT hash, channel_id, message_id FROM role_assign WHERE id = $1",
 			)
 			.bind(id)
 			.fetch_optional(storage)
@@ -168,15 +181,14 @@ impl RoleAssign {
 					.component_rows(rows)
 					.send()
 					.await?
-					.id
 			};
 
-			query("DELETE FROM role_assign WHERE id = ?")
+			query("DELETE FROM role_assign WHERE id = $1")
 				.bind(id)
 				.execute(storage)
 				.await?;
 
-			query("INSERT INTO role_assign (id, hash, channel_id, message_id) VALUES (?, ?, ?, ?)")
+			query("INSERT INTO role_assign (id, hash, channel_id, message_id) VALUES ($1, $2, $3, $4)")
 				.bind(id)
 				.bind(hash)
 				.bind(channel_id)
@@ -187,7 +199,7 @@ impl RoleAssign {
 			Result::<_>::Ok(())
 		};
 
-		tokio::spawn(async move {
+		self.tracker.spawn(async move {
 			if let Err(e) = fut.await {
 				warn!("Update message: {}", e);
 			}
@@ -246,7 +258,7 @@ impl RoleAssign {
 			Result::<_>::Ok(())
 		};
 
-		tokio::spawn(async move {
+		self.tracker.spawn(async move {
 			if let Err(e) = fut.await {
 				warn!("Button click respond: {}", e);
 			}
@@ -349,3 +361,89 @@ fn render(
 	}
 	(embed, rows)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use common::discord::types::event;
+	use common::testing::{feed, GuildState, StandIn};
+	use serde_json::json;
+	use std::time::Duration;
+	use std::{env, fs, process};
+	use warp::http::Method;
+
+	#[tokio::test]
+	async fn assigns_roles() {
+		let stand_in = StandIn::start().await;
+		stand_in.respond_json(
+			Method::POST,
+			"/channels/4/messages",
+			200,
+			json!({ "id": "20" }),
+		);
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Red", 1)
+			.text_channel(4, "roles")
+			.member(5, "someone", &[])
+			.member(6, "other", &[3]);
+		let guild = state.guild(&stand_in);
+		let click = |id, user_id| {
+			let interaction = state.button_interaction(id, 4, user_id, "roleassign_1_0");
+			Event::InteractionCreate(event::InteractionCreate {
+				interaction: serde_json::from_value(interaction).unwrap(),
+			})
+		};
+
+		let path = env::temp_dir().join(format!("dnbot_role_assign_{}.db", process::id()));
+		let _ = fs::remove_file(&path);
+		let storage = Storage::new(&format!("sqlite://{}?mode=rwc", path.display()))
+			.await
+			.unwrap();
+		let supervisor = Supervisor::new();
+		let mut role_assign = RoleAssign::new(guild.client(), storage, supervisor.tracker())
+			.await
+			.unwrap();
+		let config = json!({
+			"enabled": true,
+			"messages": [{
+				"id": 1,
+				"channel_id": "4",
+				"message": "Pick a color",
+				"buttons": [[{ "label": "Red", "role_id": "3" }]],
+			}],
+		});
+		assert!(role_assign.config(&guild, "role_assign", config).is_none());
+
+		let requests = stand_in.wait_for(1, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 1);
+		assert!(requests[0].is(Method::POST, "/channels/4/messages"));
+		let message = requests[0].json().unwrap();
+		assert_eq!(message["embeds"][0]["description"], "Pick a color");
+		let button = &message["components"][0]["components"][0];
+		assert_eq!(button["custom_id"], "roleassign_1_0");
+		assert_eq!(button["label"], "Red (1)");
+		stand_in.take_requests();
+
+		assert_eq!(
+			feed(&mut role_assign, &guild, &[click(10, 5), click(11, 6)]),
+			[false, false]
+		);
+		let mut requests = stand_in.wait_for(4, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 4);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		assert!(requests[0].is(Method::PUT, "/guilds/1/members/5/roles/3"));
+		assert!(requests[1].is(Method::DELETE, "/guilds/1/members/6/roles/3"));
+		let label = |i: usize| {
+			assert!(requests[i].path.ends_with("/callback"));
+			let response = requests[i].json().unwrap();
+			assert_eq!(response["type"], 7);
+			response["data"]["components"][0]["components"][0]["label"].clone()
+		};
+		assert_eq!(label(2), "Red (2)");
+		assert_eq!(label(3), "Red (0)");
+
+		supervisor.shutdown(Duration::from_secs(5)).await;
+		let _ = fs::remove_file(&path);
+	}
+}
@@ -0,0 +1,425 @@
+use crate::command_registry::{CommandRegistry, CommandSpec};
+use crate::modules::Permission;
+use crate::supervisor::Tracker;
+use crate::usage::{Outcome, Summary, Usage};
+use chrono::{Days, NaiveDate, Utc};
+use common::discord::types::{ApplicationCommandOption, ApplicationCommandOptionType, Event};
+use common::interaction::*;
+use common::{EventHandler, Guild};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::Write;
+use std::mem;
+
+const COMMAND_NAME: &'static str = "usage";
+const DATE_FORMAT: &'static str = "%Y-%m-%d";
+// Days covered when no start date is given
+const DEFAULT_DAYS: u64 = 30;
+// Rows per table, to stay within the message size limit
+const MAX_ROWS: usize = 20;
+// Discord rejects longer messages
+const MESSAGE_LIMIT: usize = 2000;
+// Room kept for the line about omitted entries
+const MORE_LENGTH: usize = 20;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UsageStatsConfig {
+	enabled: bool,
+	// Who can see the usage, typically moderators. Nobody if not given
+	#[serde(default)]
+	permission: Permission,
+}
+
+/// Admin command that summarizes the recorded command usage
+pub struct UsageStats {
+	config: UsageStatsConfig,
+	registry: CommandRegistry,
+	usage: Usage,
+	tracker: Tracker,
+}
+
+impl UsageStats {
+	pub fn new(registry: CommandRegistry, usage: Usage, tracker: Tracker) -> Self {
+		registry.expect("usage_stats");
+		Self {
+			config: Default::default(),
+			registry,
+			usage,
+			tracker,
+		}
+	}
+
+	/// Declare the command, or none when the module is disabled
+	fn declare_command(&self) {
+		let mut specs = Vec::new();
+		if self.config.enabled {
+			let option = |name: &str, description: &str| ApplicationCommandOption {
+				option_type: ApplicationCommandOptionType::String,
+				name: name.into(),
+				description: description.into(),
+				required: false,
+				choices: Vec::new(),
+				options: Vec::new(),
+			};
+			specs.push(CommandSpec::new(
+				COMMAND_NAME,
+				"Show how often commands were used",
+				vec![
+					option("from", "First day, as YYYY-MM-DD"),
+					option("to", "Last day, as YYYY-MM-DD"),
+				],
+			));
+		}
+		self.registry.declare("usage_stats", specs);
+	}
+
+	fn interaction(&mut self, guild: &Guild, interaction: &Interaction) -> bool {
+		if !self.config.enabled {
+			return true;
+		}
+
+		if interaction.data.name.as_deref() != Some(COMMAND_NAME) {
+			return true;
+		}
+
+		// From here on we consume the message: return `false`
+
+		if !self
+			.config
+			.permission
+			.check_restricted(guild, interaction, &self.tracker)
+		{
+			self.usage
+				.record(COMMAND_NAME, interaction, Outcome::Denied);
+			return false;
+		}
+		self.usage
+			.record(COMMAND_NAME, interaction, Outcome::Allowed);
+
+		let option = |name| {
+			interaction
+				.data
+				.options
+				.iter()
+				.find(|o| o.name == name)
+				.and_then(|o| o.value.as_deref())
+				.map(|v| NaiveDate::parse_from_str(v.trim(), DATE_FORMAT))
+		};
+		let dates = match (option("from"), option("to")) {
+			(Some(Err(_)), _) | (_, Some(Err(_))) => None,
+			(from, to) => {
+				let to = to
+					.and_then(|t| t.ok())
+					.unwrap_or_else(|| Utc::now().date_naive());
+				// Dates near the limits of the calendar overflow
+				let from = match from.and_then(|f| f.ok()) {
+					Some(from) => Some(from),
+					None => to.checked_sub_days(Days::new(DEFAULT_DAYS - 1)),
+				};
+				let end = to.checked_add_days(Days::new(1));
+				from.zip(end).map(|(from, end)| (from, to, end))
+			}
+		};
+		let (from, to, end) = match dates {
+			Some(d) => d,
+			None => {
+				interaction
+					.respond(guild)
+					.content("Dates have to be given as YYYY-MM-DD")
+					.ephemeral()
+					.spawn(&self.tracker);
+				return false;
+			}
+		};
+		if from > to {
+			interaction
+				.respond(guild)
+				.content("The first day has to come before the last day")
+				.ephemeral()
+				.spawn(&self.tracker);
+			return false;
+		}
+
+		let start = from.and_hms_opt(0, 0, 0).unwrap().timestamp();
+		let end = end.and_hms_opt(0, 0, 0).unwrap().timestamp();
+		let registered: Vec<String> = guild.commands().map(|c| c.name.clone()).collect();
+		let usage = self.usage.clone();
+		let response = interaction.respond(guild).ephemeral();
+		self.tracker.spawn(async move {
+			let content = match usage.summary(start, end).await {
+				Ok(summary) => report(&summary, from, to, &registered),
+				Err(e) => {
+					warn!("Unable to summarize usage: {}", e);
+					"Unable to look up the command usage".to_owned()
+				}
+			};
+			if let Err(e) = response.content(content).send().await {
+				warn!("Unable to respond to interaction: {}", e);
+			}
+		});
+
+		false
+	}
+}
+
+fn report(summary: &Summary, from: NaiveDate, to: NaiveDate, registered: &[String]) -> String {
+	let mut content = format!(
+		"Command usage from {} to {}\n",
+		from.format(DATE_FORMAT),
+		to.format(DATE_FORMAT)
+	);
+	let mut capped = Capped::default();
+
+	capped.push(&mut content, "**Commands**\n");
+	if summary.commands.is_empty() {
+		capped.push(&mut content, "None\n");
+	}
+	for command in summary.commands.iter().take(MAX_ROWS) {
+		let mut line = format!("`/{}`: {}", command.command, command.allowed);
+		if command.denied > 0 || command.cooldown > 0 {
+			let _ = write!(
+				line,
+				" ({} denied, {} on cooldown)",
+				command.denied, command.cooldown
+			);
+		}
+		line.push('\n');
+		capped.entry(&mut content, &line);
+	}
+
+	if !summary.channels.is_empty() {
+		capped.push(&mut content, "**Channels**\n");
+	}
+	for (channel_id, count) in summary.channels.iter().take(MAX_ROWS) {
+		capped.entry(&mut content, &format!("<#{}>: {}\n", channel_id, count));
+	}
+
+	let mut unused: Vec<_> = registered
+		.iter()
+		.filter(|n| !summary.commands.iter().any(|c| &c.command == *n))
+		.map(|n| format!("`/{}`", n))
+		.collect();
+	if !unused.is_empty() {
+		unused.sort();
+		capped.push(&mut content, "**Unused**\n");
+		for (i, name) in unused.iter().enumerate() {
+			let separator = if i == 0 { "" } else { ", " };
+			capped.entry(&mut content, &format!("{}{}", separator, name));
+		}
+	}
+
+	if capped.omitted > 0 {
+		if !content.ends_with('\n') {
+			content.push('\n');
+		}
+		let _ = write!(content, "…and {} more", capped.omitted);
+	}
+	content
+}
+
+/// Keeps a report within the message size limit. Once something doesn't fit, everything
+/// after it is left out as well, so the report doesn't skip around
+#[derive(Default)]
+struct Capped {
+	full: bool,
+	// Entries that were left out
+	omitted: usize,
+}
+
+impl Capped {
+	/// Append `text`, if it leaves room for the line about omitted entries
+	fn push(&mut self, content: &mut String, text: &str) -> bool {
+		let length = content.chars().count() + text.chars().count() + MORE_LENGTH;
+		self.full |= length > MESSAGE_LIMIT;
+		if !self.full {
+			content.push_str(text);
+		}
+		!self.full
+	}
+
+	/// Like `push`, counting `text` as an omitted entry if it doesn't fit
+	fn entry(&mut self, content: &mut String, text: &str) {
+		if !self.push(content, text) {
+			self.omitted += 1;
+		}
+	}
+}
+
+impl EventHandler for UsageStats {
+	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
+		let config = load_config!(name, "usage_stats", config);
+		let old = mem::replace(&mut self.config, config);
+		if old.enabled != self.config.enabled {
+			if self.config.enabled {
+				info!("Module enabled");
+			} else {
+				info!("Module disabled");
+			}
+		} else {
+			info!("Config updated");
+		}
+		self.declare_command();
+
+		None
+	}
+
+	fn event(&mut self, guild: &Guild, event: &Event) -> bool {
+		if let Event::InteractionCreate(ic) = event {
+			self.interaction(guild, &ic.interaction)
+		} else {
+			true
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::supervisor::Supervisor;
+	use crate::usage::{temp_usage, CommandSummary};
+	use common::discord::types::event;
+	use common::testing::{feed, GuildState, StandIn};
+	use serde_json::json;
+	use std::time::Duration;
+	use tokio::time::sleep;
+
+	#[test]
+	fn caps_report() {
+		let summary = Summary {
+			commands: vec![CommandSummary {
+				command: "hello".to_owned(),
+				allowed: 1,
+				..Default::default()
+			}],
+			channels: Vec::new(),
+		};
+		let registered: Vec<_> = (0..200).map(|i| format!("command{:03}", i)).collect();
+		let date = NaiveDate::from_ymd_opt(2023, 3, 1).unwrap();
+		let report = report(&summary, date, date, &registered);
+		assert!(report.chars().count() <= MESSAGE_LIMIT);
+		assert!(report.contains("**Unused**\n`/command000`, `/command001`"));
+
+		// Every unused command is either listed or counted
+		let listed = report.matches("`/command").count();
+		let more = report.rsplit_once("…and ").unwrap().1;
+		assert_eq!(more, format!("{} more", 200 - listed));
+	}
+
+	#[tokio::test]
+	async fn disabled() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "commands")
+			.member(4, "someone", &[]);
+		let guild = state.guild(&stand_in);
+		let interaction = state.command_interaction(10, 3, 4, "usage", &[]);
+		let interaction = Event::InteractionCreate(event::InteractionCreate {
+			interaction: serde_json::from_value(interaction).unwrap(),
+		});
+
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let usage = temp_usage("usage_stats_disabled").await;
+		let mut stats = UsageStats::new(registry.clone(), usage, tracker);
+		let config = json!({ "enabled": false, "permission": { "users": ["4"] } });
+		assert!(stats.config(&guild, "usage_stats", config).is_none());
+		registry.sync(&guild);
+
+		// The command is neither declared nor answered
+		assert_eq!(feed(&mut stats, &guild, &[interaction]), [true]);
+		let requests = stand_in.wait_for(1, Duration::from_millis(200)).await;
+		assert!(requests.is_empty());
+	}
+
+	#[tokio::test]
+	async fn reports() {
+		let stand_in = StandIn::start().await;
+		let state = GuildState::new(1, 2, "Test")
+			.role(3, "Moderator", 1)
+			.text_channel(4, "commands")
+			.member(5, "someone", &[])
+			.member(6, "moderator", &[3])
+			.command(7, "hello", "Say hello")
+			.command(8, "usage", "Show how often commands were used");
+		let guild = state.guild(&stand_in);
+		let interaction = |id, user_id, options: &[(&str, ApplicationCommandOptionType, &str)]| {
+			let interaction = state.command_interaction(id, 4, user_id, "usage", options);
+			Event::InteractionCreate(event::InteractionCreate {
+				interaction: serde_json::from_value(interaction).unwrap(),
+			})
+		};
+
+		let usage = temp_usage("usage_stats").await;
+		let tracker = Supervisor::new().tracker();
+		let registry = CommandRegistry::new(tracker.clone());
+		let mut stats = UsageStats::new(registry, usage, tracker);
+		let config = json!({ "enabled": true, "permission": { "minimum_role": "3" } });
+		assert!(stats.config(&guild, "usage_stats", config).is_none());
+
+		assert_eq!(
+			feed(&mut stats, &guild, &[interaction(10, 5, &[])]),
+			[false]
+		);
+		sleep(Duration::from_millis(500)).await;
+		assert_eq!(
+			feed(
+				&mut stats,
+				&guild,
+				&[
+					interaction(11, 6, &[]),
+					interaction(
+						12,
+						6,
+						&[("from", ApplicationCommandOptionType::String, "yesterday")]
+					),
+					interaction(
+						13,
+						6,
+						&[("to", ApplicationCommandOptionType::String, "+262143-12-31")]
+					),
+					interaction(
+						14,
+						6,
+						&[("to", ApplicationCommandOptionType::String, "-262144-01-05")]
+					),
+				]
+			),
+			[false; 4]
+		);
+
+		let mut requests = stand_in.wait_for(5, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 5);
+		requests.sort_by(|a, b| a.path.cmp(&b.path));
+		let content = |i: usize| {
+			assert!(requests[i].path.ends_with("/callback"));
+			let response = requests[i].json().unwrap();
+			assert_eq!(response["data"]["flags"], 64);
+			response["data"]["content"].as_str().unwrap().to_owned()
+		};
+		assert_eq!(content(0), "You are not allowed to use this command");
+		let report = content(1);
+		// The invocation itself might not have been recorded yet
+		assert!(report.contains("(1 denied, 0 on cooldown)"));
+		assert!(report.ends_with("**Unused**\n`/hello`"));
+		assert_eq!(content(2), "Dates have to be given as YYYY-MM-DD");
+		assert_eq!(content(3), "Dates have to be given as YYYY-MM-DD");
+		assert_eq!(content(4), "Dates have to be given as YYYY-MM-DD");
+
+		// Without a permission nobody can see the usage
+		stand_in.take_requests();
+		let config = json!({ "enabled": true });
+		assert!(stats.config(&guild, "usage_stats", config).is_none());
+		assert_eq!(
+			feed(&mut stats, &guild, &[interaction(15, 6, &[])]),
+			[false]
+		);
+		let requests = stand_in.wait_for(1, Duration::from_secs(5)).await;
+		assert_eq!(requests.len(), 1);
+		let response = requests[0].json().unwrap();
+		assert_eq!(
+			response["data"]["content"],
+			"You are not allowed to use this command"
+		);
+	}
+}
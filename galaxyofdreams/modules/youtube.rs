@@ -1,16 +1,23 @@
-// use chrono::{DateTime, Utc};
+use crate::pattern::Pattern;
+use crate::supervisor::{Shutdown, Supervisor, Tracker};
+use crate::template::{self, EmbedTemplate, Values};
+use crate::websub::{self, Rejected, Verification};
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::{DateTime, Utc};
 use common::discord;
-use common::discord::types::ChannelId;
-use common::discord::Client;
-use common::{EventHandler, Guild};
+use common::discord::types::{AllowedMentions, ChannelId, RoleId};
+use common::Client;
+use common::{EventHandler, Guild, Migration, Storage};
 use futures::channel::mpsc;
-use futures::select;
-use futures::{FutureExt, StreamExt};
+use futures::lock::Mutex as AsyncMutex;
+use futures::{future, select};
+use futures::{FutureExt, SinkExt, StreamExt};
 use http::StatusCode;
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::{HashMap, VecDeque};
+use sqlx::{query, query_as};
+use std::collections::{HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
@@ -21,20 +28,77 @@ use warp::filters::BoxedFilter;
 use warp::hyper::body::Bytes;
 use warp::{Filter, Reply};
 
-macro_rules! first {
-	($a:expr, $b:expr) => {
-		Box::pin(
-			sleep_until(
-				$a.values()
-					.chain($b.values())
-					.min()
-					.map(|v| *v)
-					.unwrap_or_else(|| Instant::now() + Duration::from_secs(24 * 60 * 60)),
-			)
-			.fuse(),
-		)
-	};
-}
+type Secrets = Arc<Mutex<HashMap<YoutubeChannel, String>>>;
+type Unsubscribing = Arc<Mutex<HashSet<YoutubeChannel>>>;
+type Status = Arc<Mutex<HashMap<YoutubeChannel, LeaseStatus>>>;
+
+const CREATE_SECRETS: &str = r#"
+	CREATE TABLE youtube_secrets (
+		yt_channel TEXT PRIMARY KEY NOT NULL,
+		secret TEXT NOT NULL
+	);
+"#;
+
+const CREATE_LEASES_SQLITE: &str = r#"
+	CREATE TABLE youtube_leases (
+		yt_channel TEXT PRIMARY KEY NOT NULL,
+		expires_timestamp INTEGER NOT NULL
+	);
+	CREATE TABLE youtube_announcements (
+		yt_id TEXT PRIMARY KEY NOT NULL,
+		yt_channel TEXT NOT NULL,
+		created_timestamp INTEGER NOT NULL
+	);
+"#;
+
+const CREATE_LEASES_POSTGRES: &str = r#"
+	CREATE TABLE youtube_leases (
+		yt_channel TEXT PRIMARY KEY NOT NULL,
+		expires_timestamp BIGINT NOT NULL
+	);
+	CREATE TABLE youtube_announcements (
+		yt_id TEXT PRIMARY KEY NOT NULL,
+		yt_channel TEXT NOT NULL,
+		created_timestamp BIGINT NOT NULL
+	);
+"#;
+
+const ADD_ANNOUNCEMENT_KIND: &str = r#"
+	ALTER TABLE youtube_announcements ADD COLUMN kind TEXT NOT NULL DEFAULT 'video';
+"#;
+
+const ADD_ANNOUNCEMENT_UPDATED: &str = r#"
+	ALTER TABLE youtube_announcements ADD COLUMN updated_timestamp BIGINT;
+"#;
+
+const MIGRATIONS: &[Migration] = &[
+	Migration {
+		name: "create_youtube_secrets",
+		sqlite: CREATE_SECRETS,
+		postgres: CREATE_SECRETS,
+	},
+	Migration {
+		name: "create_youtube_leases",
+		sqlite: CREATE_LEASES_SQLITE,
+		postgres: CREATE_LEASES_POSTGRES,
+	},
+	Migration {
+		name: "add_youtube_announcement_kind",
+		sqlite: ADD_ANNOUNCEMENT_KIND,
+		postgres: ADD_ANNOUNCEMENT_KIND,
+	},
+	Migration {
+		name: "add_youtube_announcement_updated",
+		sqlite: ADD_ANNOUNCEMENT_UPDATED,
+		postgres: ADD_ANNOUNCEMENT_UPDATED,
+	},
+];
+
+// Renew subscriptions this long before the lease expires
+const RENEW_MARGIN: u64 = 10 * 60;
+// Time to wait before retrying a failed subscription, doubled after every failure
+const BACKOFF_MIN: Duration = Duration::from_secs(30);
+const BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct YoutubeChannel(String);
@@ -100,10 +164,135 @@ impl Deref for YoutubeId {
 	}
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VideoKind {
+	Video,
+	// Scheduled premiere or live stream
+	Upcoming,
+	Live,
+}
+
+impl VideoKind {
+	fn as_str(&self) -> &'static str {
+		match self {
+			VideoKind::Video => "video",
+			VideoKind::Upcoming => "upcoming",
+			VideoKind::Live => "live",
+		}
+	}
+}
+
+impl FromStr for VideoKind {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"video" | "none" => Ok(VideoKind::Video),
+			"upcoming" => Ok(VideoKind::Upcoming),
+			"live" => Ok(VideoKind::Live),
+			_ => Err(()),
+		}
+	}
+}
+
+// Last recorded announcement of a video
+#[derive(Clone, Copy, Debug)]
+struct Announcement {
+	kind: VideoKind,
+	// Update timestamp of the announced version, unknown for older records
+	updated: Option<i64>,
+}
+
+// Placeholders available in the templates of a subscription
+const PLACEHOLDERS: &[&str] = &["ID", "TITLE", "CHANNEL", "URL", "THUMBNAIL", "ROLE"];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VideoFilter {
+	// Titles have to match at least one of these, if there are any
+	#[serde(default)]
+	include: Vec<Pattern>,
+	#[serde(default)]
+	exclude: Vec<Pattern>,
+	// Minimum duration of videos in seconds. Requires `api_key` to be set
+	#[serde(default)]
+	min_duration: Option<u64>,
+	// Skip shorts. Videos are still announced if this can't be checked
+	#[serde(default)]
+	exclude_shorts: bool,
+}
+
+impl VideoFilter {
+	/// Reason to skip a video based on its title, if any
+	fn check_title(&self, title: &str) -> Option<String> {
+		if !self.include.is_empty() && !self.include.iter().any(|p| p.is_match(title)) {
+			return Some("Title doesn't match any include rule".to_owned());
+		}
+		self.exclude
+			.iter()
+			.find(|p| p.is_match(title))
+			.map(|p| format!("Title matches exclude rule `{}`", p))
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Subscription {
 	channel_id: ChannelId,
+	// Message templates, see `PLACEHOLDERS`. May be empty if an embed is set
+	#[serde(default)]
 	text: String,
+	#[serde(default)]
+	embed: Option<EmbedTemplate>,
+	// Role that may be pinged with %ROLE%
+	#[serde(default)]
+	role: Option<RoleId>,
+	// Announce changes to videos older than `max_age`. Suppressed if not set
+	#[serde(default)]
+	update_text: Option<String>,
+	// Templates for premieres and live streams. Requires `api_key` to be set
+	#[serde(default)]
+	upcoming_text: Option<String>,
+	#[serde(default)]
+	live_text: Option<String>,
+	#[serde(default)]
+	filter: VideoFilter,
+}
+
+impl Subscription {
+	fn validate(&self) -> Result<()> {
+		let texts = [&self.update_text, &self.upcoming_text, &self.live_text];
+		if self.embed.is_none() && texts.iter().any(|t| t.as_ref() == Some(&String::new())) {
+			bail!("Templates can't be empty without an embed");
+		}
+		if self.embed.is_none() && self.text.is_empty() {
+			bail!("Either text or embed is required");
+		}
+
+		let embed = self.embed.iter().flat_map(|e| e.templates());
+		for t in texts.into_iter().flatten().chain(embed).chain([&self.text]) {
+			template::validate(t, PLACEHOLDERS)?;
+			if self.role.is_none() && t.contains("%ROLE%") {
+				bail!("%ROLE% is used without setting a role");
+			}
+		}
+
+		Ok(())
+	}
+
+	// Whether the video details have to be looked up
+	fn needs_details(&self) -> bool {
+		self.upcoming_text.is_some()
+			|| self.live_text.is_some()
+			|| self.filter.min_duration.is_some()
+	}
+
+	fn template(&self, kind: VideoKind) -> &str {
+		let template = match kind {
+			VideoKind::Video => None,
+			VideoKind::Upcoming => self.upcoming_text.as_ref(),
+			VideoKind::Live => self.live_text.as_ref(),
+		};
+		template.unwrap_or(&self.text)
+	}
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -112,6 +301,61 @@ pub struct YoutubeConfig {
 	subscriptions: HashMap<YoutubeChannel, Subscription>,
 	#[serde(default)]
 	log_channel: Option<ChannelId>,
+	// YouTube Data API key, used to detect premieres and live streams
+	#[serde(default)]
+	api_key: Option<String>,
+	// Videos published longer ago than this (in seconds) are considered updates
+	#[serde(default = "default_max_age")]
+	max_age: i64,
+	// Poll the feed of every channel at this interval (in seconds), in case
+	// notifications from the hub don't reach us. Disabled if not set
+	#[serde(default)]
+	poll_interval: Option<u64>,
+	// Channel id is appended to get the feed
+	#[serde(default = "default_feed_url")]
+	feed_url: String,
+	#[serde(default = "default_hub_url")]
+	hub_url: String,
+	// Maximum number of subscriptions waiting for verification at the same time
+	#[serde(default = "default_concurrency")]
+	concurrency: usize,
+	// Token to pass as `?token=` to view the state of the subscriptions at `/yt/status`.
+	// Disabled if not set
+	#[serde(default)]
+	status_token: Option<String>,
+}
+
+fn default_max_age() -> i64 {
+	24 * 60 * 60
+}
+
+fn default_feed_url() -> String {
+	TOPIC_URL.to_owned()
+}
+
+fn default_hub_url() -> String {
+	HUB_URL.to_owned()
+}
+
+fn default_concurrency() -> usize {
+	5
+}
+
+impl YoutubeConfig {
+	fn validate(&self) -> Result<()> {
+		for (yt_channel, subscription) in &self.subscriptions {
+			subscription
+				.validate()
+				.with_context(|| format!("Subscription to '{}'", yt_channel))?;
+			if subscription.filter.min_duration.is_some() && self.api_key.is_none() {
+				bail!(
+					"Subscription to '{}': min_duration requires an api_key",
+					yt_channel
+				);
+			}
+		}
+		Ok(())
+	}
 }
 
 impl Default for YoutubeConfig {
@@ -120,6 +364,13 @@ impl Default for YoutubeConfig {
 			enabled: false,
 			subscriptions: HashMap::new(),
 			log_channel: None,
+			api_key: None,
+			max_age: default_max_age(),
+			poll_interval: None,
+			feed_url: default_feed_url(),
+			hub_url: default_hub_url(),
+			concurrency: default_concurrency(),
+			status_token: None,
 		}
 	}
 }
@@ -129,48 +380,139 @@ pub struct Youtube {
 	// Safe to use std Mutex since we only need to keep the lock for a very short time and
 	// don't need to hold it across await points
 	config: Arc<Mutex<YoutubeConfig>>,
+	// Secrets the hub uses to sign its notifications, per channel
+	secrets: Secrets,
+	// Channels we requested to unsubscribe from, awaiting verification by the hub
+	unsubscribing: Unsubscribing,
+	status: Status,
 	sender: mpsc::Sender<Event>,
+	// Whether the first config has been received
+	configured: Arc<Mutex<bool>>,
 }
 
 impl Youtube {
-	pub fn new(client: Client, ext_url: &str) -> Self {
+	pub async fn new(
+		client: Client,
+		storage: Storage,
+		ext_url: &str,
+		supervisor: &mut Supervisor,
+	) -> Result<Self> {
+		storage.migrate("youtube", MIGRATIONS).await?;
+
+		// Load the secrets before the HTTP server starts, so notifications
+		// for existing subscriptions can be verified straight away
+		let secrets =
+			query_as::<_, (String, String)>("SELECT yt_channel, secret FROM youtube_secrets")
+				.fetch_all(&*storage)
+				.await?
+				.into_iter()
+				.map(|(c, s)| (YoutubeChannel::from(c), s))
+				.collect();
+		let secrets = Arc::new(Mutex::new(secrets));
+		let unsubscribing = Arc::new(Mutex::new(HashSet::new()));
+		let status = Arc::new(Mutex::new(HashMap::new()));
+		let configured = Arc::new(Mutex::new(false));
+
 		let (sender, recv) = mpsc::channel(8);
 		let config = Arc::new(Mutex::new(Default::default()));
 
-		let announcer = Announcer {
-			config: Arc::clone(&config),
-			ext_url: format!("{}/yt", ext_url),
-			recv,
-			client,
-		};
-		announcer.spawn();
+		// The receiver outlives the announcer so it can be restarted after a crash
+		let recv = Arc::new(AsyncMutex::new(recv));
+		let ext_url = format!("{}/yt", ext_url);
+		let tracker = supervisor.tracker();
+		let poller_storage = storage.clone();
+		let announcer_config = Arc::clone(&config);
+		let announcer_secrets = Arc::clone(&secrets);
+		let announcer_unsubscribing = Arc::clone(&unsubscribing);
+		let announcer_status = Arc::clone(&status);
+		let announcer_configured = Arc::clone(&configured);
+		supervisor.spawn("youtube announcer", move |_| {
+			let announcer = Announcer {
+				config: Arc::clone(&announcer_config),
+				secrets: Arc::clone(&announcer_secrets),
+				unsubscribing: Arc::clone(&announcer_unsubscribing),
+				status: Arc::clone(&announcer_status),
+				configured: Arc::clone(&announcer_configured),
+				ext_url: ext_url.clone(),
+				recv: Arc::clone(&recv),
+				client: client.clone(),
+				storage: storage.clone(),
+				tracker: tracker.clone(),
+			};
+			announcer.run()
+		});
 
-		Self { config, sender }
+		let poller_config = Arc::clone(&config);
+		let poller_sender = sender.clone();
+		supervisor.spawn("youtube poller", move |shutdown| {
+			let poller = Poller {
+				config: Arc::clone(&poller_config),
+				sender: poller_sender.clone(),
+				storage: poller_storage.clone(),
+			};
+			poller.run(shutdown)
+		});
+
+		Ok(Self {
+			config,
+			secrets,
+			unsubscribing,
+			status,
+			sender,
+			configured,
+		})
 	}
 
 	pub fn routes(&self) -> BoxedFilter<(impl Reply,)> {
 		let config = Arc::clone(&self.config);
+		let status_config = Arc::clone(&self.config);
+		let secrets = Arc::clone(&self.secrets);
+		let unsubscribing = Arc::clone(&self.unsubscribing);
+		let status = Arc::clone(&self.status);
 		let sender = self.sender.clone();
 		let sender2 = self.sender.clone();
+		let rejected = Arc::new(Rejected::default());
 
 		let post = warp::path("yt")
 			.and(warp::post())
+			.and(warp::header::optional::<String>("x-hub-signature"))
 			.and(warp::body::content_length_limit(1024 * 32))
 			.and(warp::body::bytes())
-			.map(move |bytes| http_post(&sender, bytes).unwrap_or(StatusCode::BAD_REQUEST));
+			.map(move |signature, bytes| {
+				http_post(&secrets, &sender, &rejected, signature, bytes)
+					.unwrap_or(StatusCode::BAD_REQUEST)
+			});
 		let get = warp::path("yt")
 			.and(warp::get())
 			.and(warp::query::<HashMap<String, String>>())
 			.map(move |query| {
-				http_get(&config, &sender2, query).unwrap_or(Box::new(StatusCode::BAD_REQUEST))
+				http_get(&config, &unsubscribing, &sender2, query)
+					.unwrap_or(Box::new(StatusCode::BAD_REQUEST))
+			});
+		let status = warp::path!("yt" / "status")
+			.and(warp::get())
+			.and(warp::query::<HashMap<String, String>>())
+			.map(move |query: HashMap<String, String>| {
+				let token = status_config.lock().unwrap().status_token.clone();
+				match (token, query.get("token")) {
+					(Some(token), Some(given)) if &token == given => {
+						let reply = warp::reply::json(&*status.lock().unwrap());
+						Box::new(reply) as Box<dyn Reply>
+					}
+					_ => Box::new(StatusCode::NOT_FOUND),
+				}
 			});
-		post.or(get).boxed()
+		status.or(post).or(get).boxed()
 	}
 }
 
 impl EventHandler for Youtube {
 	fn config(&mut self, _guild: &Guild, name: &str, config: Value) -> Option<Value> {
-		let config = load_config!(name, "youtube", config);
+		let config: YoutubeConfig = load_config!(name, "youtube", config);
+		if let Err(e) = config.validate() {
+			warn!("Invalid youtube config: {:#}", e);
+			return None;
+		}
 		let mut inner = self.config.lock().unwrap();
 		let old = mem::replace(inner.deref_mut(), config);
 		if old.enabled != inner.enabled {
@@ -186,8 +528,11 @@ impl EventHandler for Youtube {
 			info!("Config updated");
 		}
 
-		// Signal the announcer to update subscriptions
-		if inner.enabled {
+		// Signal the announcer to update subscriptions. Also when the module has just been
+		// disabled, so it unsubscribes from everything, and for the first config, so leases
+		// restored from a previous run are cleaned up when the module starts out disabled
+		let first = !mem::replace(self.configured.lock().unwrap().deref_mut(), true);
+		if first || inner.enabled || old.enabled {
 			let _ = self.sender.try_send(Event::UpdateSubscriptions);
 		}
 
@@ -204,14 +549,84 @@ pub enum Event {
 	Publication(Publication),
 	UpdateSubscriptions,
 	Subscribed(YoutubeChannel, u64),
+	Unsubscribed(YoutubeChannel),
 	SubscriptionDenied(YoutubeChannel, Option<String>),
+	// Summary of deliveries with an invalid signature
+	Rejected(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum LeaseState {
+	// Waiting for the hub to verify the subscription, until the deadline
+	Pending(Instant),
+	// Subscribed, to be renewed at the given time
+	Subscribed(Instant),
+	// Subscribing failed, to be retried at the given time
+	Retry(Instant),
+}
+
+#[derive(Debug)]
+struct Lease {
+	state: LeaseState,
+	// Consecutive failures, used for the backoff
+	failures: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LeaseStatus {
+	state: &'static str,
+	// Verification deadline, renewal or retry, depending on the state
+	next: DateTime<Utc>,
+	failures: u32,
+}
+
+// Undo recording an announcement that couldn't be sent
+async fn restore_announcement(
+	storage: &Storage,
+	yt_id: &YoutubeId,
+	previous: Option<Announcement>,
+) -> Result<()> {
+	let statement = match previous {
+		Some(a) => query(
+			"UPDATE youtube_announcements SET kind = $1, updated_timestamp = $2 WHERE yt_id = $3",
+		)
+		.bind(a.kind.as_str())
+		.bind(a.updated)
+		.bind(yt_id.to_string()),
+		None => query("DELETE FROM youtube_announcements WHERE yt_id = $1").bind(yt_id.to_string()),
+	};
+	statement.execute(&**storage).await?;
+	Ok(())
+}
+
+// Schedule a retry for a failed subscription, returning the backoff
+fn retry(leases: &mut HashMap<YoutubeChannel, Lease>, yt_channel: &YoutubeChannel) -> Duration {
+	let now = Instant::now();
+	let lease = leases.entry(yt_channel.clone()).or_insert(Lease {
+		state: LeaseState::Retry(now),
+		failures: 0,
+	});
+	let backoff = BACKOFF_MIN
+		.saturating_mul(2u32.saturating_pow(lease.failures))
+		.min(BACKOFF_MAX);
+	lease.failures += 1;
+	lease.state = LeaseState::Retry(now + backoff);
+	backoff
 }
 
 struct Announcer {
 	config: Arc<Mutex<YoutubeConfig>>,
+	secrets: Secrets,
+	unsubscribing: Unsubscribing,
+	status: Status,
+	// Subscriptions are left alone until the first config has been received. Until then the
+	// default config is in place, which would unsubscribe from every restored lease
+	configured: Arc<Mutex<bool>>,
 	ext_url: String,
-	recv: mpsc::Receiver<Event>,
+	recv: Arc<AsyncMutex<mpsc::Receiver<Event>>>,
 	client: Client,
+	storage: Storage,
+	tracker: Tracker,
 }
 
 impl Announcer {
@@ -221,7 +636,7 @@ impl Announcer {
 			None => return,
 		};
 		let client = self.client.clone();
-		tokio::spawn(async move {
+		self.tracker.spawn(async move {
 			if let Err(e) = client
 				.create_message(channel_id)
 				.content(message)
@@ -233,33 +648,192 @@ impl Announcer {
 		});
 	}
 
-	async fn run(mut self) {
-		// Store most recent announcements to avoid duplicates
-		let mut history = Buffer::new(10);
-		// Subscribed channels with their expiration time
-		let mut subscribed = HashMap::<YoutubeChannel, Instant>::new();
-		// Pending subscriptions
-		let mut pending = HashMap::<YoutubeChannel, Instant>::new();
-		let day = Duration::from_secs(24 * 60 * 60);
-		// Timer to trigger resubscribing. Use dummy timer at start
-		let mut timer = Box::pin(sleep(day).fuse());
-		let mut subscribing = false;
-		let subscriber = Subscriber::new(self.ext_url.clone()).unwrap(); // TODO: remove unwrap
+	/// Secret for the subscription to `yt_channel`, generated and stored the first time
+	async fn secret(&self, yt_channel: &YoutubeChannel) -> Result<String> {
+		let secret = self.secrets.lock().unwrap().get(yt_channel).cloned();
+		if let Some(secret) = secret {
+			return Ok(secret);
+		}
+
+		let secret = hex::encode(rand::random::<[u8; 32]>());
+		query("INSERT INTO youtube_secrets (yt_channel, secret) VALUES ($1, $2)")
+			.bind(yt_channel.to_string())
+			.bind(&secret)
+			.execute(&*self.storage)
+			.await?;
+		self.secrets
+			.lock()
+			.unwrap()
+			.insert(yt_channel.clone(), secret.clone());
+		Ok(secret)
+	}
+
+	/// Leases that haven't expired yet, with the time they should be renewed
+	async fn leases(&self) -> Result<HashMap<YoutubeChannel, Instant>> {
+		let leases = query_as::<_, (String, i64)>(
+			"SELECT yt_channel, expires_timestamp FROM youtube_leases",
+		)
+		.fetch_all(&*self.storage)
+		.await?;
+
+		let now = Instant::now();
+		let timestamp = Utc::now().timestamp();
+		let leases = leases
+			.into_iter()
+			.filter_map(|(yt_channel, expires)| {
+				let remaining = u64::try_from(expires - timestamp).ok()?;
+				let renew = now + Duration::from_secs(remaining.saturating_sub(RENEW_MARGIN));
+				Some((YoutubeChannel::from(yt_channel), renew))
+			})
+			.collect();
+		Ok(leases)
+	}
+
+	async fn store_lease(&self, yt_channel: &YoutubeChannel, lease_seconds: u64) -> Result<()> {
+		let expires = Utc::now().timestamp() + i64::try_from(lease_seconds)?;
+		query(
+			"INSERT INTO youtube_leases (yt_channel, expires_timestamp) VALUES ($1, $2) \
+			ON CONFLICT (yt_channel) DO UPDATE SET expires_timestamp = excluded.expires_timestamp",
+		)
+		.bind(yt_channel.to_string())
+		.bind(expires)
+		.execute(&*self.storage)
+		.await?;
+		Ok(())
+	}
+
+	/// Publish the state of the leases, returning when the next one needs attention
+	fn update_status(&self, leases: &HashMap<YoutubeChannel, Lease>) -> Instant {
+		let now = Instant::now();
+		let utc_now = Utc::now();
+		let mut next = now + Duration::from_secs(24 * 60 * 60);
+		let mut status = self.status.lock().unwrap();
+		status.clear();
+		for (yt_channel, lease) in leases {
+			let (state, instant) = match lease.state {
+				LeaseState::Pending(i) => ("pending", i),
+				LeaseState::Subscribed(i) => ("subscribed", i),
+				LeaseState::Retry(i) => ("retrying", i),
+			};
+			next = next.min(instant);
+			let remaining = chrono::Duration::from_std(instant.saturating_duration_since(now))
+				.unwrap_or_else(|_| chrono::Duration::zero());
+			status.insert(
+				yt_channel.clone(),
+				LeaseStatus {
+					state,
+					next: utc_now + remaining,
+					failures: lease.failures,
+				},
+			);
+		}
+		next
+	}
+
+	async fn unsubscribe(
+		&self,
+		subscriber: &Subscriber,
+		hub_url: &str,
+		yt_channel: &YoutubeChannel,
+	) {
+		debug!("Unsubscribing from '{}'", yt_channel);
+		self.unsubscribing
+			.lock()
+			.unwrap()
+			.insert(yt_channel.clone());
+		if let Err(e) = query("DELETE FROM youtube_leases WHERE yt_channel = $1")
+			.bind(yt_channel.to_string())
+			.execute(&*self.storage)
+			.await
+		{
+			warn!("Unable to remove lease for '{}': {}", yt_channel, e);
+		}
+
+		if let Err(e) = subscriber.unsubscribe(hub_url, yt_channel).await {
+			warn!("Unable to unsubscribe from '{}': {}", yt_channel, e);
+			self.log(format!(
+				"Unable to unsubscribe from `{}`:\n```{}```",
+				yt_channel, e
+			));
+		}
+	}
+
+	/// Last announcement of `yt_id`, if it has been announced before
+	async fn announced(&self, yt_id: &YoutubeId) -> Result<Option<Announcement>> {
+		let announcement = query_as::<_, (String, Option<i64>)>(
+			"SELECT kind, updated_timestamp FROM youtube_announcements WHERE yt_id = $1",
+		)
+		.bind(yt_id.to_string())
+		.fetch_optional(&*self.storage)
+		.await?;
+		Ok(announcement.and_then(|(kind, updated)| {
+			let kind = kind.parse().ok()?;
+			Some(Announcement { kind, updated })
+		}))
+	}
+
+	async fn record_announcement(&self, publication: &Publication, kind: VideoKind) -> Result<()> {
+		query(
+			"INSERT INTO youtube_announcements \
+			(yt_id, yt_channel, created_timestamp, kind, updated_timestamp) \
+			VALUES ($1, $2, $3, $4, $5) ON CONFLICT (yt_id) DO UPDATE \
+			SET kind = excluded.kind, updated_timestamp = excluded.updated_timestamp",
+		)
+		.bind(publication.yt_id.to_string())
+		.bind(publication.yt_channel.to_string())
+		.bind(Utc::now().timestamp())
+		.bind(kind.as_str())
+		.bind(publication.updated.timestamp())
+		.execute(&*self.storage)
+		.await?;
+		Ok(())
+	}
+
+	async fn run(self) -> Result<()> {
+		// Hold on to the receiver for as long as we're running. Stops once all senders are gone
+		let recv = Arc::clone(&self.recv);
+		let mut recv = recv.lock().await;
+		// Subscription state of every channel, restored from previous runs
+		let mut leases = self
+			.leases()
+			.await?
+			.into_iter()
+			.map(|(yt_channel, renew)| {
+				let lease = Lease {
+					state: LeaseState::Subscribed(renew),
+					failures: 0,
+				};
+				(yt_channel, lease)
+			})
+			.collect::<HashMap<_, _>>();
+		if !leases.is_empty() {
+			info!("Restored {} subscription leases", leases.len());
+		}
+		// Timer to trigger resubscribing
+		let mut timer = Box::pin(sleep_until(self.update_status(&leases)).fuse());
+		let subscriber = Subscriber::new(self.ext_url.clone())?;
 		let timeout = Duration::from_secs(30);
 		loop {
 			let item = select! {
-				i = self.recv.next().fuse() => match i {
+				i = recv.next().fuse() => match i {
 					Some(i) => i,
 					None => break,
 				},
-				_ = timer => Event::UpdateSubscriptions,
+				_ = timer => {
+					if !*self.configured.lock().unwrap() {
+						// The first config triggers an update
+						continue;
+					}
+					Event::UpdateSubscriptions
+				}
 			};
 
+			let verified = matches!(item, Event::Subscribed(..) | Event::SubscriptionDenied(..));
 			match item {
 				Event::Publication(p) => {
-					let channel_id;
-					let title;
-					let content;
+					let subscription;
+					let api_key;
+					let max_age;
 
 					{
 						// Encapsulate the guard so the `Future` stays `Send`able
@@ -268,15 +842,9 @@ impl Announcer {
 							// Module is disabled: skip
 							continue;
 						} else if let Some(s) = inner.subscriptions.get(&p.yt_channel) {
-							if history.contains(&p.yt_id) {
-								// Duplicate announcement: skip
-								continue;
-							}
-							// Announce
-							channel_id = s.channel_id;
-							title = p.title;
-							content = s.text.replace("%ID%", &p.yt_id);
-							history.insert(p.yt_id);
+							subscription = s.clone();
+							api_key = inner.api_key.clone();
+							max_age = inner.max_age;
 						} else {
 							// We're not subscribed to this channel: skip
 							info!(
@@ -287,126 +855,428 @@ impl Announcer {
 						}
 					}
 
+					let previous = match self.announced(&p.yt_id).await {
+						Ok(previous) => previous,
+						Err(e) => {
+							// Better to risk a duplicate than to miss an announcement
+							warn!("Unable to look up announcement for '{}': {}", p.yt_id, e);
+							None
+						}
+					};
+					let previous_kind = previous.map(|a| a.kind);
+					let is_new = (Utc::now() - p.published).num_seconds() < max_age;
+					// Redeliveries of updates we've announced already are skipped
+					let is_update = !is_new
+						&& subscription.update_text.is_some()
+						&& previous
+							.and_then(|a| a.updated)
+							.map_or(true, |u| p.updated.timestamp() > u);
+
+					// Skip duplicates before spending API quota on them, unless a premiere or
+					// stream may have gone live since
+					let api_key = api_key.filter(|_| subscription.needs_details());
+					let may_go_live = api_key.is_some()
+						&& matches!(previous_kind, None | Some(VideoKind::Upcoming));
+					if !may_go_live && !is_update && (!is_new || previous.is_some()) {
+						if is_new {
+							debug!("Skipping announcement for '{}': already announced", p.yt_id);
+						} else {
+							debug!("Skipping update of '{}' at {}", p.yt_id, p.updated);
+						}
+						continue;
+					}
+
+					let details = match &api_key {
+						Some(api_key) => match subscriber.video_details(api_key, &p.yt_id).await {
+							Ok(details) => Some(details),
+							Err(e) => {
+								warn!("Unable to look up '{}': {}", p.yt_id, e);
+								None
+							}
+						},
+						None => None,
+					};
+					let kind = details.as_ref().map_or(VideoKind::Video, |d| d.kind);
+
+					let template =
+						if kind == VideoKind::Live && previous_kind != Some(VideoKind::Live) {
+							// Stream went live, possibly after announcing it as upcoming
+							Some(subscription.template(kind))
+						} else if is_new {
+							// Skip duplicate announcements
+							previous.is_none().then(|| subscription.template(kind))
+						} else if is_update {
+							subscription.update_text.as_deref()
+						} else {
+							None
+						};
+					let template = match template {
+						Some(t) => t,
+						None if is_new => {
+							debug!("Skipping announcement for '{}': already announced", p.yt_id);
+							continue;
+						}
+						None => {
+							debug!("Skipping update of '{}' at {}", p.yt_id, p.updated);
+							continue;
+						}
+					};
+
+					let filter = &subscription.filter;
+					let mut skip = filter.check_title(&p.title);
+					if let (Some(min), Some(d), None) = (filter.min_duration, &details, &skip) {
+						// Premieres and streams don't have a duration until they're over
+						if kind == VideoKind::Video && d.duration < Duration::from_secs(min) {
+							skip = Some(format!("Shorter than {}s", min));
+						}
+					}
+					if filter.exclude_shorts && skip.is_none() {
+						match subscriber.is_short(&p.yt_id).await {
+							Ok(true) => skip = Some("Short".to_owned()),
+							Ok(false) => {}
+							// Rather announce a short than miss a video
+							Err(e) => {
+								warn!("Unable to check whether '{}' is a short: {}", p.yt_id, e)
+							}
+						}
+					}
+
+					// Recorded before sending, so redeliveries in the meantime aren't announced
+					// twice, and rolled back if sending fails. Skipped videos are recorded as well,
+					// so later updates don't trigger another check, or get announced after all
+					if let Err(e) = self.record_announcement(&p, kind).await {
+						warn!("Unable to record announcement for '{}': {}", p.yt_id, e);
+					}
+
+					if let Some(reason) = skip {
+						info!("Skipping announcement for '{}': {}", p.title, reason);
+						self.log(format!(
+							"Skipped `{}` from `{}`:\n```{}```",
+							p.title, p.channel_name, reason
+						));
+						continue;
+					}
+
+					// Announce
+					let url = format!("{}{}", WATCH_URL, p.yt_id);
+					let thumbnail = format!("{}{}/hqdefault.jpg", THUMBNAIL_URL, p.yt_id);
+					let role = subscription
+						.role
+						.map(|r| format!("<@&{}>", r))
+						.unwrap_or_default();
+					let values = Values::from([
+						("ID", &*p.yt_id),
+						("TITLE", &p.title),
+						("CHANNEL", &p.channel_name),
+						("URL", &url),
+						("THUMBNAIL", &thumbnail),
+						("ROLE", &role),
+					]);
+					let content = template::render(template, &values);
+					let embed = subscription.embed.as_ref().map(|e| e.render(&values));
+					// Only ping the configured role, regardless of what the title contains
+					let mentions = match subscription.role {
+						Some(role) => AllowedMentions::none().roles(vec![role]),
+						None => AllowedMentions::none(),
+					};
+
+					let channel_id = subscription.channel_id;
+					let yt_id = p.yt_id;
+					let title = p.title;
 					let client = self.client.clone();
-					tokio::spawn(async move {
-						match client
+					let storage = self.storage.clone();
+					self.tracker.spawn(async move {
+						let mut message = client
 							.create_message(channel_id)
 							.content(content)
-							.send()
-							.await
-						{
+							.allowed_mentions(mentions);
+						if let Some(embed) = embed {
+							message = message.embed(embed);
+						}
+						match message.send().await {
 							Ok(_) => info!("Announced '{}'", title),
-							Err(e) => warn!("Failed to announce '{}': {}", title, e),
+							Err(e) => {
+								warn!("Failed to announce '{}': {}", title, e);
+								// Let a redelivery or the poller announce it again
+								let res = restore_announcement(&storage, &yt_id, previous).await;
+								if let Err(e) = res {
+									warn!("Unable to restore announcement for '{}': {}", yt_id, e);
+								}
+							}
 						}
 					});
 				}
 				Event::UpdateSubscriptions => {
 					let now = Instant::now();
-
-					// Check for any expirations
-					subscribed.retain(|_, instant| *instant > now);
-					pending.retain(|yt_channel, instant| {
-						let retain = *instant > now;
-						if !retain {
-							warn!(
-								"Unable to subscribe to '{}': Validation timed out",
-								yt_channel
-							);
-							self.log(format!(
-								"Unable to subscribe to `{}`:\n```Validation timed out```",
-								yt_channel
-							))
-						}
-						retain
-					});
-
-					// Check for a (re)subscription
-					let to_subscribe;
+					let hub_url;
+					let concurrency;
+					let wanted;
 					{
 						// Encapsulate the guard to keep the `Future` `Send`able
 						let inner = self.config.lock().unwrap();
-						if !inner.enabled {
-							// Module is disabled: do nothing
-							continue;
+						hub_url = inner.hub_url.clone();
+						concurrency = inner.concurrency.max(1);
+						wanted = if inner.enabled {
+							inner.subscriptions.keys().cloned().collect()
+						} else {
+							// Module is disabled: unsubscribe from everything
+							Vec::new()
+						};
+					}
+
+					// Unsubscribe from channels that are no longer configured
+					let removed = leases
+						.keys()
+						.filter(|k| !wanted.contains(*k))
+						.cloned()
+						.collect::<Vec<_>>();
+					for yt_channel in removed {
+						let lease = leases.remove(&yt_channel);
+						if !matches!(
+							lease,
+							Some(Lease {
+								state: LeaseState::Retry(_),
+								..
+							})
+						) {
+							self.unsubscribe(&subscriber, &hub_url, &yt_channel).await;
 						}
+					}
 
-						to_subscribe = inner
-							.subscriptions
-							.keys()
-							.filter(|k| !subscribed.contains_key(*k) && !pending.contains_key(*k))
-							.map(|k| k.clone())
-							.next();
+					// Check for verifications that timed out
+					let timed_out = leases
+						.iter()
+						.filter(|(_, l)| matches!(l.state, LeaseState::Pending(d) if d <= now))
+						.map(|(k, _)| k.clone())
+						.collect::<Vec<_>>();
+					for yt_channel in timed_out {
+						let backoff = retry(&mut leases, &yt_channel);
+						warn!(
+							"Unable to subscribe to '{}': Validation timed out, retrying in {}s",
+							yt_channel,
+							backoff.as_secs()
+						);
+						self.log(format!(
+							"Unable to subscribe to `{}`:\n```Validation timed out```",
+							yt_channel
+						));
 					}
 
-					// Attempt to subscribe to one of the channels
-					subscribing = to_subscribe.is_some();
-					if let Some(yt_channel) = to_subscribe {
+					// Subscribe to new channels and renew or retry the ones that are due,
+					// with a limited number of verifications pending at the same time
+					let pending = leases
+						.values()
+						.filter(|l| matches!(l.state, LeaseState::Pending(_)))
+						.count();
+					let due = wanted
+						.into_iter()
+						.filter(|k| match leases.get(k).map(|l| l.state) {
+							None => true,
+							Some(LeaseState::Pending(_)) => false,
+							Some(LeaseState::Subscribed(i)) | Some(LeaseState::Retry(i)) => {
+								i <= now
+							}
+						})
+						.take(concurrency.saturating_sub(pending))
+						.collect::<Vec<_>>();
+
+					let this = &self;
+					let (hub_url, subscriber) = (&hub_url, &subscriber);
+					let results = future::join_all(due.iter().map(|yt_channel| async move {
 						debug!("Subscribing to '{}'", yt_channel);
-						match subscriber.subscribe(&yt_channel).await {
+						this.unsubscribing.lock().unwrap().remove(yt_channel);
+						let secret = this.secret(yt_channel).await?;
+						subscriber.subscribe(hub_url, yt_channel, &secret).await
+					}))
+					.await;
+
+					for (yt_channel, res) in due.into_iter().zip(results) {
+						match res {
 							Ok(_) => {
-								pending.insert(yt_channel, now + timeout);
+								let lease = leases.entry(yt_channel).or_insert(Lease {
+									state: LeaseState::Pending(now),
+									failures: 0,
+								});
+								lease.state = LeaseState::Pending(now + timeout);
 							}
 							Err(e) => {
-								warn!("Unable to subscribe to '{}': {}", yt_channel, e);
+								let backoff = retry(&mut leases, &yt_channel);
+								warn!(
+									"Unable to subscribe to '{}': {}, retrying in {}s",
+									yt_channel,
+									e,
+									backoff.as_secs()
+								);
 								self.log(format!(
 									"Unable to subscribe to `{}`:\n```{}```",
 									yt_channel, e
 								));
 							}
 						}
-						timer = Box::pin(sleep(timeout).fuse());
-						// Only one subscription at a time
-						continue;
 					}
-
-					// Update the timer to the earliest expiration
-					timer = first!(pending, subscribed);
 				}
 				Event::Subscribed(yt_channel, lease_seconds) => {
 					info!("Subscribed to '{}'", yt_channel);
-					pending.remove(&yt_channel);
-					// Renew subscription 10 minutes before expiration
-					subscribed.insert(
+					if let Err(e) = self.store_lease(&yt_channel, lease_seconds).await {
+						warn!("Unable to store lease for '{}': {}", yt_channel, e);
+					}
+					// Renew subscription shortly before expiration
+					let renew = Instant::now()
+						+ Duration::from_secs(lease_seconds.saturating_sub(RENEW_MARGIN));
+					leases.insert(
 						yt_channel,
-						Instant::now() + Duration::from_secs(lease_seconds.saturating_sub(600)),
+						Lease {
+							state: LeaseState::Subscribed(renew),
+							failures: 0,
+						},
 					);
-
-					if !subscribing {
-						timer = first!(pending, subscribed);
-					}
+				}
+				Event::Unsubscribed(yt_channel) => {
+					info!("Unsubscribed from '{}'", yt_channel);
+					self.log(format!("Unsubscribed from `{}`", yt_channel));
 				}
 				Event::SubscriptionDenied(yt_channel, reason) => {
-					pending.remove(&yt_channel);
-					timer = Box::pin(sleep(timeout).fuse());
+					let backoff = retry(&mut leases, &yt_channel);
 					match reason {
 						Some(reason) => {
-							warn!("Subscription to '{}' denied: {}", yt_channel, reason);
+							warn!(
+								"Subscription to '{}' denied: {}, retrying in {}s",
+								yt_channel,
+								reason,
+								backoff.as_secs()
+							);
 							self.log(format!(
 								"Subscription to `{}` denied:\n```{}```",
 								yt_channel, reason
 							));
 						}
 						None => {
-							warn!("Subscription to '{}' denied", yt_channel);
+							warn!(
+								"Subscription to '{}' denied, retrying in {}s",
+								yt_channel,
+								backoff.as_secs()
+							);
 							self.log(format!("Subscription to `{}` denied", yt_channel));
 						}
 					}
 				}
+				Event::Rejected(summary) => self.log(summary),
+			}
+
+			// Let the status view and the timer reflect the new state. Check again right away
+			// after a verification, since a slot opened up for channels waiting to subscribe
+			let next = self.update_status(&leases);
+			timer = Box::pin(sleep_until(if verified { Instant::now() } else { next }).fuse());
+		}
+
+		Ok(())
+	}
+}
+
+// Fetches the feeds of all subscribed channels periodically, to catch uploads we
+// haven't been notified about
+struct Poller {
+	config: Arc<Mutex<YoutubeConfig>>,
+	sender: mpsc::Sender<Event>,
+	storage: Storage,
+}
+
+impl Poller {
+	async fn run(mut self, mut shutdown: Shutdown) -> Result<()> {
+		let client = reqwest::ClientBuilder::new()
+			.timeout(Duration::from_secs(10))
+			.use_rustls_tls()
+			.build()?;
+
+		loop {
+			let interval;
+			let feed_url;
+			let max_age;
+			let channels;
+			{
+				let inner = self.config.lock().unwrap();
+				interval = match inner.poll_interval {
+					Some(i) if inner.enabled => Duration::from_secs(i.max(60)),
+					// Check again later whether polling has been enabled
+					_ => Duration::from_secs(60),
+				};
+				feed_url = inner.feed_url.clone();
+				max_age = inner.max_age;
+				channels = if inner.enabled && inner.poll_interval.is_some() {
+					inner.subscriptions.keys().cloned().collect()
+				} else {
+					Vec::new()
+				};
+			}
+
+			for yt_channel in channels {
+				let url = format!("{}{}", feed_url, yt_channel);
+				if let Err(e) = self.poll(&client, &url, &yt_channel, max_age).await {
+					warn!("Unable to poll feed of '{}': {}", yt_channel, e);
+				}
+			}
+
+			tokio::select! {
+				_ = sleep(interval) => {}
+				_ = shutdown.wait() => break,
 			}
 		}
+
+		Ok(())
 	}
 
-	fn spawn(self) {
-		tokio::spawn(self.run());
+	async fn poll(
+		&mut self,
+		client: &reqwest::Client,
+		url: &str,
+		yt_channel: &YoutubeChannel,
+		max_age: i64,
+	) -> Result<()> {
+		let bytes = client
+			.get(url)
+			.send()
+			.await?
+			.error_for_status()?
+			.bytes()
+			.await?;
+		let publications = Publication::parse_all(std::str::from_utf8(&bytes)?)
+			.map_err(|e| anyhow!("Invalid feed: {:?}", e))?;
+
+		let announced = query_as::<_, (String,)>(
+			"SELECT yt_id FROM youtube_announcements WHERE yt_channel = $1",
+		)
+		.bind(yt_channel.to_string())
+		.fetch_all(&*self.storage)
+		.await?
+		.into_iter()
+		.map(|(yt_id,)| yt_id)
+		.collect::<HashSet<_>>();
+
+		// Only pass on recent uploads, the announcer takes care of any duplicates
+		let now = Utc::now();
+		for p in publications {
+			if announced.contains(&*p.yt_id) || (now - p.published).num_seconds() >= max_age {
+				continue;
+			}
+			debug!("Found '{}' in the feed of '{}'", p.yt_id, yt_channel);
+			self.sender.send(Event::Publication(p)).await?;
+		}
+		Ok(())
 	}
 }
 
 const HUB_URL: &str = "https://pubsubhubbub.appspot.com/subscribe";
 const TOPIC_URL: &str = "https://www.youtube.com/xml/feeds/videos.xml?channel_id=";
+const WATCH_URL: &str = "https://www.youtube.com/watch?v=";
+const SHORTS_URL: &str = "https://www.youtube.com/shorts/";
+const THUMBNAIL_URL: &str = "https://i.ytimg.com/vi/";
+const VIDEOS_URL: &str = "https://www.googleapis.com/youtube/v3/videos";
 
 struct Subscriber {
 	ext_url: String,
 	client: reqwest::Client,
+	// Client that doesn't follow redirects
+	no_redirect: reqwest::Client,
 }
 
 impl Subscriber {
@@ -415,53 +1285,101 @@ impl Subscriber {
 			.timeout(Duration::from_secs(10))
 			.use_rustls_tls()
 			.build()?;
-		Ok(Self { ext_url, client })
+		let no_redirect = reqwest::ClientBuilder::new()
+			.timeout(Duration::from_secs(10))
+			.redirect(reqwest::redirect::Policy::none())
+			.use_rustls_tls()
+			.build()?;
+		Ok(Self {
+			ext_url,
+			client,
+			no_redirect,
+		})
 	}
 
-	async fn subscribe(&self, channel: &YoutubeChannel) -> Result<(), reqwest::Error> {
+	async fn subscribe(&self, hub_url: &str, channel: &YoutubeChannel, secret: &str) -> Result<()> {
 		let topic = format!("{}{}", TOPIC_URL, channel);
-		let form = [
-			("hub.mode", "subscribe"),
-			("hub.topic", &topic),
-			("hub.callback", &self.ext_url),
-		];
-		self.client.post(HUB_URL).form(&form).send().await?;
-		Ok(())
+		websub::subscribe(&self.client, hub_url, &topic, &self.ext_url, secret).await
 	}
-}
 
-// Fixed size FIFO buffer
-// TODO: replace with https://github.com/NULLx76/ringbuffer/
-struct Buffer<T> {
-	capacity: usize,
-	inner: VecDeque<T>,
-}
+	async fn unsubscribe(&self, hub_url: &str, channel: &YoutubeChannel) -> Result<()> {
+		let topic = format!("{}{}", TOPIC_URL, channel);
+		websub::unsubscribe(&self.client, hub_url, &topic, &self.ext_url).await
+	}
 
-impl<T> Buffer<T> {
-	fn new(capacity: usize) -> Self {
-		assert!(capacity > 0);
-		Self {
-			capacity,
-			inner: VecDeque::with_capacity(capacity),
-		}
+	/// Look up the duration of a video, and whether it is an upcoming or ongoing
+	/// premiere or live stream
+	async fn video_details(&self, api_key: &str, yt_id: &YoutubeId) -> Result<VideoDetails> {
+		let query = [
+			("part", "snippet,contentDetails"),
+			("id", yt_id),
+			("key", api_key),
+		];
+		let bytes = self
+			.client
+			.get(VIDEOS_URL)
+			.query(&query)
+			.send()
+			.await?
+			.error_for_status()?
+			.bytes()
+			.await?;
+		let videos: Value = serde_json::from_slice(&bytes)?;
+		let video = &videos["items"][0];
+		let kind = video["snippet"]["liveBroadcastContent"]
+			.as_str()
+			.ok_or_else(|| anyhow!("Video not found"))?;
+		let kind = kind
+			.parse()
+			.map_err(|_| anyhow!("Unknown broadcast content '{}'", kind))?;
+		let duration = video["contentDetails"]["duration"]
+			.as_str()
+			.and_then(parse_duration)
+			.ok_or_else(|| anyhow!("Invalid duration"))?;
+		Ok(VideoDetails { kind, duration })
 	}
 
-	fn insert(&mut self, value: T) -> Option<T> {
-		let pop = if self.inner.len() == self.capacity {
-			self.inner.pop_front()
+	/// Shorts can be opened on their own page, other videos are redirected. Errors
+	/// are treated as "not a short" by the announcer
+	async fn is_short(&self, yt_id: &YoutubeId) -> Result<bool> {
+		let res = self
+			.no_redirect
+			.head(format!("{}{}", SHORTS_URL, yt_id))
+			.send()
+			.await?;
+		if res.status().is_redirection() {
+			Ok(false)
 		} else {
-			None
-		};
-		self.inner.push_back(value);
-		pop
+			Ok(res.error_for_status().is_ok())
+		}
 	}
+}
+
+#[derive(Debug)]
+struct VideoDetails {
+	kind: VideoKind,
+	duration: Duration,
+}
 
-	fn contains(&self, x: &T) -> bool
-	where
-		T: PartialEq<T>,
-	{
-		self.inner.contains(x)
+// Parse an ISO 8601 duration as used by the YouTube API, like `PT1H2M3S`
+fn parse_duration(s: &str) -> Option<Duration> {
+	let s = s.strip_prefix('P')?;
+	let (date, time) = s.split_once('T').unwrap_or((s, ""));
+	let date = parse_units(date, &[('W', 7 * 24 * 60 * 60), ('D', 24 * 60 * 60)])?;
+	let time = parse_units(time, &[('H', 60 * 60), ('M', 60), ('S', 1)])?;
+	Some(Duration::from_secs(date + time))
+}
+
+// Sum of the values followed by a unit, in the given order
+fn parse_units(mut s: &str, units: &[(char, u64)]) -> Option<u64> {
+	let mut secs = 0;
+	for (unit, factor) in units {
+		if let Some((value, rest)) = s.split_once(*unit) {
+			secs += value.parse::<u64>().ok()? * factor;
+			s = rest;
+		}
 	}
+	s.is_empty().then_some(secs)
 }
 
 const BASE_NS: &str = "http://www.w3.org/2005/Atom";
@@ -472,7 +1390,7 @@ pub enum PubError {
 	InvalidXml,
 	MissingChild(&'static str),
 	MissingChildInner(&'static str),
-	// InvalidDateTime,
+	InvalidDateTime,
 }
 
 #[derive(Debug)]
@@ -480,41 +1398,61 @@ pub struct Publication {
 	title: String,
 	yt_id: YoutubeId,
 	yt_channel: YoutubeChannel,
-	// published: DateTime<Utc>,
-	// updated: DateTime<Utc>,
+	channel_name: String,
+	published: DateTime<Utc>,
+	updated: DateTime<Utc>,
 }
 
-impl FromStr for Publication {
-	type Err = PubError;
-
-	fn from_str(s: &str) -> Result<Self, Self::Err> {
+impl Publication {
+	/// Parse every entry of a feed
+	pub fn parse_all(s: &str) -> Result<Vec<Self>, PubError> {
 		let root: minidom::Element = s.parse().map_err(|_| PubError::InvalidXml)?;
-		let entry = root
-			.get_child("entry", BASE_NS)
-			.ok_or_else(|| PubError::MissingChild("entry"))?;
+		root.children()
+			.filter(|c| c.is("entry", BASE_NS))
+			.map(Self::from_entry)
+			.collect()
+	}
 
+	fn from_entry(entry: &minidom::Element) -> Result<Self, PubError> {
 		let title = entry_text(entry, "title", BASE_NS)?.into();
 		let yt_id = entry_text(entry, "videoId", YT_NS)?.into();
 		let yt_channel = entry_text(entry, "channelId", YT_NS)?.into();
+		let author = entry
+			.get_child("author", BASE_NS)
+			.ok_or_else(|| PubError::MissingChild("author"))?;
+		let channel_name = entry_text(author, "name", BASE_NS)?.into();
 
-		/*let published = DateTime::parse_from_rfc3339(entry_text(entry, "published", BASE_NS)?)
+		let published = DateTime::parse_from_rfc3339(entry_text(entry, "published", BASE_NS)?)
 			.map_err(|_| PubError::InvalidDateTime)?
 			.with_timezone(&Utc);
 
 		let updated = DateTime::parse_from_rfc3339(entry_text(entry, "updated", BASE_NS)?)
 			.map_err(|_| PubError::InvalidDateTime)?
-			.with_timezone(&Utc);*/
+			.with_timezone(&Utc);
 
 		Ok(Publication {
 			title,
 			yt_id,
 			yt_channel,
-			// published,
-			// updated,
+			channel_name,
+			published,
+			updated,
 		})
 	}
 }
 
+impl FromStr for Publication {
+	type Err = PubError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let root: minidom::Element = s.parse().map_err(|_| PubError::InvalidXml)?;
+		let entry = root
+			.get_child("entry", BASE_NS)
+			.ok_or_else(|| PubError::MissingChild("entry"))?;
+		Self::from_entry(entry)
+	}
+}
+
 // Shorthand function to get inner text of an element
 fn entry_text<'a>(
 	entry: &'a minidom::Element,
@@ -532,46 +1470,79 @@ fn entry_text<'a>(
 // HTTP server
 fn http_get(
 	config: &Arc<Mutex<YoutubeConfig>>,
+	unsubscribing: &Unsubscribing,
 	sender: &mpsc::Sender<Event>,
 	query: HashMap<String, String>,
 ) -> Option<Box<dyn warp::Reply>> {
 	debug!("HTTP GET");
-	let mode = query.get("hub.mode")?;
-	let topic = query.get("hub.topic")?;
+	let verification = Verification::parse(&query)?;
+	let topic = match verification {
+		Verification::Subscribe { topic, .. }
+		| Verification::Unsubscribe { topic, .. }
+		| Verification::Denied { topic, .. } => topic,
+	};
+	let yt_channel = YoutubeChannel::from(topic.strip_prefix(TOPIC_URL)?);
 
-	if !topic.starts_with(TOPIC_URL) {
-		return None;
+	// The channel has been removed from the config, so only confirm if we asked for it
+	if let Verification::Unsubscribe { challenge, .. } = verification {
+		if !unsubscribing.lock().ok()?.remove(&yt_channel) {
+			return Some(Box::new(StatusCode::NOT_FOUND));
+		}
+		let _ = sender.clone().try_send(Event::Unsubscribed(yt_channel));
+		return Some(Box::new(challenge.to_string()));
 	}
-	let yt_channel = YoutubeChannel::from(topic.get(TOPIC_URL.len()..)?);
+
 	if !config.lock().ok()?.subscriptions.contains_key(&yt_channel) {
 		return Some(Box::new(StatusCode::NOT_FOUND));
 	}
 
-	match mode.deref() {
-		"denied" => {
-			let reason = query.get("hub.reason").map(|r| r.to_string());
+	match verification {
+		Verification::Denied { reason, .. } => {
+			let reason = reason.map(|r| r.to_string());
 			let _ = sender
 				.clone()
 				.try_send(Event::SubscriptionDenied(yt_channel, reason));
 			Some(Box::new(StatusCode::OK))
 		}
-		"subscribe" => {
-			let challenge = query.get("hub.challenge")?;
-			let lease_seconds = query.get("hub.lease_seconds")?.parse().ok()?;
+		Verification::Subscribe {
+			challenge,
+			lease_seconds,
+			..
+		} => {
 			let _ = sender
 				.clone()
 				.try_send(Event::Subscribed(yt_channel, lease_seconds));
 			Some(Box::new(challenge.to_string()))
 		}
-		_ => None,
+		Verification::Unsubscribe { .. } => None,
 	}
 }
 
-fn http_post(sender: &mpsc::Sender<Event>, bytes: Bytes) -> Option<StatusCode> {
+fn http_post(
+	secrets: &Secrets,
+	sender: &mpsc::Sender<Event>,
+	rejected: &Rejected,
+	signature: Option<String>,
+	bytes: Bytes,
+) -> Option<StatusCode> {
 	debug!("HTTP POST");
 	let raw = std::str::from_utf8(&bytes).ok()?;
 	let publication = Publication::from_str(raw).ok()?;
-	let _ = sender.clone().try_send(Event::Publication(publication));
+
+	let secret = secrets.lock().ok()?.get(&publication.yt_channel).cloned();
+	let valid = match (secret, signature) {
+		(Some(secret), Some(signature)) => websub::verify_signature(&secret, &signature, &bytes),
+		_ => false,
+	};
+	if valid {
+		let _ = sender.clone().try_send(Event::Publication(publication));
+	} else if let Some(summary) = rejected.reject(&publication.yt_channel) {
+		// Forged deliveries are never queued, so they can't crowd out real ones. Only the
+		// occasional summary is
+		let _ = sender.clone().try_send(Event::Rejected(summary));
+	}
+
+	// Deliveries with a bad signature still have to be acknowledged
 	Some(StatusCode::OK)
 }
 
@@ -579,42 +1550,462 @@ fn http_post(sender: &mpsc::Sender<Event>, bytes: Bytes) -> Option<StatusCode> {
 mod tests {
 	use super::*;
 	use chrono::{TimeZone, Utc};
+	use common::testing::{temp_storage, GuildState, StandIn};
+	use serde_json::json;
+	use warp::http::Method;
+
+	const PUBLICATION: &str = r#"
+		<feed xmlns:yt="http://www.youtube.com/xml/schemas/2015" xmlns="http://www.w3.org/2005/Atom">
+		  <link rel="hub" href="https://pubsubhubbub.appspot.com"/>
+		  <link rel="self" href="https://www.youtube.com/xml/feeds/videos.xml?channel_id=CHANNEL_ID"/>
+		  <title>YouTube video feed</title>
+		  <updated>2015-04-01T19:05:24.552394234+00:00</updated>
+		  <entry>
+			<id>yt:video:VIDEO_ID</id>
+			<yt:videoId>VIDEO_ID</yt:videoId>
+			<yt:channelId>CHANNEL_ID</yt:channelId>
+			<title>Video title</title>
+			<link rel="alternate" href="http://www.youtube.com/watch?v=VIDEO_ID"/>
+			<author>
+			 <name>Channel title</name>
+			 <uri>http://www.youtube.com/channel/CHANNEL_ID</uri>
+			</author>
+			<published>2015-03-06T21:40:57+00:00</published>
+			<updated>2015-03-09T19:05:24.552394234+00:00</updated>
+		  </entry>
+		</feed>
+	"#;
+
+	// Feed with one entry per video id and title
+	fn feed_xml(entries: &[(&str, &str, DateTime<Utc>, DateTime<Utc>)]) -> String {
+		let entries = entries
+			.iter()
+			.map(|(yt_id, title, published, updated)| {
+				format!(
+					r#"<entry>
+						<yt:videoId>{}</yt:videoId>
+						<yt:channelId>UC1</yt:channelId>
+						<title>{}</title>
+						<author><name>Channel</name></author>
+						<published>{}</published>
+						<updated>{}</updated>
+					</entry>"#,
+					yt_id,
+					title,
+					published.to_rfc3339(),
+					updated.to_rfc3339()
+				)
+			})
+			.collect::<String>();
+		format!(
+			r#"<feed xmlns:yt="http://www.youtube.com/xml/schemas/2015" xmlns="http://www.w3.org/2005/Atom">{}</feed>"#,
+			entries
+		)
+	}
+
+	// Let announcements succeed
+	fn accept_messages(stand_in: &StandIn) {
+		let message =
+			GuildState::new(1, 2, "Test")
+				.member(2, "bot", &[])
+				.message(10, 4, 2, "Announcement");
+		stand_in.respond_json(Method::POST, "/channels/4/messages", 200, message);
+	}
+
+	// Module configured with `config`, talking to the stand-in as both Discord and the hub
+	async fn youtube(
+		stand_in: &StandIn,
+		storage: Storage,
+		supervisor: &mut Supervisor,
+		mut config: Value,
+	) -> Youtube {
+		let guild = GuildState::new(1, 2, "Test")
+			.text_channel(4, "videos")
+			.guild(stand_in);
+		let mut youtube = Youtube::new(stand_in.client(), storage, "http://localhost", supervisor)
+			.await
+			.unwrap();
+		accept_messages(stand_in);
+		config["hub_url"] = json!(format!("{}/hub", stand_in.url()));
+		assert!(youtube.config(&guild, "youtube", config).is_none());
+		youtube
+	}
+
+	// Forms of the requests to the hub once `count` requests arrived, clearing the record.
+	// Waits a little longer to catch unexpected ones
+	async fn hub_requests(stand_in: &StandIn, count: usize) -> Vec<HashMap<String, String>> {
+		stand_in.wait_for(count, Duration::from_secs(5)).await;
+		sleep(Duration::from_millis(100)).await;
+		stand_in
+			.take_requests()
+			.into_iter()
+			.filter(|r| r.is(Method::POST, "/hub"))
+			.map(|r| r.form())
+			.collect()
+	}
+
+	// Send a verification request as the hub would, returning the response
+	async fn verify(youtube: &Youtube, query: &[(&str, &str)]) -> (StatusCode, String) {
+		let query = url::form_urlencoded::Serializer::new(String::new())
+			.extend_pairs(query)
+			.finish();
+		let res = warp::test::request()
+			.path(&format!("/yt?{}", query))
+			.reply(&youtube.routes())
+			.await;
+		let body = String::from_utf8_lossy(res.body()).into_owned();
+		(res.status(), body)
+	}
+
+	async fn status(youtube: &Youtube, token: &str) -> (StatusCode, Value) {
+		let res = warp::test::request()
+			.path(&format!("/yt/status?token={}", token))
+			.reply(&youtube.routes())
+			.await;
+		let status = serde_json::from_slice(res.body()).unwrap_or_default();
+		(res.status(), status)
+	}
+
+	// Deliver a notification as the hub would
+	async fn deliver(youtube: &Youtube, secret: &str, body: String) {
+		let res = warp::test::request()
+			.method("POST")
+			.path("/yt")
+			.header("x-hub-signature", websub::sign(secret, body.as_bytes()))
+			.body(body)
+			.reply(&youtube.routes())
+			.await;
+		assert_eq!(res.status(), StatusCode::OK);
+	}
+
+	// Announcements sent to the stand-in once `count` requests arrived, clearing the record.
+	// Waits a little longer to catch unexpected ones
+	async fn announcements(stand_in: &StandIn, count: usize) -> Vec<String> {
+		stand_in.wait_for(count, Duration::from_secs(5)).await;
+		sleep(Duration::from_millis(100)).await;
+		stand_in
+			.take_requests()
+			.into_iter()
+			.filter(|r| r.is(Method::POST, "/channels/4/messages"))
+			.map(|r| r.json().unwrap()["content"].as_str().unwrap().to_owned())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn announces_again_after_failure() {
+		let stand_in = StandIn::start().await;
+		let storage = temp_storage("youtube_announces_again_after_failure").await;
+		let mut supervisor = Supervisor::new();
+		let config = json!({
+			"enabled": true,
+			"subscriptions": { "UC1": { "channel_id": "4", "text": "New: %TITLE%" } },
+		});
+		let youtube = youtube(&stand_in, storage, &mut supervisor, config).await;
+		let secret = hub_requests(&stand_in, 1).await[0]["hub.secret"].clone();
+
+		let now = Utc::now();
+		let body = feed_xml(&[("v1", "Video", now, now)]);
+		stand_in.respond(Method::POST, "/channels/4/messages", 500, "");
+		deliver(&youtube, &secret, body.clone()).await;
+		assert_eq!(announcements(&stand_in, 1).await, ["New: Video"]);
+
+		// Not recorded, so the redelivery is announced
+		accept_messages(&stand_in);
+		deliver(&youtube, &secret, body.clone()).await;
+		assert_eq!(announcements(&stand_in, 1).await, ["New: Video"]);
+
+		deliver(&youtube, &secret, body).await;
+		assert!(announcements(&stand_in, 1).await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn announces_updates_once() {
+		let stand_in = StandIn::start().await;
+		let storage = temp_storage("youtube_announces_updates_once").await;
+		let mut supervisor = Supervisor::new();
+		let config = json!({
+			"enabled": true,
+			"subscriptions": {
+				"UC1": {
+					"channel_id": "4",
+					"text": "New: %TITLE%",
+					"update_text": "Updated: %TITLE%",
+				},
+			},
+		});
+		let youtube = youtube(&stand_in, storage, &mut supervisor, config).await;
+		let secret = hub_requests(&stand_in, 1).await[0]["hub.secret"].clone();
+
+		let published = Utc::now() - chrono::Duration::days(2);
+		let update = |title, minutes| {
+			let updated = published + chrono::Duration::minutes(minutes);
+			feed_xml(&[("v1", title, published, updated)])
+		};
+		deliver(&youtube, &secret, update("First", 10)).await;
+		assert_eq!(announcements(&stand_in, 1).await, ["Updated: First"]);
+
+		// Redeliveries and older versions aren't announced again
+		deliver(&youtube, &secret, update("First", 10)).await;
+		deliver(&youtube, &secret, update("Older", 5)).await;
+		assert!(announcements(&stand_in, 0).await.is_empty());
+
+		deliver(&youtube, &secret, update("Second", 20)).await;
+		assert_eq!(announcements(&stand_in, 1).await, ["Updated: Second"]);
+	}
+
+	#[tokio::test]
+	async fn polls_missed_uploads() {
+		let stand_in = StandIn::start().await;
+		let storage = temp_storage("youtube_polls_missed_uploads").await;
+		let mut supervisor = Supervisor::new();
+		let config = json!({
+			"enabled": true,
+			"subscriptions": { "UC1": { "channel_id": "4", "text": "New: %TITLE%" } },
+			"poll_interval": 60,
+		});
+		let youtube = youtube(&stand_in, storage.clone(), &mut supervisor, config).await;
+		let secret = hub_requests(&stand_in, 1).await[0]["hub.secret"].clone();
+
+		let now = Utc::now();
+		let old = now - chrono::Duration::days(2);
+		deliver(&youtube, &secret, feed_xml(&[("v1", "One", now, now)])).await;
+		assert_eq!(announcements(&stand_in, 1).await, ["New: One"]);
+
+		// Only the upload the hub didn't deliver is announced, old ones are left alone
+		let feed = feed_xml(&[
+			("v2", "Two", now, now),
+			("v1", "One", now, now),
+			("v0", "Zero", old, old),
+		]);
+		stand_in.respond(Method::GET, "/feed", 200, feed);
+		let mut poller = Poller {
+			config: Arc::clone(&youtube.config),
+			sender: youtube.sender.clone(),
+			storage,
+		};
+		let client = reqwest::Client::new();
+		let url = format!("{}/feed?channel_id=UC1", stand_in.url());
+		let yt_channel = YoutubeChannel::from("UC1");
+		poller.poll(&client, &url, &yt_channel, 3600).await.unwrap();
+		assert_eq!(announcements(&stand_in, 2).await, ["New: Two"]);
+
+		poller.poll(&client, &url, &yt_channel, 3600).await.unwrap();
+		assert!(announcements(&stand_in, 1).await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn leases() {
+		let stand_in = StandIn::start().await;
+		let storage = temp_storage("youtube_leases").await;
+		let mut supervisor = Supervisor::new();
+		let subscription = json!({ "channel_id": "4", "text": "New: %TITLE%" });
+		let config = json!({
+			"enabled": true,
+			"subscriptions": { "UC1": subscription, "UC2": subscription, "UC3": subscription },
+			"concurrency": 2,
+			"status_token": "token",
+		});
+		let youtube = youtube(&stand_in, storage, &mut supervisor, config).await;
+		let channel = |form: &HashMap<String, String>| {
+			form["hub.topic"]
+				.strip_prefix(TOPIC_URL)
+				.unwrap()
+				.to_owned()
+		};
+
+		// Only two verifications may be pending at the same time
+		let requests = hub_requests(&stand_in, 2).await;
+		assert_eq!(requests.len(), 2);
+		let first = channel(&requests[0]);
+		let second = channel(&requests[1]);
+		assert_eq!(requests[0]["hub.callback"], "http://localhost/yt");
+		let (code, leases) = status(&youtube, "token").await;
+		assert_eq!(code, StatusCode::OK);
+		assert_eq!(leases.as_object().unwrap().len(), 2);
+		assert_eq!(leases[&first]["state"], "pending");
+		assert_eq!(status(&youtube, "wrong").await.0, StatusCode::NOT_FOUND);
+		assert_eq!(status(&youtube, "").await.0, StatusCode::NOT_FOUND);
+
+		// A verification opens up a slot for the remaining channel
+		let topic = format!("{}{}", TOPIC_URL, first);
+		let verification = [
+			("hub.mode", "subscribe"),
+			("hub.topic", topic.as_str()),
+			("hub.challenge", "challenge"),
+			("hub.lease_seconds", "86400"),
+		];
+		let res = verify(&youtube, &verification).await;
+		assert_eq!(res, (StatusCode::OK, "challenge".to_owned()));
+		let requests = hub_requests(&stand_in, 1).await;
+		assert_eq!(requests.len(), 1);
+		let third = channel(&requests[0]);
+		assert!(third != first && third != second);
+		assert_eq!(
+			status(&youtube, "token").await.1[&first]["state"],
+			"subscribed"
+		);
+
+		// Denied subscriptions are retried after a backoff
+		let topic = format!("{}{}", TOPIC_URL, second);
+		let denial = [("hub.mode", "denied"), ("hub.topic", topic.as_str())];
+		assert_eq!(verify(&youtube, &denial).await.0, StatusCode::OK);
+		assert!(hub_requests(&stand_in, 0).await.is_empty());
+		let leases = status(&youtube, "token").await.1;
+		assert_eq!(leases[&second]["state"], "retrying");
+		assert_eq!(leases[&second]["failures"], 1);
+
+		// Leases shorter than the renewal margin are renewed right away. The hub rejects the
+		// renewal, so it is retried as well
+		stand_in.respond(Method::POST, "/hub", 500, "");
+		let topic = format!("{}{}", TOPIC_URL, third);
+		let verification = [
+			("hub.mode", "subscribe"),
+			("hub.topic", topic.as_str()),
+			("hub.challenge", "challenge"),
+			("hub.lease_seconds", "60"),
+		];
+		verify(&youtube, &verification).await;
+		let requests = hub_requests(&stand_in, 1).await;
+		assert_eq!(requests.len(), 1);
+		assert_eq!(channel(&requests[0]), third);
+		let leases = status(&youtube, "token").await.1;
+		assert_eq!(leases[&third]["state"], "retrying");
+		assert_eq!(leases[&third]["failures"], 1);
+	}
+
+	#[tokio::test]
+	async fn keeps_leases_until_configured() {
+		let stand_in = StandIn::start().await;
+		let storage = temp_storage("youtube_keeps_leases_until_configured").await;
+		storage.migrate("youtube", MIGRATIONS).await.unwrap();
+		// Due for renewal as soon as the announcer starts
+		for yt_channel in ["UC1", "UC2"] {
+			query("INSERT INTO youtube_leases (yt_channel, expires_timestamp) VALUES ($1, $2)")
+				.bind(yt_channel)
+				.bind(Utc::now().timestamp() + 60)
+				.execute(&*storage)
+				.await
+				.unwrap();
+		}
+
+		let mut supervisor = Supervisor::new();
+		let mut youtube = Youtube::new(
+			stand_in.client(),
+			storage,
+			"http://localhost",
+			&mut supervisor,
+		)
+		.await
+		.unwrap();
+		sleep(Duration::from_millis(200)).await;
+		assert!(hub_requests(&stand_in, 0).await.is_empty());
+
+		// The module starts out disabled, so the restored leases are cleaned up
+		let guild = GuildState::new(1, 2, "Test").guild(&stand_in);
+		let config = json!({
+			"enabled": false,
+			"subscriptions": {},
+			"hub_url": format!("{}/hub", stand_in.url()),
+		});
+		assert!(youtube.config(&guild, "youtube", config).is_none());
+		let requests = hub_requests(&stand_in, 2).await;
+		assert_eq!(requests.len(), 2);
+		assert!(requests.iter().all(|r| r["hub.mode"] == "unsubscribe"));
+	}
+
+	#[test]
+	fn backoff() {
+		let mut leases = HashMap::new();
+		let yt_channel = YoutubeChannel::from("UC1");
+		let backoffs = (0..9)
+			.map(|_| retry(&mut leases, &yt_channel).as_secs())
+			.collect::<Vec<_>>();
+		assert_eq!(backoffs, [30, 60, 120, 240, 480, 960, 1920, 3600, 3600]);
+		let lease = &leases[&yt_channel];
+		assert_eq!(lease.failures, 9);
+		let retry_at = Instant::now() + Duration::from_secs(3500);
+		assert!(matches!(lease.state, LeaseState::Retry(i) if i > retry_at));
+	}
+
+	#[test]
+	fn title_filter() {
+		let filter = |value| serde_json::from_value::<VideoFilter>(value).unwrap();
+
+		let any = filter(json!({}));
+		assert_eq!(any.check_title("Anything"), None);
+
+		let filter = filter(json!({
+			"include": ["(?i)^stream", "highlights"],
+			"exclude": ["(?i)reupload"],
+		}));
+		assert_eq!(filter.check_title("Stream: day 1"), None);
+		assert_eq!(filter.check_title("Best highlights"), None);
+		assert_eq!(
+			filter.check_title("Vlog"),
+			Some("Title doesn't match any include rule".to_owned())
+		);
+		assert_eq!(
+			filter.check_title("stream REUPLOAD"),
+			Some("Title matches exclude rule `(?i)reupload`".to_owned())
+		);
+		assert!(serde_json::from_value::<VideoFilter>(json!({ "exclude": ["("] })).is_err());
+	}
 
 	#[test]
 	fn publication() {
-		const DATA: &str = r#"
-			<feed xmlns:yt="http://www.youtube.com/xml/schemas/2015" xmlns="http://www.w3.org/2005/Atom">
-			  <link rel="hub" href="https://pubsubhubbub.appspot.com"/>
-			  <link rel="self" href="https://www.youtube.com/xml/feeds/videos.xml?channel_id=CHANNEL_ID"/>
-			  <title>YouTube video feed</title>
-			  <updated>2015-04-01T19:05:24.552394234+00:00</updated>
-			  <entry>
-				<id>yt:video:VIDEO_ID</id>
-				<yt:videoId>VIDEO_ID</yt:videoId>
-				<yt:channelId>CHANNEL_ID</yt:channelId>
-				<title>Video title</title>
-				<link rel="alternate" href="http://www.youtube.com/watch?v=VIDEO_ID"/>
-				<author>
-				 <name>Channel title</name>
-				 <uri>http://www.youtube.com/channel/CHANNEL_ID</uri>
-				</author>
-				<published>2015-03-06T21:40:57+00:00</published>
-				<updated>2015-03-09T19:05:24.552394234+00:00</updated>
-			  </entry>
-			</feed>
-		"#;
-
-		let publication = Publication::from_str(DATA).unwrap();
+		let publication = Publication::from_str(PUBLICATION).unwrap();
 		assert_eq!(&publication.title, "Video title");
 		assert_eq!(publication.yt_channel, "CHANNEL_ID");
 		assert_eq!(publication.yt_id, "VIDEO_ID");
-		/*assert_eq!(
+		assert_eq!(&publication.channel_name, "Channel title");
+		assert_eq!(Publication::parse_all(PUBLICATION).unwrap().len(), 1);
+		assert_eq!(
 			publication.published,
-			Utc.ymd(2015, 3, 6).and_hms(21, 40, 57)
+			Utc.with_ymd_and_hms(2015, 3, 6, 21, 40, 57).unwrap()
 		);
 		assert_eq!(
 			publication.updated,
-			Utc.ymd(2015, 3, 9).and_hms_nano(19, 5, 24, 552394234)
-		);*/
+			Utc.with_ymd_and_hms(2015, 3, 9, 19, 5, 24).unwrap()
+				+ chrono::Duration::nanoseconds(552394234)
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_signature() {
+		let secrets = Arc::new(Mutex::new(HashMap::from([(
+			YoutubeChannel::from("CHANNEL_ID"),
+			"secret".to_owned(),
+		)])));
+		let (sender, mut recv) = mpsc::channel(8);
+		let rejected = Rejected::default();
+		let bytes = Bytes::from_static(PUBLICATION.as_bytes());
+		for signature in [None, Some("sha1=00".to_owned())] {
+			let status = http_post(&secrets, &sender, &rejected, signature, bytes.clone());
+			assert_eq!(status, Some(StatusCode::OK));
+		}
+		// Summarized for the log channel once per interval
+		assert!(matches!(
+			recv.try_next(),
+			Ok(Some(Event::Rejected(s)))
+				if s == "1 delivery with an invalid signature rejected for `CHANNEL_ID`"
+		));
+		assert!(recv.try_next().is_err());
+
+		let signature = websub::sign("secret", PUBLICATION.as_bytes());
+		http_post(&secrets, &sender, &rejected, Some(signature), bytes);
+		assert!(matches!(
+			recv.try_next(),
+			Ok(Some(Event::Publication(p))) if p.yt_id == "VIDEO_ID"
+		));
+	}
+
+	#[test]
+	fn duration() {
+		assert_eq!(parse_duration("PT1H2M3S"), Some(Duration::from_secs(3723)));
+		assert_eq!(parse_duration("PT45S"), Some(Duration::from_secs(45)));
+		assert_eq!(parse_duration("P1DT1M"), Some(Duration::from_secs(86460)));
+		assert_eq!(parse_duration("P0D"), Some(Duration::ZERO));
+		assert_eq!(parse_duration("PT1S2M"), None);
+		assert_eq!(parse_duration("1H"), None);
 	}
 }
@@ -0,0 +1,43 @@
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::Deref;
+
+/// Regular expression that can be used in module configs. Invalid expressions are
+/// rejected when the config is loaded
+#[derive(Clone, Debug)]
+pub struct Pattern(Regex);
+
+impl Deref for Pattern {
+	type Target = Regex;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl fmt::Display for Pattern {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Display::fmt(self.0.as_str(), f)
+	}
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let pattern = String::deserialize(deserializer)?;
+		Regex::new(&pattern).map(Pattern).map_err(D::Error::custom)
+	}
+}
+
+impl Serialize for Pattern {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		serializer.serialize_str(self.0.as_str())
+	}
+}
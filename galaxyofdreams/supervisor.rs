@@ -0,0 +1,193 @@
+use anyhow::Result;
+use common::interaction::Spawner;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{info, warn};
+use std::future::Future;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::select;
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{sleep, timeout, Instant};
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+// A task that crashes after running for this long is considered to have been healthy
+const BACKOFF_RESET: Duration = Duration::from_secs(10 * 60);
+
+/// Keeps track of the background tasks of the bot. Long running tasks are restarted
+/// with a backoff when they crash, and on shutdown every task gets the chance to finish
+/// its work before the deadline passes.
+pub struct Supervisor {
+	shutdown: watch::Sender<bool>,
+	tasks: JoinSet<()>,
+	tracker: Tracker,
+}
+
+impl Supervisor {
+	pub fn new() -> Self {
+		let (shutdown, _) = watch::channel(false);
+		Self {
+			shutdown,
+			tasks: JoinSet::new(),
+			tracker: Tracker::new(),
+		}
+	}
+
+	pub fn tracker(&self) -> Tracker {
+		self.tracker.clone()
+	}
+
+	pub fn signal(&self) -> Shutdown {
+		Shutdown(self.shutdown.subscribe())
+	}
+
+	/// Spawn a long running task. The future is created by `f`, which will be called again
+	/// if the task returns an error or panics before the shutdown signal has been sent
+	pub fn spawn<F, Fut>(&mut self, name: &'static str, mut f: F)
+	where
+		F: FnMut(Shutdown) -> Fut + Send + 'static,
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		let mut shutdown = self.signal();
+		self.tasks.spawn(async move {
+			let mut backoff = BACKOFF_MIN;
+			loop {
+				let started = Instant::now();
+				// Run on a separate task so we are able to catch panics
+				let mut task = Task(tokio::spawn(f(shutdown.clone())));
+				let res = (&mut task.0).await;
+				if shutdown.is_shutdown() {
+					break;
+				}
+
+				match res {
+					Ok(Ok(_)) => {
+						info!("Task '{}' finished", name);
+						break;
+					}
+					Ok(Err(e)) => warn!("Task '{}' failed: {}", name, e),
+					Err(e) => warn!("Task '{}' crashed: {}", name, e),
+				}
+
+				if started.elapsed() >= BACKOFF_RESET {
+					backoff = BACKOFF_MIN;
+				}
+				warn!("Restarting '{}' in {}s", name, backoff.as_secs());
+				select! {
+					_ = sleep(backoff) => {}
+					_ = shutdown.wait() => break,
+				}
+				backoff = (backoff * 2).min(BACKOFF_MAX);
+			}
+		});
+	}
+
+	/// Send the shutdown signal and wait for all tasks to finish, up to `deadline`.
+	/// Anything still running after that is aborted
+	pub async fn shutdown(mut self, deadline: Duration) {
+		let _ = self.shutdown.send(true);
+
+		let tracker = self.tracker.clone();
+		let drain = async {
+			while self.tasks.join_next().await.is_some() {}
+			tracker.drain().await;
+		};
+		match timeout(deadline, drain).await {
+			Ok(_) => info!("All tasks finished"),
+			Err(_) => warn!("Shutdown deadline passed, aborting remaining tasks"),
+		}
+	}
+}
+
+/// Receiving end of the shutdown signal
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+	pub fn is_shutdown(&self) -> bool {
+		*self.0.borrow()
+	}
+
+	pub async fn wait(&mut self) {
+		while !self.is_shutdown() {
+			if self.0.changed().await.is_err() {
+				// Supervisor went away
+				break;
+			}
+		}
+	}
+}
+
+/// Spawns short lived tasks, like sending a message or writing to the database,
+/// that will be waited upon during shutdown
+#[derive(Clone)]
+pub struct Tracker(Arc<Mutex<JoinSet<()>>>);
+
+impl Tracker {
+	fn new() -> Self {
+		Self(Arc::new(Mutex::new(JoinSet::new())))
+	}
+
+	pub fn spawn<F>(&self, fut: F)
+	where
+		F: Future<Output = ()> + Send + 'static,
+	{
+		let mut tasks = self.0.lock().unwrap();
+		// Reap finished tasks so the set doesn't keep growing
+		while let Some(Some(_)) = tasks.join_next().now_or_never() {}
+		tasks.spawn(fut);
+	}
+
+	async fn drain(&self) {
+		loop {
+			let mut tasks = mem::replace(&mut *self.0.lock().unwrap(), JoinSet::new());
+			if tasks.is_empty() {
+				break;
+			}
+			while tasks.join_next().await.is_some() {}
+		}
+	}
+}
+
+impl Spawner for Tracker {
+	fn spawn_boxed(&self, fut: BoxFuture<'static, ()>) {
+		self.spawn(fut);
+	}
+}
+
+// Abort the inner task if the supervising task itself is aborted
+struct Task<T>(JoinHandle<T>);
+
+impl<T> Drop for Task<T> {
+	fn drop(&mut self) {
+		self.0.abort();
+	}
+}
+
+/// Resolves when the process receives an interrupt or terminate signal
+pub async fn terminate() {
+	#[cfg(unix)]
+	{
+		let mut term = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+			Ok(s) => s,
+			Err(e) => {
+				warn!("Unable to listen for terminate signal: {}", e);
+				let _ = signal::ctrl_c().await;
+				return;
+			}
+		};
+		select! {
+			_ = signal::ctrl_c() => {}
+			_ = term.recv() => {}
+		}
+	}
+
+	#[cfg(not(unix))]
+	{
+		let _ = signal::ctrl_c().await;
+	}
+}
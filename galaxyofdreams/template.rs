@@ -0,0 +1,183 @@
+use anyhow::{bail, Result};
+use common::discord::types::Embed;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Values for the `%NAME%` placeholders of a template
+pub type Values<'a> = HashMap<&'a str, &'a str>;
+
+/// Replace every known `%NAME%` placeholder in `template`. Anything else, like a
+/// lone percent sign, is left untouched
+pub fn render(template: &str, values: &Values) -> String {
+	let mut out = String::with_capacity(template.len());
+	scan(
+		template,
+		|name| values.contains_key(name),
+		|part| match part {
+			Part::Text(text) => out.push_str(text),
+			Part::Placeholder(name) => out.push_str(values[name]),
+		},
+	);
+	out
+}
+
+/// Check that `template` only uses placeholders from `known`
+pub fn validate(template: &str, known: &[&str]) -> Result<()> {
+	// Unknown names that look like placeholders are picked up as well, so they can be reported
+	let is_placeholder = |name: &str| {
+		!name.is_empty()
+			&& name
+				.chars()
+				.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+	};
+	let mut unknown = None;
+	scan(template, is_placeholder, |part| match part {
+		Part::Placeholder(name) if !known.contains(&name) => {
+			unknown.get_or_insert(name);
+		}
+		_ => {}
+	});
+	if let Some(name) = unknown {
+		bail!(
+			"Unknown placeholder '%{}%', expected one of {}",
+			name,
+			known
+				.iter()
+				.map(|k| format!("%{}%", k))
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+	}
+	Ok(())
+}
+
+enum Part<'a> {
+	Text(&'a str),
+	Placeholder(&'a str),
+}
+
+// Split `template` into text and the placeholders accepted by `is_placeholder`. A name runs up
+// to the next percent sign. If it isn't accepted, the first percent sign is text and the next
+// one may start a placeholder
+fn scan<'a>(
+	template: &'a str,
+	is_placeholder: impl Fn(&str) -> bool,
+	mut part: impl FnMut(Part<'a>),
+) {
+	let mut rest = template;
+	while let Some(start) = rest.find('%') {
+		part(Part::Text(&rest[..start]));
+		let after = &rest[start + 1..];
+		match after.find('%').filter(|end| is_placeholder(&after[..*end])) {
+			Some(end) => {
+				part(Part::Placeholder(&after[..end]));
+				rest = &after[end + 1..];
+			}
+			None => {
+				part(Part::Text("%"));
+				rest = after;
+			}
+		}
+	}
+	part(Part::Text(rest));
+}
+
+/// Embed with templates for its fields
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EmbedTemplate {
+	#[serde(default)]
+	title: Option<String>,
+	#[serde(default)]
+	description: Option<String>,
+	#[serde(default)]
+	url: Option<String>,
+	#[serde(default)]
+	thumbnail: Option<String>,
+	#[serde(default)]
+	image: Option<String>,
+	#[serde(default)]
+	footer: Option<String>,
+	#[serde(default)]
+	color: Option<u32>,
+	#[serde(default)]
+	fields: Vec<FieldTemplate>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FieldTemplate {
+	name: String,
+	value: String,
+	#[serde(default)]
+	inline: bool,
+}
+
+impl EmbedTemplate {
+	/// All templates of the embed
+	pub fn templates(&self) -> impl Iterator<Item = &String> {
+		[
+			&self.title,
+			&self.description,
+			&self.url,
+			&self.thumbnail,
+			&self.image,
+			&self.footer,
+		]
+		.into_iter()
+		.flatten()
+		.chain(self.fields.iter().flat_map(|f| [&f.name, &f.value]))
+	}
+
+	pub fn render(&self, values: &Values) -> Embed {
+		let mut embed = Embed::new();
+		if let Some(title) = &self.title {
+			embed = embed.title(render(title, values));
+		}
+		if let Some(description) = &self.description {
+			embed = embed.description(render(description, values));
+		}
+		if let Some(url) = &self.url {
+			embed = embed.url(render(url, values));
+		}
+		if let Some(thumbnail) = &self.thumbnail {
+			embed = embed.thumbnail(render(thumbnail, values));
+		}
+		if let Some(image) = &self.image {
+			embed = embed.image(render(image, values));
+		}
+		if let Some(footer) = &self.footer {
+			embed = embed.footer(render(footer, values));
+		}
+		if let Some(color) = self.color {
+			embed = embed.color(color);
+		}
+		for field in &self.fields {
+			embed = embed.field(
+				render(&field.name, values),
+				render(&field.value, values),
+				field.inline,
+			);
+		}
+		embed
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn placeholders() {
+		let values = Values::from([("ID", "abc"), ("TITLE", "100% %ID%")]);
+		assert_eq!(
+			render("%TITLE% (%ID%) at 50% %OTHER%", &values),
+			"100% %ID% (abc) at 50% %OTHER%"
+		);
+		assert_eq!(render("%ID%%ID%%", &values), "abcabc%");
+
+		assert!(validate("%TITLE% (%ID%) at 50%", &["ID", "TITLE"]).is_ok());
+		assert!(validate("%TITLE% %URL%", &["ID", "TITLE"]).is_err());
+		assert!(validate("50% %URL%", &["ID", "TITLE"]).is_err());
+		assert!(validate("%ID%%URL%", &["ID", "TITLE"]).is_err());
+		assert!(validate("100%%ID%", &["ID", "TITLE"]).is_ok());
+	}
+}
@@ -0,0 +1,218 @@
+use crate::supervisor::Tracker;
+use anyhow::Result;
+use chrono::Utc;
+use common::discord::types::ChannelId;
+use common::interaction::Interaction;
+use common::{Migration, Storage};
+use log::warn;
+use sqlx::{query, query_as};
+use std::collections::HashMap;
+
+const MIGRATIONS: &[Migration] = &[Migration {
+	name: "create_command_usage",
+	sqlite: r#"
+		CREATE TABLE IF NOT EXISTS command_usage (
+			command_usage_id INTEGER PRIMARY KEY AUTOINCREMENT,
+			command TEXT NOT NULL,
+			user_id INTEGER,
+			channel_id INTEGER,
+			outcome TEXT NOT NULL,
+			created_timestamp INTEGER NOT NULL
+		);
+
+		CREATE INDEX IF NOT EXISTS command_usage_created ON command_usage (created_timestamp);
+	"#,
+	postgres: r#"
+		CREATE TABLE IF NOT EXISTS command_usage (
+			command_usage_id BIGSERIAL PRIMARY KEY,
+			command TEXT NOT NULL,
+			user_id BIGINT,
+			channel_id BIGINT,
+			outcome TEXT NOT NULL,
+			created_timestamp BIGINT NOT NULL
+		);
+
+		CREATE INDEX IF NOT EXISTS command_usage_created ON command_usage (created_timestamp);
+	"#,
+}];
+
+/// What happened to a command invocation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+	Allowed,
+	// Not allowed in the channel or for the member
+	Denied,
+	Cooldown,
+}
+
+impl Outcome {
+	fn as_str(self) -> &'static str {
+		match self {
+			Outcome::Allowed => "allowed",
+			Outcome::Denied => "denied",
+			Outcome::Cooldown => "cooldown",
+		}
+	}
+}
+
+/// Uses of a single command in a date range
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CommandSummary {
+	pub command: String,
+	pub allowed: i64,
+	pub denied: i64,
+	pub cooldown: i64,
+}
+
+impl CommandSummary {
+	pub fn total(&self) -> i64 {
+		self.allowed + self.denied + self.cooldown
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+	// Most used first
+	pub commands: Vec<CommandSummary>,
+	// Allowed uses per channel, most used first
+	pub channels: Vec<(ChannelId, i64)>,
+}
+
+/// Records every command invocation, so we can see which commands are actually used
+#[derive(Clone)]
+pub struct Usage {
+	storage: Storage,
+	tracker: Tracker,
+}
+
+impl Usage {
+	pub async fn new(storage: Storage, tracker: Tracker) -> Result<Self> {
+		storage.migrate("command_usage", MIGRATIONS).await?;
+		Ok(Self { storage, tracker })
+	}
+
+	/// Record the use of `command` through `interaction` in the background
+	pub fn record(&self, command: &str, interaction: &Interaction, outcome: Outcome) {
+		let storage = self.storage.clone();
+		let command = command.to_owned();
+		let user_id = interaction
+			.member
+			.as_ref()
+			.and_then(|m| m.user.as_ref())
+			.map(|u| u.id);
+		let channel_id = interaction.channel_id;
+		let now = Utc::now().timestamp();
+		self.tracker.spawn(async move {
+			let res = query("INSERT INTO command_usage (command, user_id, channel_id, outcome, created_timestamp) VALUES ($1, $2, $3, $4, $5)")
+				.bind(&command)
+				.bind(user_id)
+				.bind(channel_id)
+				.bind(outcome.as_str())
+				.bind(now)
+				.execute(&*storage)
+				.await;
+			if let Err(e) = res {
+				warn!("Unable to record use of '{}': {}", command, e);
+			}
+		});
+	}
+
+	/// Uses by command and channel between the timestamps `from` (inclusive) and `to` (exclusive)
+	pub async fn summary(&self, from: i64, to: i64) -> Result<Summary> {
+		let rows = query_as::<_, (String, String, i64)>(
+			"SELECT command, outcome, COUNT(*) FROM command_usage WHERE created_timestamp >= $1 AND created_timestamp < $2 GROUP BY command, outcome",
+		)
+		.bind(from)
+		.bind(to)
+		.fetch_all(&*self.storage)
+		.await?;
+
+		let mut commands: HashMap<String, CommandSummary> = HashMap::new();
+		for (command, outcome, count) in rows {
+			let summary = commands
+				.entry(command.clone())
+				.or_insert_with(|| CommandSummary {
+					command,
+					..Default::default()
+				});
+			match outcome.as_str() {
+				"allowed" => summary.allowed += count,
+				"denied" => summary.denied += count,
+				"cooldown" => summary.cooldown += count,
+				_ => (),
+			}
+		}
+		let mut commands: Vec<_> = commands.into_values().collect();
+		commands.sort_by(|a, b| {
+			b.total()
+				.cmp(&a.total())
+				.then_with(|| a.command.cmp(&b.command))
+		});
+
+		let mut channels = query_as::<_, (ChannelId, i64)>(
+			"SELECT channel_id, COUNT(*) FROM command_usage WHERE created_timestamp >= $1 AND created_timestamp < $2 AND outcome = 'allowed' AND channel_id IS NOT NULL GROUP BY channel_id",
+		)
+		.bind(from)
+		.bind(to)
+		.fetch_all(&*self.storage)
+		.await?;
+		channels.sort_by(|a, b| b.1.cmp(&a.1));
+
+		Ok(Summary { commands, channels })
+	}
+}
+
+/// Usage backed by a fresh SQLite database in the temp directory
+#[cfg(test)]
+pub(crate) async fn temp_usage(name: &str) -> Usage {
+	let storage = common::testing::temp_storage(name).await;
+	let tracker = crate::supervisor::Supervisor::new().tracker();
+	Usage::new(storage, tracker).await.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::testing::GuildState;
+	use std::time::Duration;
+	use tokio::time::sleep;
+
+	#[tokio::test]
+	async fn summarizes() {
+		let usage = temp_usage("usage").await;
+		let state = GuildState::new(1, 2, "Test")
+			.text_channel(3, "commands")
+			.text_channel(4, "general")
+			.member(5, "someone", &[]);
+		let interaction = |channel_id| -> Interaction {
+			let interaction = state.command_interaction(10, channel_id, 5, "hello", &[]);
+			serde_json::from_value(interaction).unwrap()
+		};
+
+		usage.record("hello", &interaction(3), Outcome::Allowed);
+		usage.record("hello", &interaction(3), Outcome::Allowed);
+		usage.record("hello", &interaction(3), Outcome::Cooldown);
+		usage.record("hello", &interaction(4), Outcome::Denied);
+		usage.record("logo", &interaction(3), Outcome::Allowed);
+		usage.record("logo", &interaction(4), Outcome::Allowed);
+		usage.record("joined", &interaction(4), Outcome::Allowed);
+		sleep(Duration::from_millis(500)).await;
+
+		let now = Utc::now().timestamp();
+		let summary = usage.summary(now - 60, now + 60).await.unwrap();
+		let commands: Vec<_> = summary
+			.commands
+			.iter()
+			.map(|c| (c.command.as_str(), c.allowed, c.denied, c.cooldown))
+			.collect();
+		assert_eq!(
+			commands,
+			[("hello", 2, 1, 1), ("logo", 2, 0, 0), ("joined", 1, 0, 0)]
+		);
+		assert_eq!(summary.channels, [(3.into(), 3), (4.into(), 2)]);
+
+		let summary = usage.summary(now + 60, now + 120).await.unwrap();
+		assert!(summary.commands.is_empty());
+		assert!(summary.channels.is_empty());
+	}
+}
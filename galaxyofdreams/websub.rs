@@ -0,0 +1,220 @@
+//! Pieces shared by the modules that follow feeds through a WebSub hub
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha1::Sha1;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Minimum time between two summaries of rejected deliveries
+const REJECTED_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+pub async fn subscribe(
+	client: &reqwest::Client,
+	hub_url: &str,
+	topic: &str,
+	callback: &str,
+	secret: &str,
+) -> Result<()> {
+	let form = [
+		("hub.mode", "subscribe"),
+		("hub.topic", topic),
+		("hub.callback", callback),
+		("hub.secret", secret),
+	];
+	client
+		.post(hub_url)
+		.form(&form)
+		.send()
+		.await?
+		.error_for_status()?;
+	Ok(())
+}
+
+pub async fn unsubscribe(
+	client: &reqwest::Client,
+	hub_url: &str,
+	topic: &str,
+	callback: &str,
+) -> Result<()> {
+	let form = [
+		("hub.mode", "unsubscribe"),
+		("hub.topic", topic),
+		("hub.callback", callback),
+	];
+	client
+		.post(hub_url)
+		.form(&form)
+		.send()
+		.await?
+		.error_for_status()?;
+	Ok(())
+}
+
+/// Verification request sent by the hub to the callback URL
+#[derive(Debug)]
+pub enum Verification<'a> {
+	Subscribe {
+		topic: &'a str,
+		challenge: &'a str,
+		lease_seconds: u64,
+	},
+	Unsubscribe {
+		topic: &'a str,
+		challenge: &'a str,
+	},
+	Denied {
+		topic: &'a str,
+		reason: Option<&'a str>,
+	},
+}
+
+impl<'a> Verification<'a> {
+	pub fn parse(query: &'a HashMap<String, String>) -> Option<Self> {
+		let topic = query.get("hub.topic")?;
+		let challenge = query.get("hub.challenge");
+		let verification = match query.get("hub.mode")?.as_str() {
+			"subscribe" => Verification::Subscribe {
+				topic,
+				challenge: challenge?,
+				lease_seconds: query.get("hub.lease_seconds")?.parse().ok()?,
+			},
+			"unsubscribe" => Verification::Unsubscribe {
+				topic,
+				challenge: challenge?,
+			},
+			"denied" => Verification::Denied {
+				topic,
+				reason: query.get("hub.reason").map(|r| r.as_str()),
+			},
+			_ => return None,
+		};
+		Some(verification)
+	}
+}
+
+/// Check the `X-Hub-Signature` header of a content distribution, which contains
+/// the HMAC of the body
+pub fn verify_signature(secret: &str, signature: &str, body: &[u8]) -> bool {
+	let (method, signature) = match signature.split_once('=') {
+		Some(s) => s,
+		None => return false,
+	};
+	let signature = match hex::decode(signature) {
+		Ok(s) => s,
+		Err(_) => return false,
+	};
+	match method {
+		"sha1" => verify::<Hmac<Sha1>>(secret, &signature, body),
+		"sha256" => verify::<Hmac<Sha256>>(secret, &signature, body),
+		_ => false,
+	}
+}
+
+/// Signature the hub would send for `body`, to simulate deliveries in tests
+#[cfg(test)]
+pub fn sign(secret: &str, body: &[u8]) -> String {
+	let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+	mac.update(body);
+	format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Counts deliveries with an invalid signature. Anyone can send those, so they're never
+/// queued, and they're summarized at most once per interval
+#[derive(Debug, Default)]
+pub struct Rejected {
+	// Time of the last summary and the number of deliveries rejected since, per topic
+	state: Mutex<(Option<Instant>, BTreeMap<String, u64>)>,
+}
+
+impl Rejected {
+	/// Count a rejected delivery for `topic`. Returns a summary of the deliveries rejected
+	/// since the last one, for the module's log channel, unless one was given out recently
+	pub fn reject(&self, topic: &str) -> Option<String> {
+		let mut state = self.state.lock().unwrap();
+		let (last, counts) = &mut *state;
+		*counts.entry(topic.to_owned()).or_default() += 1;
+		let now = Instant::now();
+		if last.map_or(false, |l| now < l + REJECTED_LOG_INTERVAL) {
+			return None;
+		}
+		*last = Some(now);
+
+		let summary = mem::take(counts)
+			.into_iter()
+			.map(|(topic, count)| {
+				let deliveries = if count == 1 { "delivery" } else { "deliveries" };
+				format!(
+					"{} {} with an invalid signature rejected for `{}`",
+					count, deliveries, topic
+				)
+			})
+			.collect::<Vec<_>>()
+			.join("\n");
+		warn!("{}", summary);
+		Some(summary)
+	}
+}
+
+fn verify<M: Mac + hmac::digest::KeyInit>(secret: &str, signature: &[u8], body: &[u8]) -> bool {
+	let mut mac = match <M as Mac>::new_from_slice(secret.as_bytes()) {
+		Ok(m) => m,
+		Err(_) => return false,
+	};
+	mac.update(body);
+	mac.verify_slice(signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn signature() {
+		let body = b"The quick brown fox jumps over the lazy dog";
+		let signature = "sha1=de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9";
+		assert!(verify_signature("key", signature, body));
+		assert!(!verify_signature("other", signature, body));
+		assert!(!verify_signature("key", signature, b"Tampered body"));
+		assert!(!verify_signature(
+			"key",
+			"de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9",
+			body
+		));
+		assert!(!verify_signature("key", "sha1=nothex", body));
+
+		let signature = "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+		assert!(verify_signature("key", signature, body));
+		assert!(!verify_signature(
+			"key",
+			"md5=80070713463e7749b90c2dc24911e275",
+			body
+		));
+	}
+
+	#[test]
+	fn rejected() {
+		let rejected = Rejected::default();
+		assert_eq!(
+			rejected.reject("a").as_deref(),
+			Some("1 delivery with an invalid signature rejected for `a`")
+		);
+		assert_eq!(rejected.reject("b"), None);
+		assert_eq!(rejected.reject("a"), None);
+		assert_eq!(rejected.reject("b"), None);
+
+		// Summarizes everything since the last summary once the interval has passed
+		rejected.state.lock().unwrap().0 = Some(Instant::now() - REJECTED_LOG_INTERVAL);
+		assert_eq!(
+			rejected.reject("a").as_deref(),
+			Some(
+				"2 deliveries with an invalid signature rejected for `a`\n\
+				2 deliveries with an invalid signature rejected for `b`"
+			)
+		);
+	}
+}
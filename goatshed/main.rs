@@ -1,7 +1,7 @@
 use anyhow::{bail, Result};
 use common::discord::types::Event;
 use common::discord::{Builder, GatewayError, GatewayEvent};
-use common::EventHandler;
+use common::{EventHandler, Guild};
 use config::Config;
 use futures::channel::mpsc;
 use futures::SinkExt;
@@ -85,12 +85,17 @@ async fn real_main() -> Result<()> {
 	.build()
 	.await?;
 
-	// Discard events until we find our guild
+	// Discard events until we find our guild, remembering our own user from READY
+	let mut user_id = None;
 	let mut gc = None;
 	while let Some(ev) = ev_recv.next().await {
-		if let GatewayEvent::Event(Event::GuildCreate(c)) = ev {
-			gc = Some(c);
-			break;
+		match ev {
+			GatewayEvent::Event(Event::Ready(r)) => user_id = Some(r.user.id),
+			GatewayEvent::Event(Event::GuildCreate(c)) => {
+				gc = Some(c);
+				break;
+			}
+			_ => (),
 		}
 	}
 
@@ -101,9 +106,10 @@ async fn real_main() -> Result<()> {
 		shutdown.send();
 	});
 
-	let mut guild = match gc {
-		Some(gc) => discord.guild(ev_recv, gc).await?,
-		None => bail!("Connection closed before receiving our guild"),
+	let mut guild = match (gc, user_id) {
+		(Some(gc), Some(user_id)) => Guild::new(discord.guild(ev_recv, gc).await?, user_id),
+		(Some(_), None) => bail!("Received our guild before READY"),
+		(None, _) => bail!("Connection closed before receiving our guild"),
 	};
 
 	let mut chain = radio::Radio::new(
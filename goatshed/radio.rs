@@ -4,7 +4,7 @@ use chrono::{DateTime, Utc};
 use common::discord::types::{ChannelId, Embed};
 use common::discord::voice::source::ffmpeg_stream;
 use common::discord::voice::{Controller, Event, Listener, Updater};
-use common::discord::Client;
+use common::Client;
 use common::{Guild, HasUpdater, VoiceEventHandler};
 // use futures::channel::mpsc;
 use futures::StreamExt;